@@ -0,0 +1,735 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, TryLockError},
+    io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::StatsError,
+    exports::{CACHE_DIR, CONFIG, DRY_RUN, cache_path},
+    utility::{StatsSnapshot, write_atomic},
+};
+
+// Layout version of the JSON cache written by `LocCache::save`
+pub const CACHE_VERSION: u32 = 1;
+
+// Comment lines at the top of the LOC cache
+pub const CACHE_COMMENT_SIZE: usize = 7;
+
+// Comment line the legacy text cache used to record when it was rebuilt
+const GENERATED_AT_PREFIX: &str = "Generated at ";
+
+/// The LOC cache of one user: a free-form comment and one entry per
+/// repository, in the order the repositories were queried.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocCache {
+    pub version: u32,
+    // Unix time (seconds) at which the repository list was last rebuilt
+    pub generated_at: u64,
+    pub comment: Vec<String>,
+    pub repos: Vec<CachedRepo>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CachedRepo {
+    // SHA-256 of the repository's `nameWithOwner`
+    pub hash: String,
+    // Commits in the whole default branch history, used to spot changes
+    pub commits: i64,
+    // Commits authored by the user
+    pub my_commits: i64,
+    pub loc_add: i64,
+    pub loc_del: i64,
+}
+
+/// The stable part of a user's account data, cached in `owner.json` so that
+/// `user_getter` doesn't have to run every time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedOwner {
+    pub id: String,
+    pub created_at: String,
+}
+
+impl LocCache {
+    /// An empty cache with `comment_size` placeholder comment lines.
+    pub fn new(comment_size: usize) -> Self {
+        LocCache {
+            version: CACHE_VERSION,
+            generated_at: 0,
+            comment: vec![
+                "This line is a comment block. Write whatever you want here.".to_string();
+                comment_size
+            ],
+            repos: Vec::new(),
+        }
+    }
+
+    /// Load the cache of `user_name`. A legacy text cache is converted and
+    /// saved as JSON, and the text file removed. Returns `None` if the user
+    /// has no cache yet, or if it is corrupted (e.g. truncated by a crash
+    /// mid-write) so that it gets rebuilt from scratch.
+    pub fn load(user_name: &str, comment_size: usize) -> Result<Option<Self>, StatsError> {
+        let path = cache_file(user_name);
+        if path.exists() {
+            return match serde_json::from_str(&fs::read_to_string(&path)?) {
+                Ok(cache) => Ok(Some(cache)),
+                Err(err) => {
+                    warn!(
+                        "The cache {} is corrupted ({}), rebuilding it",
+                        path.display(),
+                        err
+                    );
+                    Ok(None)
+                }
+            };
+        }
+
+        // The text cache always covered the whole history
+        let legacy_path = legacy_cache_file(user_name);
+        if CONFIG.loc_since.is_some() || !legacy_path.exists() {
+            return Ok(None);
+        }
+
+        let cache = LocCache::from_legacy(&fs::read_to_string(&legacy_path)?, comment_size);
+        if *DRY_RUN {
+            return Ok(cache);
+        }
+
+        info!("Upgrading the text cache {} to JSON", legacy_path.display());
+        match &cache {
+            Some(cache) => cache.save(user_name)?,
+            None => warn!(
+                "The cache {} is corrupted, rebuilding it",
+                legacy_path.display()
+            ),
+        }
+        fs::remove_file(&legacy_path)?;
+
+        Ok(cache)
+    }
+
+    /// Parse the legacy text cache: `comment_size` comment lines, then one
+    /// `{hash} {commits} {my_commits} {loc_add} {loc_del}` line per repository.
+    /// Returns `None` if the comment block is cut short or any data line
+    /// doesn't match that layout.
+    pub fn from_legacy(contents: &str, comment_size: usize) -> Option<Self> {
+        let lines: Vec<&str> = contents.lines().collect();
+        if lines.len() < comment_size {
+            return None;
+        }
+        let (comment, data) = lines.split_at(comment_size);
+
+        let generated_at = comment
+            .iter()
+            .find_map(|line| line.strip_prefix(GENERATED_AT_PREFIX)?.trim().parse().ok())
+            .unwrap_or(0);
+
+        Some(LocCache {
+            version: CACHE_VERSION,
+            generated_at,
+            comment: comment
+                .iter()
+                .filter(|line| !line.starts_with(GENERATED_AT_PREFIX))
+                .map(|line| line.to_string())
+                .collect(),
+            repos: data
+                .iter()
+                .map(|line| CachedRepo::from_legacy_line(line))
+                .collect::<Option<_>>()?,
+        })
+    }
+
+    /// Write the cache as JSON. Does nothing on a dry run.
+    pub fn save(&self, user_name: &str) -> Result<(), StatsError> {
+        if *DRY_RUN {
+            return Ok(());
+        }
+
+        write_atomic(cache_file(user_name), &serde_json::to_string_pretty(self)?)?;
+
+        // What a failed count left behind is superseded by the saved cache
+        match fs::remove_file(partial_file(user_name)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the entries with zeroed ones for `edges` and mark the cache
+    /// as generated now.
+    pub fn flush(&mut self, edges: &[Value]) {
+        self.repos = edges
+            .iter()
+            .filter_map(|edge| edge.pointer("/node/nameWithOwner")?.as_str())
+            .map(|name_with_owner| CachedRepo {
+                hash: name_hash(name_with_owner),
+                ..CachedRepo::default()
+            })
+            .collect();
+        self.generated_at = unix_now();
+    }
+
+    /// Whether the cache was generated more than `ttl_hours` ago.
+    pub fn expired(&self, ttl_hours: u64) -> bool {
+        unix_now().saturating_sub(self.generated_at) > ttl_hours * 3600
+    }
+
+    /// Drop the entries of repositories that aren't among `edges` anymore,
+    /// e.g. deleted or renamed ones. Returns how many were dropped.
+    pub fn vacuum(&mut self, edges: &[Value]) -> usize {
+        let live: HashSet<String> = edges
+            .iter()
+            .filter_map(|edge| edge.pointer("/node/nameWithOwner")?.as_str())
+            .map(name_hash)
+            .collect();
+
+        let before = self.repos.len();
+        self.repos.retain(|repo| live.contains(&repo.hash));
+        before - self.repos.len()
+    }
+
+    /// Replace the comment block with `template`, one line per line of it.
+    /// `{user}` and `{generated_at}` are filled in with the user name and the
+    /// Unix time the cache was generated at.
+    pub fn set_comment(&mut self, template: &str, user_name: &str) {
+        let comment = template
+            .replace("{user}", user_name)
+            .replace("{generated_at}", &self.generated_at.to_string());
+
+        // A literal `\n` counts as a line break, as newlines are awkward in .env
+        self.comment = comment
+            .replace("\\n", "\n")
+            .lines()
+            .map(str::to_string)
+            .collect();
+    }
+
+    /// Lines added and deleted over all entries.
+    pub fn loc_totals(&self) -> (i64, i64) {
+        self.repos.iter().fold((0, 0), |(add, del), repo| {
+            (add + repo.loc_add, del + repo.loc_del)
+        })
+    }
+
+    /// Whether each entry belongs to the edge at the same position.
+    pub fn matches(&self, edges: &[Value]) -> bool {
+        self.repos.len() == edges.len()
+            && self.repos.iter().zip(edges).all(|(repo, edge)| {
+                edge.pointer("/node/nameWithOwner")
+                    .and_then(|v| v.as_str())
+                    .is_some_and(|name| name_hash(name) == repo.hash)
+            })
+    }
+}
+
+impl CachedRepo {
+    /// Parse a `{hash} {commits} {my_commits} {loc_add} {loc_del}` line of the
+    /// text cache or archive. Numeric fields go through [`parse_field`].
+    pub fn from_legacy_line(line: &str) -> Option<Self> {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [hash, commits, my_commits, loc_add, loc_del, ..] => Some(CachedRepo {
+                hash: hash.to_string(),
+                commits: parse_field(commits)?,
+                my_commits: parse_field(my_commits)?,
+                loc_add: parse_field(loc_add)?,
+                loc_del: parse_field(loc_del)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a numeric field of the text cache or archive, ignoring surrounding
+/// whitespace and a single trailing `,`. `None` if it isn't a number.
+pub fn parse_field(field: &str) -> Option<i64> {
+    let field = field.trim();
+    field.strip_suffix(',').unwrap_or(field).trim().parse().ok()
+}
+
+/// The cached account data of `user_name`, if any. A corrupted owner cache
+/// is ignored, so the data gets fetched again.
+pub fn load_owner(user_name: &str) -> Option<CachedOwner> {
+    let contents = fs::read_to_string(owner_file()).ok()?;
+    match serde_json::from_str::<HashMap<String, CachedOwner>>(&contents) {
+        Ok(mut owners) => owners.remove(user_name),
+        Err(err) => {
+            warn!(
+                "The owner cache {} is corrupted ({}), ignoring it",
+                owner_file().display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Add or replace the cached account data of `user_name`, keeping the other
+/// users'. Does nothing on a dry run.
+pub fn save_owner(user_name: &str, owner: &CachedOwner) -> Result<(), StatsError> {
+    if *DRY_RUN {
+        return Ok(());
+    }
+
+    let mut owners: HashMap<String, CachedOwner> = fs::read_to_string(owner_file())
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default();
+    owners.insert(user_name.to_string(), owner.clone());
+
+    fs::create_dir_all(&*CACHE_DIR)?;
+    write_atomic(owner_file(), &serde_json::to_string_pretty(&owners)?)
+}
+
+/// SHA-256 hex digest of a user or repository name, as used in the cache.
+pub fn name_hash(name: &str) -> String {
+    hex::encode(Sha256::digest(name.as_bytes()))
+}
+
+/// Path of the JSON cache of `user_name`. With `--loc-since` the window is
+/// part of the key, so totals over different windows are kept apart.
+pub fn cache_file(user_name: &str) -> PathBuf {
+    cache_path(&format!("{}.json", name_hash(&cache_key(user_name))))
+}
+
+/// The current Unix time in seconds.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Path of the state a failed LOC count of `user_name` left behind. It is
+/// kept apart from the cache, which a partial count must not overwrite.
+pub fn partial_file(user_name: &str) -> PathBuf {
+    cache_file(user_name).with_extension("partial")
+}
+
+/// What the LOC cache of `user_name` is keyed on: the user, and the window
+/// with `--loc-since`.
+pub fn cache_key(user_name: &str) -> String {
+    match CONFIG.loc_since_timestamp() {
+        Some(since) => format!("{}@{}", user_name, since),
+        None => user_name.to_string(),
+    }
+}
+
+// Net LOC of past runs, one JSON object per line, in the cache directory
+pub const LOC_HISTORY_FILE: &str = "loc_history.jsonl";
+
+// Runs kept in the LOC history; older ones are dropped
+const LOC_HISTORY_LIMIT: usize = 365;
+
+/// Net LOC of one run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocRun {
+    // Unix time (seconds) of the run
+    pub timestamp: u64,
+    pub loc: i64,
+}
+
+/// Add a run to the LOC history and return the history, oldest first.
+/// Unreadable lines are skipped, and only the last `LOC_HISTORY_LIMIT` runs
+/// are kept. Dry runs don't write the file.
+pub fn record_loc_run(loc: i64) -> Result<Vec<LocRun>, StatsError> {
+    let path = cache_path(LOC_HISTORY_FILE);
+    let mut history: Vec<LocRun> = fs::read_to_string(&path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+
+    history.push(LocRun {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        loc,
+    });
+    let excess = history.len().saturating_sub(LOC_HISTORY_LIMIT);
+    history.drain(..excess);
+
+    if !*DRY_RUN {
+        let mut contents = String::new();
+        for run in &history {
+            contents.push_str(&serde_json::to_string(run)?);
+            contents.push('\n');
+        }
+        fs::create_dir_all(&*CACHE_DIR)?;
+        write_atomic(&path, &contents)?;
+    }
+
+    Ok(history)
+}
+
+// Stats of the last run, in the cache directory, which the next run shows
+// its changes against
+pub const LAST_STATS_FILE: &str = "last_stats.json";
+
+/// Record the stats of this run and return those of the last one, if any
+/// were recorded. Stats this run didn't query keep their last value, so that
+/// `--only` runs don't lose them. Dry runs don't write the file.
+pub fn record_last_stats(stats: &StatsSnapshot) -> Result<Option<StatsSnapshot>, StatsError> {
+    let path = cache_path(LAST_STATS_FILE);
+    let previous: Option<Value> = fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok());
+
+    let mut current = serde_json::to_value(stats)?;
+    if let (Some(current), Some(Value::Object(previous))) = (current.as_object_mut(), &previous) {
+        for (key, value) in current.iter_mut() {
+            if value.is_null()
+                && let Some(last) = previous.get(key)
+            {
+                *value = last.clone();
+            }
+        }
+    }
+
+    if !*DRY_RUN {
+        fs::create_dir_all(&*CACHE_DIR)?;
+        write_atomic(&path, &serde_json::to_string_pretty(&current)?)?;
+    }
+
+    Ok(previous.and_then(|previous| serde_json::from_value(previous).ok()))
+}
+
+// Where recounts that failed part way through left off, per repository, in
+// the cache directory
+pub const RESUME_FILE: &str = "loc_resume.json";
+
+// Serializes the read-modify-write of the resume file between the repositories
+// that are recounted at once
+static RESUME_LOCK: Mutex<()> = Mutex::new(());
+
+/// How far a failed recount of one repository got: the cursor after the last
+/// page it counted and the totals up to there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumePoint {
+    // Cache key (users and LOC window) the totals were counted for
+    pub cache_key: String,
+    // Commits of the repository when the recount started. Pages shift when
+    // commits are added, so the point only applies while this is unchanged.
+    pub commits: i64,
+    pub cursor: String,
+    pub loc_add: usize,
+    pub loc_del: usize,
+    pub my_commits: usize,
+}
+
+/// The point a recount of `name_with_owner` can resume from, if it failed
+/// before with the same cache key and commit count.
+pub fn load_resume(name_with_owner: &str, cache_key: &str, commits: i64) -> Option<ResumePoint> {
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    read_resume_points()
+        .remove(&name_hash(name_with_owner))
+        .filter(|point| point.cache_key == cache_key && point.commits == commits)
+}
+
+/// Record where a recount of `name_with_owner` left off, or with `None`
+/// forget it once the repository was counted in full. Dry runs don't write.
+pub fn save_resume(name_with_owner: &str, point: Option<ResumePoint>) -> Result<(), StatsError> {
+    if *DRY_RUN {
+        return Ok(());
+    }
+
+    let _guard = RESUME_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+    let mut points = read_resume_points();
+    let hash = name_hash(name_with_owner);
+    let changed = match point {
+        Some(point) => points.insert(hash, point.clone()) != Some(point),
+        None => points.remove(&hash).is_some(),
+    };
+    if !changed {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&*CACHE_DIR)?;
+    write_atomic(
+        cache_path(RESUME_FILE),
+        &serde_json::to_string_pretty(&points)?,
+    )
+}
+
+/// Every saved resume point by repository hash. A corrupted file is ignored.
+fn read_resume_points() -> HashMap<String, ResumePoint> {
+    fs::read_to_string(cache_path(RESUME_FILE))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Exclusive lock on the LOC cache of one user, released when dropped.
+pub struct CacheLock {
+    _file: File,
+}
+
+/// Lock the LOC cache of `user_name` against other runs, waiting for one that
+/// holds it to finish. Held around loading, recounting and saving the cache,
+/// so overlapping runs can't interleave their writes.
+pub fn lock_cache(user_name: &str) -> Result<CacheLock, StatsError> {
+    let path = cache_file(user_name).with_extension("lock");
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            info!("Waiting for another run to release {}", path.display());
+            file.lock()?;
+        }
+        Err(TryLockError::Error(err)) => return Err(err.into()),
+    }
+
+    Ok(CacheLock { _file: file })
+}
+
+/// Path of the owner cache shared by all users.
+fn owner_file() -> PathBuf {
+    cache_path("owner.json")
+}
+
+/// Path of the text cache used before the JSON one.
+fn legacy_cache_file(user_name: &str) -> PathBuf {
+    cache_path(&format!("{}.txt", name_hash(user_name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn legacy_lines_may_end_their_fields_with_commas() {
+        assert_eq!(
+            CachedRepo::from_legacy_line("abc 10, 4, 120, 30,"),
+            Some(CachedRepo {
+                hash: "abc".to_string(),
+                commits: 10,
+                my_commits: 4,
+                loc_add: 120,
+                loc_del: 30,
+            })
+        );
+        assert_eq!(
+            CachedRepo::from_legacy_line("abc 10 4 120 30"),
+            CachedRepo::from_legacy_line("abc 10, 4, 120, 30,")
+        );
+        assert_eq!(CachedRepo::from_legacy_line("abc 10 4 120"), None);
+        assert_eq!(CachedRepo::from_legacy_line("abc 10 four 120 30"), None);
+    }
+
+    #[test]
+    fn legacy_caches_keep_the_users_commits_in_the_third_column() {
+        let contents = "comment\nGenerated at 1700000000\naaa 50 3 100 10\nbbb 20 9 40 4\n";
+
+        let cache = LocCache::from_legacy(contents, 2).unwrap();
+
+        assert_eq!(cache.generated_at, 1700000000);
+        assert_eq!(cache.comment, vec!["comment"]);
+        assert_eq!(
+            cache.repos.iter().map(|repo| repo.my_commits).sum::<i64>(),
+            12
+        );
+        assert_eq!(cache.loc_totals(), (140, 14));
+    }
+
+    #[test]
+    fn saved_caches_load_back_unchanged() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        let mut cache = LocCache::new(2);
+        cache.set_comment("First line\\nSecond line", "round-trip");
+        cache.repos = vec![
+            CachedRepo::from_legacy_line("aaa 50 3 100 10").unwrap(),
+            CachedRepo::from_legacy_line("bbb 20 9 40 4").unwrap(),
+        ];
+
+        cache.save("round-trip").unwrap();
+        let loaded = LocCache::load("round-trip", 2).unwrap().unwrap();
+
+        assert_eq!(loaded.comment, vec!["First line", "Second line"]);
+        assert_eq!(loaded.repos, cache.repos);
+        assert_eq!(loaded.version, CACHE_VERSION);
+    }
+
+    #[test]
+    fn flush_zeroes_the_entries_and_restarts_the_ttl() {
+        let mut cache = LocCache::from_legacy("comment\naaa 50 3 100 10\n", 1).unwrap();
+        cache.generated_at = unix_now() - 3 * 3600;
+        assert!(cache.expired(2));
+        assert!(!cache.expired(4));
+
+        cache.flush(&[json!({ "node": { "nameWithOwner": "octo/hello" } })]);
+
+        assert!(!cache.expired(1));
+        assert_eq!(
+            cache.repos,
+            vec![CachedRepo {
+                hash: name_hash("octo/hello"),
+                ..CachedRepo::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn legacy_text_caches_are_upgraded_to_json() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        fs::write(legacy_cache_file("legacy"), "comment\naaa 50 3 100 10\n").unwrap();
+
+        let cache = LocCache::load("legacy", 1).unwrap().unwrap();
+
+        assert!(!legacy_cache_file("legacy").exists());
+        let saved: Value =
+            serde_json::from_str(&fs::read_to_string(cache_file("legacy")).unwrap()).unwrap();
+        assert_eq!(saved["version"], CACHE_VERSION);
+        assert_eq!(saved["repos"][0]["my_commits"], 3);
+        assert_eq!(
+            LocCache::load("legacy", 1).unwrap().unwrap().repos,
+            cache.repos
+        );
+    }
+
+    #[test]
+    fn corrupted_caches_are_rebuilt_rather_than_read() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        fs::write(
+            cache_file("truncated"),
+            r#"{"version": 1, "repos": [{"hash": "#,
+        )
+        .unwrap();
+
+        assert!(LocCache::load("truncated", 1).unwrap().is_none());
+        // A comment block cut short, and a data line missing a field
+        assert!(LocCache::from_legacy("comment\n", 2).is_none());
+        assert!(LocCache::from_legacy("comment\naaa 50 3 100\n", 1).is_none());
+    }
+
+    #[test]
+    fn custom_comments_fill_in_the_user_and_leave_the_data_alone() {
+        let mut cache = LocCache::from_legacy("comment\naaa 50 3 100 10\n", 1).unwrap();
+        cache.generated_at = 1700000000;
+
+        cache.set_comment(
+            "Cache of {user}\\nGenerated {generated_at}\nKeep out",
+            "octo",
+        );
+
+        assert_eq!(
+            cache.comment,
+            vec!["Cache of octo", "Generated 1700000000", "Keep out"]
+        );
+        assert_eq!(cache.repos.len(), 1);
+        assert_eq!(cache.loc_totals(), (100, 10));
+    }
+
+    #[test]
+    fn numeric_fields_allow_whitespace_and_one_trailing_comma() {
+        assert_eq!(parse_field("123,"), Some(123));
+        assert_eq!(parse_field(" 123 "), Some(123));
+        assert_eq!(parse_field(" -7 , "), Some(-7));
+        assert_eq!(parse_field("123,,"), None);
+        assert_eq!(parse_field("abc"), None);
+        assert_eq!(parse_field(""), None);
+    }
+
+    #[test]
+    fn vacuum_drops_only_the_stale_entries() {
+        let entry = |name: &str| CachedRepo {
+            hash: name_hash(name),
+            loc_add: 10,
+            ..CachedRepo::default()
+        };
+        let mut cache = LocCache::new(1);
+        cache.repos = vec![
+            entry("octo/live"),
+            entry("octo/deleted"),
+            entry("octo/renamed"),
+        ];
+        let edges = [
+            json!({ "node": { "nameWithOwner": "octo/live" } }),
+            json!({ "node": { "nameWithOwner": "octo/new-name" } }),
+        ];
+
+        assert_eq!(cache.vacuum(&edges), 2);
+        assert_eq!(cache.repos, vec![entry("octo/live")]);
+        assert_eq!(cache.vacuum(&edges), 0);
+    }
+
+    #[test]
+    fn locked_read_modify_writes_dont_lose_updates() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        LocCache::new(1).save("locked").unwrap();
+
+        let writers: Vec<_> = ["octo/one", "octo/two"]
+            .into_iter()
+            .map(|name| {
+                std::thread::spawn(move || {
+                    let _lock = lock_cache("locked").unwrap();
+                    let mut cache = LocCache::load("locked", 1).unwrap().unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                    cache.repos.push(CachedRepo {
+                        hash: name_hash(name),
+                        ..CachedRepo::default()
+                    });
+                    cache.save("locked").unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let cache = LocCache::load("locked", 1).unwrap().unwrap();
+        assert_eq!(cache.repos.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn loc_runs_are_appended_to_the_history() {
+        let _lock = crate::utility::LOC_TEST_LOCK.lock().await;
+
+        for loc in [100, 150] {
+            record_loc_run(loc).unwrap();
+        }
+        let history = record_loc_run(130).unwrap();
+
+        let saved = fs::read_to_string(cache_path(LOC_HISTORY_FILE)).unwrap();
+        assert_eq!(saved.lines().count(), history.len());
+        let locs: Vec<i64> = history.iter().map(|run| run.loc).collect();
+        assert_eq!(locs[locs.len() - 3..], [100, 150, 130]);
+        assert!(history.len() <= LOC_HISTORY_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn last_stats_keep_what_a_run_did_not_query() {
+        let _lock = crate::utility::LOC_TEST_LOCK.lock().await;
+        let _ = fs::remove_file(cache_path(LAST_STATS_FILE));
+
+        let first = StatsSnapshot {
+            stars: Some(10),
+            commits: Some(200),
+            ..Default::default()
+        };
+        assert!(record_last_stats(&first).unwrap().is_none());
+
+        let stars_only = StatsSnapshot {
+            stars: Some(12),
+            ..Default::default()
+        };
+        let last = record_last_stats(&stars_only).unwrap().unwrap();
+        assert_eq!((last.stars, last.commits), (Some(10), Some(200)));
+
+        let last = record_last_stats(&StatsSnapshot::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!((last.stars, last.commits), (Some(12), Some(200)));
+    }
+}
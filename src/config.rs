@@ -0,0 +1,673 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, SecondsFormat, Utc};
+use clap::Parser;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::StatsError,
+    exports::{AFFILIATIONS, FORMATS, QUERY_NAMES},
+};
+
+/// Command line options. Options that aren't given fall back to the
+/// environment, which `.env` is loaded into first.
+#[derive(Debug, Parser)]
+#[command(about = "Render GitHub stats into the profile SVGs")]
+pub struct Config {
+    /// GitHub user to collect stats for. Several comma-separated users, e.g.
+    /// a personal and a work account, are combined into one card.
+    #[arg(long = "user", env = "USER_NAME")]
+    pub user_name: Option<String>,
+
+    /// GitHub access token
+    #[arg(long, env = "ACCESS_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// File to read the GitHub access token from, e.g. a mounted secret.
+    /// Takes precedence over `--token`.
+    #[arg(long, env = "ACCESS_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+
+    /// User-Agent sent with every GitHub request
+    #[arg(
+        long,
+        env = "USER_AGENT",
+        default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))
+    )]
+    pub user_agent: String,
+
+    /// Proxy for all GitHub requests, e.g. `http://proxy:8080`. Overrides
+    /// HTTPS_PROXY/HTTP_PROXY, which are used otherwise; NO_PROXY still applies.
+    #[arg(long, value_parser = parse_proxy)]
+    pub proxy: Option<String>,
+
+    /// Seconds before a GitHub request is given up on
+    #[arg(long, env = "REQUEST_TIMEOUT_SECS", default_value_t = 30, value_parser = parse_positive::<u64>)]
+    pub request_timeout_secs: u64,
+
+    /// Comment block of the LOC cache. `{user}` and `{generated_at}` are
+    /// filled in, and `\n` starts a new line.
+    #[arg(long, env = "CACHE_COMMENT")]
+    pub cache_comment: Option<String>,
+
+    /// Directory holding the LOC cache and archive
+    #[arg(long, env = "CACHE_DIR", default_value = "cache")]
+    pub cache_dir: PathBuf,
+
+    /// Hours after which the LOC cache is rebuilt from scratch. It never
+    /// expires unless given.
+    #[arg(long, env = "CACHE_TTL_HOURS")]
+    pub cache_ttl_hours: Option<u64>,
+
+    /// SVGs to render, as theme=path pairs. Each is filled from the template
+    /// of the same file name in `--template-dir`.
+    #[arg(
+        long = "svg",
+        num_args = 1..,
+        value_parser = parse_theme_path,
+        default_values = ["dark=src/dark_mode.svg", "light=src/light_mode.svg"]
+    )]
+    pub svgs: Vec<(String, PathBuf)>,
+
+    /// Directory holding the SVG templates, which are only ever read
+    #[arg(long, env = "TEMPLATE_DIR", default_value = "templates")]
+    pub template_dir: PathBuf,
+
+    /// Directory to write the rendered SVGs to, under the file names of their
+    /// templates, instead of the `--svg` paths
+    #[arg(long, env = "OUTPUT_DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Also rasterize the SVG of a theme to PNG, as theme=path pairs. Needs
+    /// the `png` feature.
+    #[arg(long = "png", num_args = 1.., value_parser = parse_theme_path)]
+    pub pngs: Vec<(String, PathBuf)>,
+
+    /// Scale of the PNGs relative to the SVG size
+    #[arg(long, default_value_t = 1.0)]
+    pub png_scale: f32,
+
+    /// Only write the SVGs of these themes, e.g. `--themes dark`
+    #[arg(long, value_delimiter = ',')]
+    pub themes: Option<Vec<String>>,
+
+    /// Affiliations whose repositories count towards the stars, e.g.
+    /// `OWNER,COLLABORATOR,ORGANIZATION_MEMBER`
+    #[arg(
+        long,
+        env = "STAR_AFFILIATIONS",
+        value_delimiter = ',',
+        default_value = "OWNER",
+        value_parser = clap::builder::PossibleValuesParser::new(AFFILIATIONS)
+    )]
+    pub star_affiliations: Vec<String>,
+
+    /// Count forks towards LOC and stars
+    #[arg(long, env = "INCLUDE_FORKS")]
+    pub include_forks: bool,
+
+    /// Count archived repositories towards LOC and stars
+    #[arg(long, env = "INCLUDE_ARCHIVED")]
+    pub include_archived: bool,
+
+    /// Repositories (owner/name) to compute stars, LOC and commits over
+    /// instead of everything the user is affiliated with
+    #[arg(long, env = "REPOS", value_delimiter = ',')]
+    pub repos: Vec<String>,
+
+    /// Report LOC straight from the existing cache, without recounting any
+    /// repository even if its commits changed
+    #[arg(long, env = "CACHE_ONLY", conflicts_with = "force_repos")]
+    pub cache_only: bool,
+
+    /// Never talk to GitHub: render LOC and commits from the cache and leave
+    /// every other stat as the SVGs already show it
+    #[arg(long, env = "OFFLINE", conflicts_with_all = ["force_repos", "refresh_owner"])]
+    pub offline: bool,
+
+    /// Print the repository, star and member counts of this organization
+    /// instead of rendering the stats of a user
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["cache_only", "offline"])]
+    pub org: Option<String>,
+
+    /// Drop LOC cache entries of repositories that no longer exist or were
+    /// renamed before counting
+    #[arg(long, conflicts_with_all = ["cache_only", "offline"])]
+    pub vacuum: bool,
+
+    /// Print the commits and LOC of each repository after counting
+    #[arg(long)]
+    pub per_repo: bool,
+
+    /// Repositories fetched per page by the LOC, star and repository count
+    /// queries, at most 100. Smaller pages help when they time out.
+    #[arg(long, env = "PAGE_SIZE", value_parser = parse_page_size)]
+    pub page_size: Option<usize>,
+
+    /// Repositories whose commit history is walked at once when counting LOC
+    #[arg(long, env = "LOC_CONCURRENCY", default_value_t = 4, value_parser = parse_positive::<usize>)]
+    pub loc_concurrency: usize,
+
+    /// Stop listing repositories for LOC after this many, to bound the API
+    /// calls for accounts with thousands of them. Off unless given.
+    #[arg(long, env = "MAX_REPOS")]
+    pub max_repos: Option<usize>,
+
+    /// Repositories (owner/name) whose LOC is recounted even if the cache
+    /// looks up to date
+    #[arg(long, env = "FORCE_REPOS", value_delimiter = ',')]
+    pub force_repos: Vec<String>,
+
+    /// Only count repositories with one of these topics, e.g. `showcase`
+    #[arg(long, env = "INCLUDE_TOPICS", value_delimiter = ',')]
+    pub include_topics: Vec<String>,
+
+    /// Leave repositories with any of these topics out
+    #[arg(long, env = "EXCLUDE_TOPICS", value_delimiter = ',')]
+    pub exclude_topics: Vec<String>,
+
+    /// Repositories (owner/name) to leave out of the LOC totals
+    #[arg(long, env = "EXCLUDE_REPOS", value_delimiter = ',')]
+    pub exclude_repos: Vec<String>,
+
+    /// Run the queries and print the results without writing the SVGs or
+    /// the cache
+    #[arg(long, env = "DRY_RUN")]
+    pub dry_run: bool,
+
+    /// What to output: `table` (the timing table), `json` (the stats on
+    /// stdout) and/or `svg` (the rendered SVGs)
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "table,svg",
+        value_parser = clap::builder::PossibleValuesParser::new(FORMATS)
+    )]
+    pub format: Vec<String>,
+
+    /// Only run these queries; the others keep the values already in the SVGs
+    #[arg(long, value_delimiter = ',', value_parser = clap::builder::PossibleValuesParser::new(QUERY_NAMES))]
+    pub only: Option<Vec<String>>,
+
+    /// Languages to leave out of the top languages, e.g. `HTML,CSS`
+    #[arg(long, env = "EXCLUDE_LANGUAGES", value_delimiter = ',')]
+    pub exclude_languages: Vec<String>,
+
+    /// Only keep this many of the largest languages. Their shares are of the
+    /// languages kept, not of all the user's code.
+    #[arg(long, env = "TOP_LANGUAGES")]
+    pub top_languages: Option<usize>,
+
+    /// Only count LOC and commits since this ISO 8601 time or date, e.g.
+    /// `2024-01-01T00:00:00Z` or `2024-01-01`. Each window gets its own cache.
+    #[arg(long, env = "LOC_SINCE", value_parser = parse_timestamp)]
+    pub loc_since: Option<DateTime<Utc>>,
+
+    /// Count contributions from this ISO 8601 time or date instead of a year
+    /// ago, e.g. `2024-01-01T00:00:00Z` or `2024-01-01`
+    #[arg(long, value_parser = parse_timestamp)]
+    pub contributions_from: Option<DateTime<Utc>>,
+
+    /// Count contributions up to this ISO 8601 time or date instead of now
+    #[arg(long, value_parser = parse_timestamp)]
+    pub contributions_to: Option<DateTime<Utc>>,
+
+    /// Fetch the account id and creation date again instead of reading them
+    /// from the owner cache
+    #[arg(long)]
+    pub refresh_owner: bool,
+
+    /// Also print debug logs
+    #[arg(short, long, conflicts_with = "quiet")]
+    pub verbose: bool,
+
+    /// Only print warnings and errors, no timing table or query counts
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Check the setup (env, token, rate limit, templates and cache
+    /// directory) instead of rendering, and exit non-zero if anything fails
+    #[arg(long)]
+    pub doctor: bool,
+
+    /// Check the LOC cache against a fresh listing of the repositories
+    /// instead of rendering, and exit non-zero if they disagree
+    #[arg(long, conflicts_with_all = ["cache_only", "offline"])]
+    pub verify_cache: bool,
+
+    /// Directory to write Shields.io endpoint files for the stars, commits
+    /// and LOC badges to
+    #[arg(long, env = "SHIELDS_DIR")]
+    pub shields_dir: Option<PathBuf>,
+
+    /// Also write the stats as a table into this README, between its
+    /// `<!--START_STATS-->` and `<!--END_STATS-->` markers
+    #[arg(long, env = "README_PATH", value_name = "FILE")]
+    pub readme: Option<PathBuf>,
+
+    /// POST the stats as JSON to this URL, e.g. an endpoint of your own
+    /// service, after rendering
+    #[arg(long, env = "POST_URL")]
+    pub post_url: Option<String>,
+
+    /// Authorization header sent with `--post-url`, e.g. `Bearer <token>`
+    #[arg(long, env = "POST_AUTH", hide_env_values = true, requires = "post_url")]
+    pub post_auth: Option<String>,
+
+    /// Print the index, id and text of every tspan in this SVG instead of
+    /// rendering
+    #[arg(long, value_name = "FILE")]
+    pub list_tspans: Option<PathBuf>,
+
+    /// Also write the stats as JSON to this file
+    #[arg(long, env = "STATS_JSON")]
+    pub stats_json: Option<PathBuf>,
+
+    /// Write a JSON report of the run to this file, also when it fails
+    #[arg(long)]
+    pub report_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// The users to collect stats for, from `--user` or USER_NAME.
+    pub fn user_names(&self) -> Result<Vec<&str>, StatsError> {
+        let user_names: Vec<&str> = self
+            .user_name
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+
+        if user_names.is_empty() {
+            return Err(StatsError::MissingEnv("USER_NAME".to_string()));
+        }
+        Ok(user_names)
+    }
+
+    /// The users joined with commas, which keys their combined LOC cache.
+    pub fn users_key(&self) -> Result<String, StatsError> {
+        Ok(self.user_names()?.join(","))
+    }
+
+    /// The `--repos` list without blank entries.
+    pub fn custom_repos(&self) -> Vec<String> {
+        self.repos
+            .iter()
+            .map(|repo| repo.trim().to_string())
+            .filter(|repo| !repo.is_empty())
+            .collect()
+    }
+
+    /// The first of the users, whose account the queries that aren't
+    /// combined over all of them run for.
+    pub fn user_name(&self) -> Result<&str, StatsError> {
+        Ok(self.user_names()?[0])
+    }
+
+    /// `--loc-since` as the ISO 8601 timestamp GitHub takes, e.g.
+    /// `2024-01-01T00:00:00Z`.
+    pub fn loc_since_timestamp(&self) -> Option<String> {
+        self.loc_since.as_ref().map(github_timestamp)
+    }
+
+    /// `--contributions-from` and `--contributions-to` as the ISO 8601
+    /// timestamps GitHub takes. Fails if the range ends before it starts.
+    pub fn contributions_range(&self) -> Result<(Option<String>, Option<String>), StatsError> {
+        if let (Some(from), Some(to)) = (self.contributions_from, self.contributions_to)
+            && from > to
+        {
+            return Err(StatsError::Invalid(format!(
+                "--contributions-from {} is after --contributions-to {}",
+                github_timestamp(&from),
+                github_timestamp(&to)
+            )));
+        }
+
+        Ok((
+            self.contributions_from.as_ref().map(github_timestamp),
+            self.contributions_to.as_ref().map(github_timestamp),
+        ))
+    }
+
+    /// Whether `--format` includes `format`.
+    pub fn has_format(&self, format: &str) -> bool {
+        self.format.iter().any(|f| f == format)
+    }
+
+    /// Whether LOC comes from the cache as it is, with `--cache-only` or
+    /// `--offline`.
+    pub fn loc_cache_only(&self) -> bool {
+        self.cache_only || self.offline
+    }
+
+    /// The `--svg` targets selected by `--themes`, all of them by default.
+    pub fn svg_targets(&self) -> Result<Vec<&(String, PathBuf)>, StatsError> {
+        let Some(themes) = &self.themes else {
+            return Ok(self.svgs.iter().collect());
+        };
+
+        for theme in themes {
+            if !self.svgs.iter().any(|(name, _)| name == theme) {
+                return Err(StatsError::Invalid(format!(
+                    "Unknown theme \"{}\" in --themes. Use one of: {}",
+                    theme,
+                    self.svgs
+                        .iter()
+                        .map(|(name, _)| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )));
+            }
+        }
+
+        Ok(self
+            .svgs
+            .iter()
+            .filter(|(name, _)| themes.contains(name))
+            .collect())
+    }
+
+    /// The template an `--svg` path is filled from and the file it is
+    /// rendered to, following `--template-dir` and `--output-dir`. Fails if
+    /// the two are the same file, as the template would be overwritten.
+    pub fn svg_paths(&self, path: &Path) -> Result<(PathBuf, PathBuf), StatsError> {
+        let file_name = path.file_name().ok_or_else(|| {
+            StatsError::Invalid(format!("--svg path {} has no file name", path.display()))
+        })?;
+
+        let template = self.template_dir.join(file_name);
+        let output = match &self.output_dir {
+            Some(output_dir) => output_dir.join(file_name),
+            None => path.to_path_buf(),
+        };
+
+        let same_file = match (fs::canonicalize(&template), fs::canonicalize(&output)) {
+            (Ok(template), Ok(output)) => template == output,
+            _ => template == output,
+        };
+        if same_file {
+            return Err(StatsError::Invalid(format!(
+                "{} would overwrite its own template. Point --template-dir or --output-dir \
+                 somewhere else.",
+                output.display()
+            )));
+        }
+
+        Ok((template, output))
+    }
+}
+
+/// Check that a `--proxy` value is a usable proxy URL.
+fn parse_proxy(value: &str) -> Result<String, String> {
+    reqwest::Proxy::all(value)
+        .map(|_| value.to_string())
+        .map_err(|err| err.to_string())
+}
+
+/// Parse an ISO 8601 time with an offset, or a date taken as midnight UTC.
+fn parse_timestamp(value: &str) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc())
+        .map_err(|_| {
+            format!(
+                "expected an ISO 8601 time like 2024-01-01T00:00:00Z or a date like 2024-01-01, got \"{}\"",
+                value
+            )
+        })
+}
+
+/// A time as the ISO 8601 timestamp GitHub takes, e.g. `2024-01-01T00:00:00Z`.
+fn github_timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Parse a number that must be at least 1.
+fn parse_positive<T>(value: &str) -> Result<T, String>
+where
+    T: std::str::FromStr + PartialOrd + From<u8>,
+    T::Err: std::fmt::Display,
+{
+    let number: T = value
+        .trim()
+        .parse()
+        .map_err(|err: T::Err| err.to_string())?;
+    if number < T::from(1) {
+        return Err("must be at least 1".to_string());
+    }
+    Ok(number)
+}
+
+/// Parse a page size within the 1..=100 GitHub accepts.
+fn parse_page_size(value: &str) -> Result<usize, String> {
+    let size = parse_positive::<usize>(value)?;
+    if size > 100 {
+        return Err("must be at most 100".to_string());
+    }
+    Ok(size)
+}
+
+/// Parse a `--svg` or `--png` value of the form `theme=path`.
+fn parse_theme_path(value: &str) -> Result<(String, PathBuf), String> {
+    let (theme, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected theme=path, got \"{}\"", value))?;
+
+    Ok((theme.trim().to_string(), PathBuf::from(path.trim())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exports::{CONFIG, DRY_RUN, cache_path};
+
+    fn parse(args: &[&str]) -> Result<Config, clap::Error> {
+        Config::try_parse_from(["test"].iter().chain(args))
+    }
+
+    #[test]
+    fn cache_files_live_in_the_cache_dir() {
+        let config = parse(&["--user", "octo", "--cache-dir", "/srv/stats"]).unwrap();
+        assert_eq!(config.cache_dir, PathBuf::from("/srv/stats"));
+
+        assert_eq!(
+            cache_path("owner.json"),
+            CONFIG.cache_dir.join("owner.json")
+        );
+    }
+
+    #[test]
+    fn dry_run_is_a_flag() {
+        assert!(parse(&["--user", "octo", "--dry-run"]).unwrap().dry_run);
+        assert!(parse(&["--user", "octo", "--dry-run=false"]).is_err());
+        // The test config renders for real, which the other tests rely on
+        assert!(!*DRY_RUN);
+    }
+
+    #[test]
+    fn options_parse_from_argv() {
+        let config = parse(&[
+            "--user",
+            "octo, work-octo",
+            "--token",
+            "ghp_test",
+            "--svg",
+            "dark=out/dark.svg",
+            "light = out/light.svg",
+            "--loc-concurrency",
+            "8",
+        ])
+        .unwrap();
+
+        assert_eq!(config.user_names().unwrap(), vec!["octo", "work-octo"]);
+        assert_eq!(config.token.as_deref(), Some("ghp_test"));
+        assert_eq!(
+            config.svgs,
+            vec![
+                ("dark".to_string(), PathBuf::from("out/dark.svg")),
+                ("light".to_string(), PathBuf::from("out/light.svg")),
+            ]
+        );
+        assert_eq!(config.loc_concurrency, 8);
+
+        assert!(parse(&["--svg", "out/dark.svg"]).is_err());
+        assert!(parse(&["--loc-concurrency", "0"]).is_err());
+        assert_eq!(parse(&["--page-size", "100"]).unwrap().page_size, Some(100));
+        assert!(parse(&["--page-size", "0"]).is_err());
+        assert!(parse(&["--page-size", "101"]).is_err());
+        assert!(parse(&["--only", "everything"]).is_err());
+    }
+
+    #[test]
+    fn themes_select_which_svgs_are_written() {
+        let names = |config: &Config| -> Vec<String> {
+            config
+                .svg_targets()
+                .unwrap()
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+
+        assert_eq!(names(&parse(&[]).unwrap()), vec!["dark", "light"]);
+        assert_eq!(names(&parse(&["--themes", "dark"]).unwrap()), vec!["dark"]);
+
+        let err = parse(&["--themes", "dark,sepia"])
+            .unwrap()
+            .svg_targets()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown theme \"sepia\" in --themes. Use one of: dark, light"
+        );
+    }
+
+    #[test]
+    fn verbose_and_quiet_conflict() {
+        assert!(parse(&["-q"]).unwrap().quiet);
+        assert!(parse(&["--verbose"]).unwrap().verbose);
+        assert_eq!(
+            parse(&["-v", "-q"]).unwrap_err().kind(),
+            clap::error::ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn loc_since_takes_a_time_or_a_date() {
+        let since =
+            |value: &str| parse(&["--loc-since", value]).map(|config| config.loc_since_timestamp());
+
+        assert_eq!(
+            since("2024-01-01").unwrap().as_deref(),
+            Some("2024-01-01T00:00:00Z")
+        );
+        assert_eq!(
+            since("2024-01-01T05:00:00+02:00").unwrap().as_deref(),
+            Some("2024-01-01T03:00:00Z")
+        );
+        assert!(since("last year").is_err());
+    }
+
+    #[test]
+    fn contributions_ranges_are_parsed_and_ordered() {
+        let config = parse(&[
+            "--contributions-from",
+            "2024-01-01",
+            "--contributions-to",
+            "2024-06-30T12:00:00+02:00",
+        ])
+        .unwrap();
+        assert_eq!(
+            config.contributions_range().unwrap(),
+            (
+                Some("2024-01-01T00:00:00Z".to_string()),
+                Some("2024-06-30T10:00:00Z".to_string())
+            )
+        );
+        assert_eq!(
+            parse(&[]).unwrap().contributions_range().unwrap(),
+            (None, None)
+        );
+
+        assert!(parse(&["--contributions-from", "last year"]).is_err());
+        assert!(matches!(
+            parse(&[
+                "--contributions-from",
+                "2024-06-30",
+                "--contributions-to",
+                "2024-01-01",
+            ])
+            .unwrap()
+            .contributions_range(),
+            Err(StatsError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn svgs_render_into_the_output_dir() {
+        let config = parse(&["--template-dir", "tpl", "--output-dir", "out"]).unwrap();
+
+        let paths: Vec<(PathBuf, PathBuf)> = config
+            .svg_targets()
+            .unwrap()
+            .iter()
+            .map(|(_, path)| config.svg_paths(path).unwrap())
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                (
+                    PathBuf::from("tpl/dark_mode.svg"),
+                    PathBuf::from("out/dark_mode.svg")
+                ),
+                (
+                    PathBuf::from("tpl/light_mode.svg"),
+                    PathBuf::from("out/light_mode.svg")
+                ),
+            ]
+        );
+        // Without it the `--svg` paths are written
+        assert_eq!(
+            parse(&[])
+                .unwrap()
+                .svg_paths(Path::new("src/dark_mode.svg"))
+                .unwrap()
+                .1,
+            PathBuf::from("src/dark_mode.svg")
+        );
+    }
+
+    #[test]
+    fn svgs_may_not_overwrite_their_template() {
+        let config = parse(&["--template-dir", "src"]).unwrap();
+
+        assert!(matches!(
+            config.svg_paths(Path::new("src/dark_mode.svg")),
+            Err(StatsError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn formats_combine_and_default_to_the_table_and_svgs() {
+        let default = parse(&[]).unwrap();
+        assert!(default.has_format("table") && default.has_format("svg"));
+        assert!(!default.has_format("json"));
+
+        let json_only = parse(&["--format", "json"]).unwrap();
+        assert!(json_only.has_format("json"));
+        assert!(!json_only.has_format("table") && !json_only.has_format("svg"));
+
+        let combined = parse(&["--format", "json,svg"]).unwrap();
+        assert_eq!(combined.format, vec!["json", "svg"]);
+
+        assert!(parse(&["--format", "yaml"]).is_err());
+    }
+}
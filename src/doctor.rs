@@ -0,0 +1,177 @@
+use serde_json::json;
+use std::{fs, path::Path};
+
+use crate::{
+    config::Config,
+    error::StatsError,
+    exports::read_token,
+    query::{check_template, check_token_scopes},
+    utility::GraphQlTransport,
+};
+
+/// Run the `--doctor` checks of `config`, printing a pass or fail line for
+/// each. Returns the exit status: 0 if all of them passed, 1 otherwise.
+pub async fn run_doctor(transport: &impl GraphQlTransport, config: &Config) -> i32 {
+    let mut checks: Vec<(String, Result<String, StatsError>)> = vec![
+        (
+            "USER_NAME".to_string(),
+            config.user_name().map(|user| format!("set to {}", user)),
+        ),
+        (
+            "ACCESS_TOKEN".to_string(),
+            match read_token(config) {
+                Ok(Some(_)) if config.token_file.is_some() => Ok("read from file".to_string()),
+                Ok(Some(_)) => Ok("set".to_string()),
+                Ok(None) => Err(StatsError::MissingEnv("ACCESS_TOKEN".to_string())),
+                Err(err) => Err(StatsError::Invalid(err)),
+            },
+        ),
+    ];
+
+    // The rest of the GitHub checks can't pass if this one fails
+    let viewer = check_viewer(transport).await;
+    let authenticated = viewer.is_ok();
+    match viewer {
+        Ok((login, remaining, limit)) => {
+            checks.push((
+                "GitHub API".to_string(),
+                Ok(format!("authenticated as {}", login)),
+            ));
+            checks.push((
+                "Rate limit".to_string(),
+                if remaining > 0 {
+                    Ok(format!("{} of {} remaining", remaining, limit))
+                } else {
+                    Err(StatsError::RateLimited(format!("0 of {} remaining", limit)))
+                },
+            ));
+        }
+        Err(err) => checks.push(("GitHub API".to_string(), Err(err))),
+    }
+    if authenticated {
+        checks.push((
+            "Token scopes".to_string(),
+            check_token_scopes(transport)
+                .await
+                .map(|_| "sufficient".to_string()),
+        ));
+    }
+
+    for (theme, path) in &config.svgs {
+        let template = config
+            .template_dir
+            .join(path.file_name().unwrap_or_default());
+        checks.push((
+            format!("{} template {}", theme, template.display()),
+            check_template(&template),
+        ));
+    }
+
+    checks.push((
+        format!("Cache directory {}", config.cache_dir.display()),
+        check_writable(&config.cache_dir).map(|_| "writable".to_string()),
+    ));
+
+    if report(checks) { 0 } else { 1 }
+}
+
+/// Print a pass or fail line per check. Returns whether all of them passed.
+fn report(checks: Vec<(String, Result<String, StatsError>)>) -> bool {
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(detail) => println!("[ OK ] {}: {}", name, detail),
+            Err(err) => {
+                passed = false;
+                println!("[FAIL] {}: {}", name, err);
+            }
+        }
+    }
+
+    passed
+}
+
+/// The authenticated login and the remaining and total rate limit, from one
+/// minimal query.
+async fn check_viewer(transport: &impl GraphQlTransport) -> Result<(String, u64, u64), StatsError> {
+    let query = "query { viewer { login } rateLimit { remaining limit } }";
+    let json = transport.post("doctor", query, &json!({})).await?;
+
+    Ok((
+        json["data"]["viewer"]["login"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        json["data"]["rateLimit"]["remaining"].as_u64().unwrap_or(0),
+        json["data"]["rateLimit"]["limit"].as_u64().unwrap_or(0),
+    ))
+}
+
+/// Create the directory if needed and write and remove a file in it.
+fn check_writable(dir: &Path) -> Result<(), StatsError> {
+    fs::create_dir_all(dir)?;
+    let probe = dir.join(".doctor");
+    fs::write(&probe, "")?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{exports::CACHE_DIR, utility::MockTransport};
+    use clap::Parser;
+
+    #[test]
+    fn one_failing_check_fails_the_doctor() {
+        let dir = CACHE_DIR.join("doctor");
+        let file = dir.join("not-a-dir");
+        check_writable(&dir).unwrap();
+        fs::write(&file, "").unwrap();
+
+        assert!(report(vec![(
+            "Cache directory".to_string(),
+            check_writable(&dir).map(|_| "writable".to_string()),
+        )]));
+        assert!(!report(vec![
+            ("USER_NAME".to_string(), Ok("set to octo".to_string())),
+            (
+                "Cache directory".to_string(),
+                check_writable(&file).map(|_| "writable".to_string()),
+            ),
+        ]));
+        assert!(!dir.join(".doctor").exists());
+    }
+
+    #[tokio::test]
+    async fn a_token_without_the_repo_scope_fails_the_doctor() {
+        let cache_dir = CACHE_DIR.join("doctor-run");
+        let config = Config::try_parse_from([
+            "test".as_ref(),
+            "--user".as_ref(),
+            "octo".as_ref(),
+            "--token".as_ref(),
+            "ghp_test".as_ref(),
+            "--cache-dir".as_ref(),
+            cache_dir.as_os_str(),
+        ])
+        .unwrap();
+        let viewer = json!({
+            "data": {
+                "viewer": { "login": "octo" },
+                "rateLimit": { "remaining": 4999, "limit": 5000 },
+            }
+        });
+
+        let github = MockTransport::new()
+            .respond("doctor", viewer.clone())
+            .respond("check_token_scopes", json!("repo, read:user"));
+        assert_eq!(run_doctor(&github, &config).await, 0);
+
+        let github = MockTransport::new()
+            .respond("doctor", viewer)
+            .respond("check_token_scopes", json!("read:user"));
+        assert_eq!(run_doctor(&github, &config).await, 1);
+        assert_eq!(github.count("check_token_scopes"), 1);
+    }
+}
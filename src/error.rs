@@ -0,0 +1,53 @@
+use std::{fmt, io, string::FromUtf8Error};
+use thiserror::Error;
+
+/// Everything that can go wrong while collecting the stats.
+#[derive(Debug, Error)]
+pub enum StatsError {
+    // GitHub's primary or secondary (anti-abuse) rate limit
+    #[error("rate limited by GitHub: {0}")]
+    RateLimited(String),
+
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    // Reading or writing the cache, or any other file
+    #[error("file error: {0}")]
+    Cache(#[from] io::Error),
+
+    #[error("{0} not found")]
+    MissingEnv(String),
+
+    // A GraphQL request that GitHub answered with an error
+    #[error("GraphQL query failed: {0}")]
+    GraphQl(String),
+
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    Svg(String),
+
+    // A bad option or argument passed in by the caller
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<xmltree::Error> for StatsError {
+    fn from(err: xmltree::Error) -> Self {
+        StatsError::Svg(err.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for StatsError {
+    fn from(err: FromUtf8Error) -> Self {
+        StatsError::Svg(err.to_string())
+    }
+}
+
+// `writeln!` into a String, used when building cache files
+impl From<fmt::Error> for StatsError {
+    fn from(err: fmt::Error) -> Self {
+        StatsError::Cache(io::Error::other(err))
+    }
+}
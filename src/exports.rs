@@ -1,28 +1,178 @@
-use dotenvy::dotenv;
+use crate::{config::Config, error::StatsError};
+use clap::Parser;
 use once_cell::sync::Lazy;
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT};
-use std::env;
+use reqwest::header::{AUTHORIZATION, HeaderMap, HeaderValue, USER_AGENT};
+use std::fs;
+use std::path::PathBuf;
 
-// Could be set once after querying user ID
-pub static OWNER_ID: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+// Could be set once after querying the ids of the users
+pub static OWNER_IDS: once_cell::sync::OnceCell<Vec<String>> = once_cell::sync::OnceCell::new();
 
-pub static USER_NAME: Lazy<String> = Lazy::new(|| {
-    dotenv().ok();
-    env::var("USER_NAME").expect("USER_NAME not found")
+// Command line options, falling back to the environment and `.env`
+#[cfg(not(test))]
+pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+    dotenvy::dotenv().ok();
+    Config::parse()
 });
 
-pub fn get_auth_headers() -> HeaderMap {
-    dotenv().ok();
-    let token = env::var("ACCESS_TOKEN").expect("Access Token not found");
+// Tests don't see the test harness' arguments or the checkout's `.env`, and
+// keep their cache in a directory of their own
+#[cfg(test)]
+pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+    let cache_dir = std::env::temp_dir().join(format!("github-stats-test-{}", std::process::id()));
+    Config::parse_from([
+        "test".as_ref(),
+        "--user".as_ref(),
+        "test-user".as_ref(),
+        "--cache-dir".as_ref(),
+        cache_dir.as_os_str(),
+    ])
+});
+
+// Directory holding the LOC cache and archive, `cache` unless `--cache-dir`
+// or CACHE_DIR is given
+pub static CACHE_DIR: Lazy<PathBuf> = Lazy::new(|| CONFIG.cache_dir.clone());
+
+/// Path of a file inside the cache directory.
+pub fn cache_path(name: &str) -> PathBuf {
+    CACHE_DIR.join(name)
+}
+
+static ACCESS_TOKEN: Lazy<Result<Option<String>, String>> = Lazy::new(|| read_token(&CONFIG));
+
+/// The access token of `config`, read from ACCESS_TOKEN_FILE if that is set
+/// and taken from ACCESS_TOKEN otherwise. Surrounding whitespace is trimmed.
+pub fn read_token(config: &Config) -> Result<Option<String>, String> {
+    let token = match &config.token_file {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|err| format!("Can't read ACCESS_TOKEN_FILE {}: {}", path.display(), err))?,
+        None => config.token.clone().unwrap_or_default(),
+    };
+
+    let token = token.trim();
+    Ok((!token.is_empty()).then(|| token.to_string()))
+}
+
+/// The GitHub access token, if any was given.
+pub fn access_token() -> Result<Option<&'static str>, StatsError> {
+    ACCESS_TOKEN
+        .as_ref()
+        .map(|token| token.as_deref())
+        .map_err(|err| StatsError::Invalid(err.clone()))
+}
 
+/// Headers for GitHub requests. Without a token the Authorization header is
+/// left out, which GitHub's GraphQL API answers with 401.
+pub fn get_auth_headers() -> Result<HeaderMap, StatsError> {
+    request_headers(access_token()?, &CONFIG.user_agent)
+}
+
+/// The headers of `get_auth_headers` for a given token and User-Agent.
+fn request_headers(token: Option<&str>, user_agent: &str) -> Result<HeaderMap, StatsError> {
     let mut headers = HeaderMap::new();
 
+    if let Some(token) = token {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|_| StatsError::Invalid("Invalid ACCESS_TOKEN".to_string()))?,
+        );
+    }
+
     headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        USER_AGENT,
+        HeaderValue::from_str(user_agent)
+            .map_err(|_| StatsError::Invalid(format!("Invalid USER_AGENT \"{}\"", user_agent)))?,
     );
 
-    headers.insert(USER_AGENT, HeaderValue::from_static("my_rust_app"));
+    Ok(headers)
+}
+
+// Run the queries and print the results without writing the SVGs or the
+// cache, with `--dry-run` or DRY_RUN=true
+pub static DRY_RUN: Lazy<bool> = Lazy::new(|| CONFIG.dry_run);
+
+/// Repository affiliations accepted by `--star-affiliations`.
+pub const AFFILIATIONS: [&str; 3] = ["OWNER", "COLLABORATOR", "ORGANIZATION_MEMBER"];
+
+/// Outputs accepted by `--format`.
+pub const FORMATS: [&str; 3] = ["table", "json", "svg"];
+
+/// Query names accepted by `--only`.
+pub const QUERY_NAMES: [&str; 12] = [
+    "stars",
+    "repos",
+    "loc",
+    "commits",
+    "stats",
+    "languages",
+    "streak",
+    "contributions",
+    "watchers",
+    "sponsors",
+    "pinned",
+    "last_active",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_carry_the_configured_user_agent() {
+        let headers = request_headers(None, "stats-bot/2.0").unwrap();
+        assert_eq!(headers[USER_AGENT], "stats-bot/2.0");
+
+        let config = Config::try_parse_from(["test", "--user-agent", "stats-bot/2.0"]).unwrap();
+        assert_eq!(config.user_agent, "stats-bot/2.0");
+        assert_eq!(
+            CONFIG.user_agent,
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"))
+        );
+
+        assert!(matches!(
+            request_headers(None, "stats\nbot"),
+            Err(StatsError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn the_authorization_header_is_only_sent_with_a_token() {
+        let headers = request_headers(Some("ghp_test"), "stats-bot/2.0").unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bearer ghp_test");
+
+        assert!(
+            !request_headers(None, "stats-bot/2.0")
+                .unwrap()
+                .contains_key(AUTHORIZATION)
+        );
+    }
+
+    #[test]
+    fn the_token_file_takes_precedence_over_the_token() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        let path = CACHE_DIR.join("token");
+        fs::write(&path, "  ghp_from_file\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let config =
+            Config::try_parse_from(["test", "--token", "ghp_inline", "--token-file", path])
+                .unwrap();
+        let token = read_token(&config).unwrap();
+        assert_eq!(token.as_deref(), Some("ghp_from_file"));
+        let headers = request_headers(token.as_deref(), "stats-bot/2.0").unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bearer ghp_from_file");
+
+        let config = Config::try_parse_from(["test", "--token", " ghp_inline "]).unwrap();
+        assert_eq!(read_token(&config).unwrap().as_deref(), Some("ghp_inline"));
 
-    headers
+        let missing = CACHE_DIR.join("no-token");
+        let config =
+            Config::try_parse_from(["test", "--token-file", missing.to_str().unwrap()]).unwrap();
+        assert!(
+            read_token(&config)
+                .unwrap_err()
+                .starts_with("Can't read ACCESS_TOKEN_FILE")
+        );
+    }
 }
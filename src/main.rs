@@ -1,146 +1,1213 @@
+mod cache;
+mod config;
+mod doctor;
+mod error;
 mod exports;
+#[cfg(feature = "png")]
+mod png;
 mod query;
 mod utility;
 
-use std::{env, fs, sync::MutexGuard};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::MutexGuard,
+};
 
-use dotenvy::dotenv;
-use exports::{OWNER_ID, USER_NAME};
+use cache::{
+    CACHE_COMMENT_SIZE, CachedOwner, load_owner, record_last_stats, record_loc_run, save_owner,
+};
+use chrono::Utc;
+use config::Config;
+use error::StatsError;
+use exports::{AFFILIATIONS, CONFIG, OWNER_IDS, cache_path};
+use log::warn;
 use query::{
-    commit_counter, graph_repos_stars, loc_query, stats_getter, svg_overwrite, user_getter,
+    ARCHIVE_FILE, LANGUAGE_SLOTS, LOC_PAGE_SIZE, LogProgress, PINNED_SLOTS, REPOS_PAGE_SIZE,
+    UserInfo, add_archive, cache_builder, check_token_scopes, commit_counter, commit_streak,
+    contributions_last_year, custom_repo_edges, custom_repo_stars, diff_values, follow_counts,
+    graph_repos_stars, last_active_repo, loc_query, org_stats, owned_repo_totals, pinned_repos,
+    sponsors_count, stats_getter, svg_element_getter, svg_overwrite, template_tspan_ids,
+    top_languages, user_getter, verify_cache,
+};
+use utility::{
+    DEFAULT_MAX_RETRIES, DEFAULT_RETRY_BASE_DELAY, GitHubTransport, GraphQlTransport,
+    LOC_EMA_ALPHA, LocStats, QUERY_COUNT, RunReport, StatsSnapshot, format_with_commas, formatter,
+    formatter_skipped, loc_trend, perf_counter_async, perf_counter_try, post_stats,
+    print_table_header, print_total_time, rate_limit_info, relative_time, show_timings,
+    update_readme, write_run_report, write_shields_endpoints, write_stats_json,
 };
-use utility::{formatter, perf_counter, query_count, QUERY_COUNT};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok();
-    let user_name = env::var("USER_NAME").expect("USER_NAME not found!");
-    let github_token = env::var("ACCESS_TOKEN").expect("ACCESS_TOKEN not found!");
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Diagnostics go through `log`; RUST_LOG picks the level, `info` unless
+    // set or narrowed down by `--verbose`/`--quiet`
+    let default_level = if CONFIG.verbose {
+        "debug"
+    } else if CONFIG.quiet {
+        "warn"
+    } else {
+        "info"
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level))
+        .init();
+
+    if CONFIG.doctor {
+        std::process::exit(doctor::run_doctor(&GitHubTransport, &CONFIG).await);
+    }
+
+    if let Some(template) = &CONFIG.list_tspans {
+        svg_element_getter(template)?;
+        return Ok(());
+    }
+
+    if let Some(org) = &CONFIG.org {
+        print_org_stats(&GitHubTransport, org).await?;
+        return Ok(());
+    }
+
+    if CONFIG.verify_cache {
+        let discrepancies = verify_cache(
+            &GitHubTransport,
+            AFFILIATIONS.map(String::from).to_vec(),
+            CACHE_COMMENT_SIZE,
+            CONFIG.include_forks,
+            CONFIG.include_archived,
+            CONFIG.page_size.unwrap_or(LOC_PAGE_SIZE),
+            &CONFIG.user_names()?,
+        )
+        .await?;
+        for discrepancy in &discrepancies {
+            println!("[FAIL] {}", discrepancy);
+        }
+        if discrepancies.is_empty() {
+            println!(
+                "[ OK ] The LOC cache of {} matches GitHub",
+                CONFIG.users_key()?
+            );
+            return Ok(());
+        }
+        std::process::exit(1);
+    }
+
+    // Resolve the template and output of each SVG up front, so a bad path
+    // fails before any query runs
+    let svg_targets = if CONFIG.has_format("svg") {
+        CONFIG
+            .svg_targets()?
+            .into_iter()
+            .map(|(theme, path)| Ok((theme.as_str(), CONFIG.svg_paths(path)?)))
+            .collect::<Result<Vec<_>, StatsError>>()?
+    } else {
+        Vec::new()
+    };
+
+    if !CONFIG.offline {
+        check_token_scopes(&GitHubTransport).await?;
+    }
+
+    // The report is written whether or not the run succeeds, so a failed CI
+    // run still shows how far it got
+    let mut report = RunReport::default();
+    let result = run_stats(&GitHubTransport, svg_targets, &mut report).await;
+    if let Some(report_file) = &CONFIG.report_file {
+        report.error = result.as_ref().err().map(|err| err.to_string());
+        write_run_report(report_file, &report)?;
+    }
+    result?;
+    Ok(())
+}
+
+/// Query the stats of the users, render them into each SVG of `svg_targets`
+/// (theme, then template and output path) and write whatever other outputs
+/// were asked for. How each stat went is recorded in `report`.
+async fn run_stats(
+    transport: &impl GraphQlTransport,
+    svg_targets: Vec<(&str, (PathBuf, PathBuf))>,
+    report: &mut RunReport,
+) -> Result<(), StatsError> {
+    let user_names = CONFIG.user_names()?;
+    let user_name = user_names[0].to_string();
+    let (contributions_from, contributions_to) = CONFIG.contributions_range()?;
+
+    let selected = |name: &str| query_selected(&CONFIG, name);
 
-    println!("Calculation times:");
+    // The stats that only render into tspans with their own ids are only
+    // queried when a template has one of those ids or `--only` asks for
+    // them, or, for those in the snapshot, when another output shows it
+    let template_ids = svg_targets
+        .iter()
+        .map(|(_, (template, _))| template_tspan_ids(template))
+        .collect::<Result<Vec<_>, StatsError>>()?
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>();
+    let snapshot_shown = CONFIG.has_format("json")
+        || CONFIG.stats_json.is_some()
+        || CONFIG.shields_dir.is_some()
+        || CONFIG.readme.is_some()
+        || CONFIG.post_url.is_some();
+    let shown = |ids: &[&str], in_snapshot: bool| {
+        CONFIG.only.is_some()
+            || (in_snapshot && snapshot_shown)
+            || ids.iter().any(|id| template_ids.contains(*id))
+    };
+    let follows_shown = ["followers", "following"]
+        .iter()
+        .any(|id| template_ids.contains(*id));
 
-    let (user_data, user_time) = {
-        let (res, time) = perf_counter(|| user_getter(USER_NAME.as_str()));
-        (res?, time)
+    if show_timings() {
+        print_table_header();
+    }
+
+    // LOC counts the commits authored by any of the users, so every
+    // account's id is needed
+    let mut accounts = Vec::new();
+    let mut user_time = 0.0;
+    for name in &user_names {
+        let account = account_data(transport, name, follows_shown).await;
+        if account.is_err() {
+            report.record("account data", user_time, Some(false));
+        }
+        let (account, time) = account?;
+        accounts.push(account);
+        user_time += time;
+    }
+    report.record("account data", user_time, Some(true));
+    OWNER_IDS.get_or_init(|| accounts.iter().map(|account| account.id.clone()).collect());
+    let user_data = combine_accounts(accounts);
+
+    // `--repos` narrows stars, LOC and commits down to an explicit list of
+    // repositories instead of everything the user is affiliated with.
+    // Offline, LOC comes from the cache as it is, so the list isn't needed
+    let custom_repos = CONFIG.custom_repos();
+    let (custom_edges, custom_time) = if custom_repos.is_empty() || CONFIG.offline {
+        report.record("custom repositories", 0.0, None);
+        (None, 0.0)
+    } else {
+        let timing = perf_counter_async(custom_repo_edges(transport, &custom_repos)).await;
+        report.record("custom repositories", timing.1, Some(timing.0.is_ok()));
+        let (custom_edges, time) = timed("custom repositories", timing)?;
+        formatter("custom repositories", time, None, 0);
+        (Some(custom_edges), time)
     };
-    let (owner_id, acc_date) = user_data;
-    OWNER_ID.set(owner_id).expect("Owner id was already set");
-    formatter("account data", user_time, None, 0);
 
     let affiliations = vec![
         "OWNER".to_string(),
         "COLLABORATOR".to_string(),
         "ORGANIZATION_MEMBER".to_string(),
     ];
-    let comment_size = 7;
     let force_cache = false;
-    let cursor = None;
-    let edges = Vec::new();
 
-    let (total_loc, loc_time) = {
-        let (res, time) =
-            perf_counter(|| loc_query(affiliations, comment_size, force_cache, cursor, edges));
-        (res?, time)
+    let (total_loc, loc_time) = if selected("loc") {
+        let timing = perf_counter_async(async {
+            match &custom_edges {
+                Some(custom_edges) => {
+                    cache_builder(
+                        transport,
+                        &LogProgress,
+                        custom_edges,
+                        CACHE_COMMENT_SIZE,
+                        force_cache,
+                        &CONFIG.force_repos,
+                        0,
+                        0,
+                        &user_names.join(","),
+                    )
+                    .await
+                }
+                None => {
+                    loc_query(
+                        transport,
+                        &LogProgress,
+                        affiliations,
+                        CACHE_COMMENT_SIZE,
+                        force_cache,
+                        &CONFIG.force_repos,
+                        CONFIG.include_forks,
+                        CONFIG.include_archived,
+                        CONFIG.page_size.unwrap_or(LOC_PAGE_SIZE),
+                        CONFIG.max_repos,
+                        &user_names,
+                    )
+                    .await
+                }
+            }
+        })
+        .await;
+        report.record("LOC", timing.1, Some(timing.0.is_ok()));
+        let (total_loc, time) = timed("LOC", timing)?;
+        (Some(total_loc), time)
+    } else {
+        report.record("LOC", 0.0, None);
+        (None, 0.0)
     };
+    report.loc_cached = total_loc.map(|total_loc| total_loc.3);
 
-    if total_loc.3 {
-        formatter("LOC (cached)", loc_time, None, 0);
-    } else {
-        formatter("LOC (no cache)", loc_time, None, 0);
+    match total_loc {
+        Some(_) if CONFIG.loc_cache_only() => {
+            formatter("LOC (cache only)", loc_time, None, 0);
+        }
+        Some(total_loc) if total_loc.3 => {
+            formatter("LOC (cached)", loc_time, None, 0);
+        }
+        Some(_) => {
+            formatter("LOC (no cache)", loc_time, None, 0);
+        }
+        None => formatter_skipped("LOC"),
     }
 
-    let (commit_result, commit_time) = perf_counter(|| commit_counter(7));
-    let commit_data = commit_result?;
+    let (commit_data, commit_time) = if selected("commits") {
+        let timing = perf_counter_try(|| commit_counter(CACHE_COMMENT_SIZE));
+        report.record("commit counter", timing.1, Some(timing.0.is_ok()));
+        let (commit_data, time) = timed("commit counter", timing)?;
+        (Some(commit_data), time)
+    } else {
+        report.record("commit counter", 0.0, None);
+        (None, 0.0)
+    };
 
-    let (star_result, star_time) = perf_counter(|| {
-        graph_repos_stars(
-            "stars",
-            vec!["OWNER".to_string()],
-            None,
-            &user_name,
-            &github_token,
-        )
-    });
-    let star_data = star_result?;
-
-    let (repo_result, repo_time) = perf_counter(|| {
-        graph_repos_stars(
-            "repos",
-            vec!["OWNER".to_string()],
-            None,
-            &user_name,
-            &github_token,
+    let repos_page_size = CONFIG.page_size.unwrap_or(REPOS_PAGE_SIZE);
+
+    // The remaining queries don't depend on each other, so run them together.
+    // Stars, watchers, repositories and issue/PR stats are combined over all
+    // users; the others are of the first user.
+    // Stars of the owned repositories, watchers and visibility all come out
+    // of a single walk of the owned repositories.
+    let want_stars = selected("stars");
+    let want_watchers = selected("watchers") && shown(&["watchers"], true);
+    let want_visibility = selected("repos") && shown(&["repos_public", "repos_private"], true);
+    let stars_owned = custom_edges.is_none() && CONFIG.star_affiliations == ["OWNER"];
+    let (
+        (star_data, star_walk_time),
+        (repo_data, repo_time),
+        (contrib_data, contrib_time),
+        (stats_data, stats_time),
+        (last_active_result, last_active_time),
+        (language_data, language_time),
+        (streak_data, streak_time),
+        (contributions_data, contributions_time),
+        (owned_data, owned_time),
+        (sponsors_data, sponsors_time),
+        (pinned_data, pinned_time),
+    ) = tokio::join!(
+        run_selected(want_stars && !stars_owned, async {
+            match &custom_edges {
+                Some(custom_edges) => Ok(custom_repo_stars(custom_edges)),
+                None => {
+                    graph_repos_stars(
+                        transport,
+                        "stars",
+                        CONFIG.star_affiliations.clone(),
+                        CONFIG.include_forks,
+                        CONFIG.include_archived,
+                        repos_page_size,
+                        None,
+                        &user_names,
+                    )
+                    .await
+                }
+            }
+        }),
+        run_selected(
+            selected("repos"),
+            graph_repos_stars(
+                transport,
+                "repos",
+                vec!["OWNER".to_string()],
+                CONFIG.include_forks,
+                CONFIG.include_archived,
+                repos_page_size,
+                None,
+                &user_names,
+            ),
+        ),
+        run_selected(
+            selected("repos"),
+            graph_repos_stars(
+                transport,
+                "repos",
+                vec![
+                    "OWNER".to_string(),
+                    "COLLABORATOR".to_string(),
+                    "ORGANIZATION_MEMBER".to_string(),
+                ],
+                CONFIG.include_forks,
+                CONFIG.include_archived,
+                repos_page_size,
+                None,
+                &user_names,
+            ),
+        ),
+        run_selected(selected("stats"), stats_getter(transport, &user_names)),
+        run_selected(
+            selected("last_active") && shown(&["last_active"], false),
+            last_active_repo(transport, &user_name),
+        ),
+        run_selected(
+            selected("languages") && shown(&LANGUAGE_SLOTS, false),
+            top_languages(
+                transport,
+                &user_name,
+                &CONFIG.exclude_languages,
+                CONFIG.top_languages,
+            ),
+        ),
+        run_selected(
+            selected("streak")
+                && shown(
+                    &[
+                        "streak_current",
+                        "streak_current_range",
+                        "streak_longest",
+                        "streak_longest_range",
+                    ],
+                    false,
+                ),
+            commit_streak(transport, &user_name),
+        ),
+        run_selected(
+            selected("contributions") && shown(&["contributions"], true),
+            contributions_last_year(
+                transport,
+                &user_name,
+                contributions_from.as_deref(),
+                contributions_to.as_deref(),
+            ),
+        ),
+        run_selected(
+            (want_stars && stars_owned) || want_watchers || want_visibility,
+            owned_repo_totals(
+                transport,
+                vec!["OWNER".to_string()],
+                CONFIG.include_forks,
+                CONFIG.include_archived,
+                repos_page_size,
+                &user_names,
+            ),
+        ),
+        run_selected(
+            selected("sponsors") && shown(&["sponsors"], true),
+            sponsors_count(transport, &user_name),
+        ),
+        run_selected(
+            selected("pinned") && shown(&[&PINNED_SLOTS[..], &["pinned_stars"]].concat(), true),
+            pinned_repos(transport, &user_name),
+        ),
+    );
+    for (stat, time, succeeded) in [
+        (
+            "star counter",
+            star_walk_time,
+            star_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "owned repos",
+            owned_time,
+            owned_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "my repositories",
+            repo_time,
+            repo_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "contributed repos",
+            contrib_time,
+            contrib_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "issues/prs stats",
+            stats_time,
+            stats_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "last active repo",
+            last_active_time,
+            last_active_result.as_ref().map(Result::is_ok),
+        ),
+        (
+            "top languages",
+            language_time,
+            language_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "commit streak",
+            streak_time,
+            streak_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "contributions",
+            contributions_time,
+            contributions_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "sponsors",
+            sponsors_time,
+            sponsors_data.as_ref().map(Result::is_ok),
+        ),
+        (
+            "pinned repos",
+            pinned_time,
+            pinned_data.as_ref().map(Result::is_ok),
+        ),
+    ] {
+        report.record(stat, time, succeeded);
+    }
+    let owned_data = owned_data.transpose()?;
+    let star_data = star_data
+        .transpose()?
+        .or(owned_data.filter(|_| want_stars).map(|owned| owned.stars));
+    let watcher_data = owned_data
+        .filter(|_| want_watchers)
+        .map(|owned| owned.watchers);
+    let visibility_data = owned_data
+        .filter(|_| want_visibility)
+        .map(|owned| (owned.public, owned.private));
+    // Rows of stats from the walk of the owned repositories show its time
+    let star_time = if stars_owned {
+        owned_time
+    } else {
+        star_walk_time
+    };
+    let (watcher_time, visibility_time) = (owned_time, owned_time);
+    let repo_data = repo_data.transpose()?;
+    let contrib_data = contrib_data.transpose()?;
+    let stats_data = stats_data.transpose()?;
+    let language_data = language_data.transpose()?;
+    let streak_data = streak_data.transpose()?;
+    let contributions_data = contributions_data.transpose()?;
+    let sponsors_data = sponsors_data.transpose()?;
+    let pinned_data = pinned_data.transpose()?;
+
+    // Repositories the user lost access to are kept in the archive, so that
+    // their history still counts towards LOC, commits and contributed repos
+    let (total_loc, commit_data, contrib_data) = if cache_path(ARCHIVE_FILE).exists() {
+        let [
+            added_loc,
+            deleted_loc,
+            net_loc,
+            added_commits,
+            contributed_repos,
+        ] = add_archive()?;
+        (
+            total_loc.map(|(loc_add, loc_del, loc_net, cached)| {
+                (
+                    loc_add + added_loc,
+                    loc_del + deleted_loc,
+                    loc_net + net_loc,
+                    cached,
+                )
+            }),
+            commit_data.map(|commits| commits + added_commits as usize),
+            contrib_data.map(|repos| repos + contributed_repos as usize),
         )
-    });
-    let repo_data = repo_result?;
-
-    let (contrib_result, contrib_time) = perf_counter(|| {
-        graph_repos_stars(
-            "repos",
-            vec![
-                "OWNER".to_string(),
-                "COLLABORATOR".to_string(),
-                "ORGANIZATION_MEMBER".to_string(),
-            ],
-            None,
-            &user_name,
-            &github_token,
+    } else {
+        (total_loc, commit_data, contrib_data)
+    };
+
+    match stats_data {
+        Some(_) => {
+            formatter("issues/prs stats", stats_time, None, 0);
+        }
+        None => formatter_skipped("issues/prs stats"),
+    }
+
+    let last_active_data = last_active_result.transpose()?.map(|last_active| {
+        last_active.map(
+            |(name, pushed_at)| match relative_time(&pushed_at, Utc::now()) {
+                Some(ago) => format!("{} ({})", name, ago),
+                None => name,
+            },
         )
     });
-    let contrib_data = contrib_result?;
+    match last_active_data {
+        Some(_) => {
+            formatter("last active repo", last_active_time, None, 0);
+        }
+        None => formatter_skipped("last active repo"),
+    }
+    let last_active_data = last_active_data.flatten();
 
-    let (stats_result, stats_time) = perf_counter(|| stats_getter());
-    let stats_data = stats_result?;
-    formatter("issues/prs stats", stats_time, None, 0);
+    match language_data {
+        Some(_) => {
+            formatter("top languages", language_time, None, 0);
+        }
+        None => formatter_skipped("top languages"),
+    }
 
-    let commit_data = formatter("commit counter", commit_time, Some(commit_data), 0);
-    let star_data = formatter("star counter", star_time, Some(star_data), 0);
-    let repo_data = formatter("my repositories", repo_time, Some(repo_data), 0);
-    let contrib_data = formatter("contributed repos", contrib_time, Some(contrib_data), 0);
+    match streak_data {
+        Some(_) => {
+            formatter("commit streak", streak_time, None, 0);
+        }
+        None => formatter_skipped("commit streak"),
+    }
 
-    // Format added, deleted, and total LOC with commas
-    // Convert to array or vector to iterate:
-    let total_loc_arr = [total_loc.0, total_loc.1, total_loc.2, total_loc.3 as i32];
-    let formatted_loc: Vec<String> = total_loc_arr
-        .iter()
-        .take(total_loc_arr.len() - 1)
-        .map(|loc| format!("{:}", loc))
-        .collect();
-
-    svg_overwrite(
-        "src/dark_mode.svg",
-        commit_data.as_deref().unwrap_or(""),
-        star_data.as_deref().unwrap_or(""),
-        repo_data.as_deref().unwrap_or(""),
-        contrib_data.as_deref().unwrap_or(""),
-        &stats_data,
-        &formatted_loc,
-    )?;
+    match contributions_data {
+        Some(_) => {
+            formatter("contributions", contributions_time, None, 0);
+        }
+        None => formatter_skipped("contributions"),
+    }
 
-    svg_overwrite(
-        "src/light_mode.svg",
-        commit_data.as_deref().unwrap_or(""),
-        star_data.as_deref().unwrap_or(""),
-        repo_data.as_deref().unwrap_or(""),
-        contrib_data.as_deref().unwrap_or(""),
-        &stats_data,
-        &formatted_loc,
-    )?;
+    match sponsors_data {
+        Some(_) => {
+            formatter("sponsors", sponsors_time, None, 0);
+        }
+        None => formatter_skipped("sponsors"),
+    }
 
-    // Move cursor up to overwrite previous lines (ANSI escape sequences)
-    print!(
-        "\x1B[8F{:<21} {:>11.4} s \x1B[E\x1B[E\x1B[E\x1B[E\x1B[E\x1B[E\x1B[E\x1B[E\n",
-        "Total function time:",
-        user_time + loc_time + commit_time + star_time + repo_time + contrib_time + stats_time
-    );
+    match visibility_data {
+        Some(_) => {
+            formatter("repo visibility", visibility_time, None, 0);
+        }
+        None => formatter_skipped("repo visibility"),
+    }
 
-    // Print total GitHub GraphQL API calls and counts
-    let query_count_guard: MutexGuard<_> = QUERY_COUNT.lock().unwrap();
+    match pinned_data {
+        Some(_) => {
+            formatter("pinned repos", pinned_time, None, 0);
+        }
+        None => formatter_skipped("pinned repos"),
+    }
+
+    let snapshot = StatsSnapshot {
+        commits: commit_data,
+        stars: star_data,
+        repos: repo_data,
+        contributed_repos: contrib_data,
+        issues: stats_data
+            .as_ref()
+            .and_then(|stats| stats["issues"].as_u64()),
+        prs: stats_data
+            .as_ref()
+            .and_then(|stats| stats["prs_opened"].as_u64()),
+        prs_merged: stats_data
+            .as_ref()
+            .and_then(|stats| stats["prs_merged"].as_u64()),
+        prs_reviewed: stats_data
+            .as_ref()
+            .and_then(|stats| stats["prs_reviewed"].as_u64()),
+        issues_closed: stats_data
+            .as_ref()
+            .and_then(|stats| stats["issues_closed"].as_u64()),
+        loc_added: total_loc.map(|total_loc| total_loc.0),
+        loc_deleted: total_loc.map(|total_loc| total_loc.1),
+        loc_net: total_loc.map(|total_loc| total_loc.2),
+        contributions: contributions_data,
+        watchers: watcher_data,
+        sponsors: sponsors_data,
+        repos_public: visibility_data.map(|(public, _)| public),
+        repos_private: visibility_data.map(|(_, private)| private),
+        pinned_stars: pinned_data
+            .as_ref()
+            .map(|pinned| pinned.iter().map(|(_, stars)| stars).sum()),
+    };
+
+    // The change of each stat since the last run, for the `_diff` tspans
+    let diff_data = match record_last_stats(&snapshot)? {
+        Some(last) => diff_values(&last, &snapshot)?,
+        None => HashMap::new(),
+    };
+
+    let commit_data = timing_row("commit counter", commit_time, commit_data);
+    let star_data = timing_row("star counter", star_time, star_data);
+    let watcher_data = timing_row("watcher counter", watcher_time, watcher_data);
+    let repo_data = timing_row("my repositories", repo_time, repo_data);
+    let contrib_data = timing_row("contributed repos", contrib_time, contrib_data);
+
+    let loc_stats = match total_loc {
+        Some((added, deleted, _, _)) => {
+            let history: Vec<i64> = record_loc_run((added - deleted).into())?
+                .iter()
+                .map(|run| run.loc)
+                .collect();
+            Some(LocStats {
+                trend: loc_trend(&history, LOC_EMA_ALPHA),
+                ..LocStats::new(added, deleted)
+            })
+        }
+        None => None,
+    };
+
+    if let Some(output_dir) = &CONFIG.output_dir
+        && !svg_targets.is_empty()
+        && !*exports::DRY_RUN
+    {
+        fs::create_dir_all(output_dir)?;
+    }
+
+    for (theme, (template, output)) in svg_targets {
+        let changed = svg_overwrite(
+            &template,
+            &output,
+            commit_data.as_deref(),
+            star_data.as_deref(),
+            repo_data.as_deref(),
+            contrib_data.as_deref(),
+            stats_data.as_ref(),
+            loc_stats.as_ref(),
+            last_active_data.as_deref(),
+            language_data.as_deref(),
+            streak_data.as_ref(),
+            contributions_data,
+            watcher_data.as_deref(),
+            sponsors_data,
+            visibility_data,
+            pinned_data.as_deref(),
+            Some(&user_data),
+            &diff_data,
+        )?;
+        report
+            .svg_changed
+            .push((output.display().to_string(), changed));
+
+        if let Some((_, png_path)) = CONFIG.pngs.iter().find(|(name, _)| name == theme) {
+            write_png(&output, png_path)?;
+        }
+    }
+
+    if let Some(stats_json) = CONFIG.stats_json.as_ref() {
+        export_stats_json(stats_json, &snapshot, *exports::DRY_RUN)?;
+    }
+
+    if CONFIG.has_format("json") {
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+    }
+
+    if let Some(shields_dir) = &CONFIG.shields_dir {
+        if *exports::DRY_RUN {
+            log::info!("Dry run: not writing {}", shields_dir.display());
+        } else {
+            write_shields_endpoints(shields_dir, &snapshot)?;
+        }
+    }
+
+    if let Some(readme) = &CONFIG.readme {
+        if *exports::DRY_RUN {
+            log::info!("Dry run: not writing {}", readme.display());
+        } else {
+            update_readme(readme, &snapshot)?;
+        }
+    }
+
+    if let Some(post_url) = &CONFIG.post_url {
+        if *exports::DRY_RUN {
+            log::info!("Dry run: not posting the stats to {}", post_url);
+        } else {
+            post_stats(
+                post_url,
+                CONFIG.post_auth.as_deref(),
+                &snapshot,
+                DEFAULT_MAX_RETRIES,
+                DEFAULT_RETRY_BASE_DELAY,
+            )
+            .await?;
+        }
+    }
+
+    let total_time = user_time
+        + custom_time
+        + loc_time
+        + commit_time
+        + star_walk_time
+        + repo_time
+        + contrib_time
+        + stats_time
+        + last_active_time
+        + language_time
+        + streak_time
+        + contributions_time
+        + owned_time
+        + sponsors_time
+        + pinned_time;
+    print_total_time(total_time);
+
+    if !show_timings() {
+        return Ok(());
+    }
 
-    let total_calls: usize = query_count_guard.values().sum();
+    // Print the GitHub GraphQL API call counts
+    let query_count_guard: MutexGuard<_> = QUERY_COUNT.lock().unwrap();
 
     for (funct_name, count) in query_count_guard.iter() {
         println!("{} called {} times", funct_name, count);
     }
+    drop(query_count_guard);
+
+    if let Some(rate_limit) = rate_limit_info() {
+        println!(
+            "Rate limit: {} of {} remaining ({} used), resets at Unix time {}",
+            rate_limit.remaining, rate_limit.limit, rate_limit.used, rate_limit.reset
+        );
+    }
+
+    Ok(())
+}
+
+/// Write the `--stats-json` file, unless this is a dry run.
+fn export_stats_json(
+    path: &Path,
+    snapshot: &StatsSnapshot,
+    dry_run: bool,
+) -> Result<(), StatsError> {
+    if dry_run {
+        log::info!("Dry run: not writing {}", path.display());
+        return Ok(());
+    }
+
+    write_stats_json(path, snapshot)
+}
+
+/// Rasterize a just written SVG to PNG.
+#[cfg(feature = "png")]
+fn write_png(svg_path: &Path, png_path: &Path) -> Result<(), StatsError> {
+    if *exports::DRY_RUN {
+        log::info!("Dry run: not writing {}", png_path.display());
+        return Ok(());
+    }
+
+    png::render_png(svg_path, png_path, CONFIG.png_scale)
+}
+
+#[cfg(not(feature = "png"))]
+fn write_png(_svg_path: &Path, png_path: &Path) -> Result<(), StatsError> {
+    Err(StatsError::Invalid(format!(
+        "Can't write {}: PNG output needs the png feature (cargo build --features png)",
+        png_path.display()
+    )))
+}
+
+/// Print the timing row for a counter, or mark it skipped when it wasn't run.
+fn timing_row(query_type: &str, duration: f64, funct_return: Option<usize>) -> Option<String> {
+    match funct_return {
+        Some(value) => formatter(query_type, duration, Some(value), 0),
+        None => {
+            formatter_skipped(query_type);
+            None
+        }
+    }
+}
+
+/// Unwrap a timed result, logging how long a failed query took before its
+/// error is returned.
+fn timed<T>(
+    query_type: &str,
+    (res, time): (Result<T, StatsError>, f64),
+) -> Result<(T, f64), StatsError> {
+    match res {
+        Ok(value) => Ok((value, time)),
+        Err(err) => {
+            warn!("{} failed after {:.4} s", query_type, time);
+            Err(err)
+        }
+    }
+}
+
+/// Print the stats of an organization: a table unless `--format` leaves it
+/// out, and the counts on stdout with `--format json`. Nothing is rendered.
+async fn print_org_stats(transport: &impl GraphQlTransport, org: &str) -> Result<(), StatsError> {
+    if show_timings() {
+        print_table_header();
+    }
+
+    let (stats, time) = timed(
+        "org stats",
+        perf_counter_async(org_stats(
+            transport,
+            org,
+            CONFIG.include_forks,
+            CONFIG.include_archived,
+            CONFIG.page_size.unwrap_or(REPOS_PAGE_SIZE),
+        ))
+        .await,
+    )?;
+    formatter("org stats", time, None, 0);
+
+    if show_timings() {
+        println!("Organization {}:", org);
+        for (label, value) in [
+            ("repositories", stats.repos),
+            ("stars", stats.stars),
+            ("members", stats.members),
+        ] {
+            println!(
+                "{:<23}{:>12}",
+                format!("   {}:", label),
+                format_with_commas(value as i64)
+            );
+        }
+    }
+
+    if CONFIG.has_format("json") {
+        let json = serde_json::json!({
+            "org": org,
+            "repos": stats.repos,
+            "stars": stats.stars,
+            "members": stats.members,
+        });
+        println!("{}", serde_json::to_string_pretty(&json)?);
+    }
 
     Ok(())
 }
+
+/// Whether the query `name` runs. `--only` runs a subset of the queries; the
+/// rest keep the values already rendered in the SVGs. Offline, only the
+/// cached ones can run.
+fn query_selected(config: &Config, name: &str) -> bool {
+    (!config.offline || matches!(name, "loc" | "commits"))
+        && config
+            .only
+            .as_ref()
+            .is_none_or(|names| names.iter().any(|n| n == name))
+}
+
+/// The account data of one user. The id and creation date never change, so
+/// they are only fetched once and then read from the owner cache. Followers
+/// and following are fetched on every run if the SVGs show them, except
+/// offline where they are left as they are.
+async fn account_data(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+    follows_shown: bool,
+) -> Result<(UserInfo, f64), StatsError> {
+    let cached_owner = if CONFIG.refresh_owner {
+        None
+    } else {
+        load_owner(user_name)
+    };
+
+    match cached_owner {
+        Some(owner) => {
+            let (follows, time) = if CONFIG.offline || !follows_shown {
+                (None, 0.0)
+            } else {
+                let (follows, time) = timed(
+                    "account data",
+                    perf_counter_async(follow_counts(transport, user_name)).await,
+                )?;
+                (Some(follows), time)
+            };
+            formatter("account data (cached)", time, None, 0);
+            let user_data = UserInfo {
+                id: owner.id,
+                created_at: owner.created_at,
+                followers: follows.map(|(followers, _)| followers),
+                following: follows.map(|(_, following)| following),
+            };
+            Ok((user_data, time))
+        }
+        None if CONFIG.offline => Err(StatsError::Invalid(format!(
+            "--offline needs the cached account data of {}. Run once without it first.",
+            user_name
+        ))),
+        None => {
+            let (user_data, time) = timed(
+                "account data",
+                perf_counter_async(user_getter(transport, user_name)).await,
+            )?;
+            save_owner(
+                user_name,
+                &CachedOwner {
+                    id: user_data.id.clone(),
+                    created_at: user_data.created_at.clone(),
+                },
+            )?;
+            formatter("account data", time, None, 0);
+            Ok((user_data, time))
+        }
+    }
+}
+
+/// The account data the card shows for several users: the oldest account,
+/// with followers and following summed when all of them are known.
+fn combine_accounts(accounts: Vec<UserInfo>) -> UserInfo {
+    let followers = accounts.iter().map(|account| account.followers).sum();
+    let following = accounts.iter().map(|account| account.following).sum();
+
+    let oldest = accounts
+        .into_iter()
+        .min_by(|a, b| a.created_at.cmp(&b.created_at))
+        .unwrap_or_default();
+
+    UserInfo {
+        followers,
+        following,
+        ..oldest
+    }
+}
+
+/// Await and time a query if it was selected, otherwise skip it.
+async fn run_selected<T, F>(selected: bool, query: F) -> (Option<Result<T, StatsError>>, f64)
+where
+    F: Future<Output = Result<T, StatsError>>,
+{
+    if !selected {
+        return (None, 0.0);
+    }
+
+    let (res, time) = perf_counter_async(query).await;
+    (Some(res), time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LocCache;
+    use crate::query::list_tspans;
+    use crate::utility::{LOC_CACHE_HITS, LOC_CACHE_MISSES, LOC_TEST_LOCK, MockTransport};
+    use clap::Parser;
+    use serde_json::{Value, json};
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    fn fixture(name: &str) -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name);
+        serde_json::from_str(&fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|err| panic!("Bad fixture {}: {}", path.display(), err))
+    }
+
+    // Answers for every query a default run sends, for the user `test-user`
+    fn github() -> MockTransport {
+        let owned = json!({ "owner_affiliation": ["OWNER"] });
+        MockTransport::new()
+            .respond("user_getter", fixture("user.json"))
+            .respond("loc_query", fixture("loc_repos.json"))
+            .respond_to(
+                "recursive_loc",
+                json!({ "owner": "octo", "repo_name": "hello", "cursor": null }),
+                fixture("history_page_1.json"),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "owner": "octo", "repo_name": "hello", "cursor": "history-1" }),
+                fixture("history_page_2.json"),
+            )
+            .respond_always(
+                "graph_repos_stars",
+                owned.clone(),
+                fixture("owned_repos.json"),
+            )
+            .respond_always(
+                "graph_repos_stars",
+                json!({}),
+                fixture("contributed_repos.json"),
+            )
+            .respond_always("owned_repo_totals", owned, fixture("owned_repos.json"))
+            .respond("stats_getter", fixture("stats.json"))
+            .respond("last_active_repo", fixture("last_active.json"))
+            .respond("top_languages", fixture("languages.json"))
+            .respond("commit_streak", fixture("streak.json"))
+            .respond("contributions_last_year", fixture("contributions.json"))
+            .respond("sponsors_count", fixture("sponsors.json"))
+            .respond("pinned_repos", fixture("pinned.json"))
+    }
+
+    #[tokio::test]
+    async fn run_stats_renders_the_card_from_github() {
+        let template = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/template.svg");
+        let output = exports::CACHE_DIR.join("card.svg");
+        fs::create_dir_all(&*exports::CACHE_DIR).unwrap();
+        let transport = github();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let hits = LOC_CACHE_HITS.load(Ordering::Relaxed);
+        let misses = LOC_CACHE_MISSES.load(Ordering::Relaxed);
+
+        let mut report = RunReport::default();
+
+        run_stats(
+            &transport,
+            vec![("dark", (template, output.clone()))],
+            &mut report,
+        )
+        .await
+        .unwrap();
+
+        let rendered: HashMap<String, String> = list_tspans(&output)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(_, id, text)| Some((id?, text)))
+            .collect();
+        let tspan = |id: &str| rendered[id].as_str();
+        assert_eq!(tspan("repos"), "2");
+        assert_eq!(tspan("contrib"), "4");
+        assert_eq!(tspan("stars"), "6");
+        assert_eq!(tspan("watchers"), "3");
+        assert_eq!(tspan("repos_public"), "1");
+        assert_eq!(tspan("repos_private"), "1");
+        assert_eq!(tspan("commits"), "2");
+        assert_eq!(tspan("issues"), "4");
+        assert_eq!(tspan("prs"), "7");
+        assert_eq!(tspan("loc"), "100");
+        assert_eq!(tspan("loc_add"), "130++");
+        assert_eq!(tspan("loc_del"), "30--");
+        assert_eq!(tspan("followers"), "12");
+        assert_eq!(tspan("sponsors"), "2");
+        assert!(tspan("last_active").starts_with("octo/hello ("));
+        assert_eq!(tspan("lang_1"), "Rust 75.0%");
+        assert_eq!(tspan("streak_current"), "3 days");
+        assert_eq!(tspan("streak_longest"), "3 days");
+        assert_eq!(tspan("contributions"), "1234");
+        assert_eq!(tspan("pinned_1"), "octo/hello (5)");
+
+        // Only the commits of the user count, and the fork isn't walked
+        let cache = LocCache::load("test-user", CACHE_COMMENT_SIZE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache.repos.len(), 1);
+        assert_eq!(cache.repos[0].commits, 3);
+        assert_eq!(cache.repos[0].my_commits, 2);
+        assert_eq!(cache.repos[0].loc_add, 130);
+        assert_eq!(cache.repos[0].loc_del, 30);
+        assert_eq!(transport.count("recursive_loc"), 2);
+        assert_eq!(transport.count("owned_repo_totals"), 1);
+
+        let report_file = exports::CACHE_DIR.join("report.json");
+        write_run_report(&report_file, &report).unwrap();
+        let written: Value =
+            serde_json::from_str(&fs::read_to_string(&report_file).unwrap()).unwrap();
+        assert_eq!(written["status"]["LOC"], "ok");
+        assert_eq!(written["status"]["pinned repos"], "ok");
+        assert_eq!(written["status"]["custom repositories"], "skipped");
+        assert_eq!(
+            written["cache"],
+            json!({ "hits": hits, "misses": misses + 1 })
+        );
+        assert_eq!(written["loc_cached"], false);
+        assert_eq!(written["error"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn account_data_fetches_follows_on_an_owner_cache_hit() {
+        let _lock = LOC_TEST_LOCK.lock().await;
+        fs::create_dir_all(&*exports::CACHE_DIR).unwrap();
+        save_owner(
+            "cached-owner",
+            &CachedOwner {
+                id: "U_cached".to_string(),
+                created_at: "2015-06-01T00:00:00Z".to_string(),
+            },
+        )
+        .unwrap();
+        let transport = MockTransport::new().respond("follow_counts", fixture("user.json"));
+
+        let (account, _) = account_data(&transport, "cached-owner", true)
+            .await
+            .unwrap();
+
+        assert_eq!(account.id, "U_cached");
+        assert_eq!(account.followers, Some(12));
+        assert_eq!(account.following, Some(3));
+        assert_eq!(transport.count("user_getter"), 0);
+    }
+
+    #[tokio::test]
+    async fn selected_queries_run_concurrently_and_keep_their_results() {
+        let query = |value: usize| async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            Ok::<_, StatsError>(value)
+        };
+
+        let start = Instant::now();
+        let ((first, first_time), (second, _), (skipped, skipped_time)) = tokio::join!(
+            run_selected(true, query(1)),
+            run_selected(true, query(2)),
+            run_selected(false, query(3)),
+        );
+
+        assert_eq!(first.unwrap().unwrap(), 1);
+        assert_eq!(second.unwrap().unwrap(), 2);
+        assert!(skipped.is_none());
+        assert_eq!(skipped_time, 0.0);
+        assert!(first_time >= 0.2);
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+
+    #[test]
+    fn combined_accounts_add_up_follows_and_keep_the_oldest() {
+        let account = |id: &str, created_at: &str, followers: Option<u64>| UserInfo {
+            id: id.to_string(),
+            created_at: created_at.to_string(),
+            followers,
+            following: Some(1),
+        };
+
+        let combined = combine_accounts(vec![
+            account("U_work", "2020-01-01T00:00:00Z", Some(5)),
+            account("U_octo", "2015-06-01T00:00:00Z", Some(12)),
+        ]);
+
+        assert_eq!(combined.id, "U_octo");
+        assert_eq!(combined.followers, Some(17));
+        assert_eq!(combined.following, Some(2));
+
+        // Offline the counts are unknown rather than zero
+        let offline = combine_accounts(vec![account("U_octo", "2015-06-01T00:00:00Z", None)]);
+        assert_eq!(offline.followers, None);
+    }
+
+    #[tokio::test]
+    async fn account_data_caches_the_owner_on_a_miss() {
+        let _lock = LOC_TEST_LOCK.lock().await;
+        fs::create_dir_all(&*exports::CACHE_DIR).unwrap();
+        let transport = MockTransport::new().respond("user_getter", fixture("user.json"));
+
+        let (fetched, _) = account_data(&transport, "new-owner", false).await.unwrap();
+        let (cached, _) = account_data(&transport, "new-owner", false).await.unwrap();
+
+        assert_eq!(fetched.id, "U_octo");
+        assert_eq!(
+            load_owner("new-owner"),
+            Some(CachedOwner {
+                id: "U_octo".to_string(),
+                created_at: "2015-06-01T00:00:00Z".to_string(),
+            })
+        );
+        assert_eq!(cached.id, "U_octo");
+        // The follows aren't shown, so the second run needs no query at all
+        assert_eq!(cached.followers, None);
+        assert_eq!(transport.requests().len(), 1);
+    }
+
+    #[test]
+    fn offline_runs_only_the_cached_queries() {
+        let parse = |args: &[&str]| Config::try_parse_from(["test"].iter().chain(args)).unwrap();
+
+        let offline = parse(&["--offline"]);
+        assert!(offline.loc_cache_only());
+        assert!(query_selected(&offline, "loc"));
+        assert!(query_selected(&offline, "commits"));
+        assert!(!query_selected(&offline, "stats"));
+        assert!(!query_selected(&offline, "stars"));
+
+        let only_stats = parse(&["--offline", "--only", "stats,loc"]);
+        assert!(query_selected(&only_stats, "loc"));
+        assert!(!query_selected(&only_stats, "stats"));
+        assert!(query_selected(&parse(&[]), "stats"));
+
+        assert!(Config::try_parse_from(["test", "--offline", "--refresh-owner"]).is_err());
+    }
+
+    #[test]
+    fn dry_runs_do_not_write_the_stats_json() {
+        fs::create_dir_all(&*exports::CACHE_DIR).unwrap();
+        let path = exports::CACHE_DIR.join("dry-run-stats.json");
+        let snapshot = StatsSnapshot {
+            stars: Some(12),
+            ..Default::default()
+        };
+
+        export_stats_json(&path, &snapshot, true).unwrap();
+        assert!(!path.exists());
+
+        export_stats_json(&path, &snapshot, false).unwrap();
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json["stars"], 12);
+    }
+}
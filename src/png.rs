@@ -0,0 +1,59 @@
+use resvg::{tiny_skia, usvg};
+use std::{fs, path::Path};
+
+use crate::error::StatsError;
+
+/// Rasterize the SVG at `svg_path` into a PNG at `png_path`, `scale` times
+/// its own size. Text is drawn with the system fonts.
+pub fn render_png(svg_path: &Path, png_path: &Path, scale: f32) -> Result<(), StatsError> {
+    let mut options = usvg::Options::default();
+    options.fontdb_mut().load_system_fonts();
+
+    let tree = usvg::Tree::from_data(&fs::read(svg_path)?, &options)
+        .map_err(|err| StatsError::Svg(format!("{}: {}", svg_path.display(), err)))?;
+
+    let size = tree
+        .size()
+        .to_int_size()
+        .scale_by(scale)
+        .ok_or_else(|| StatsError::Invalid(format!("Invalid PNG scale {}", scale)))?;
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| StatsError::Invalid(format!("Invalid PNG size {:?}", size)))?;
+
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    pixmap
+        .save_png(png_path)
+        .map_err(|err| StatsError::Svg(format!("{}: {}", png_path.display(), err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exports::CONFIG;
+
+    #[test]
+    fn render_png_scales_the_svg() {
+        let dir = CONFIG.cache_dir.join("png");
+        fs::create_dir_all(&dir).unwrap();
+        let svg_path = dir.join("card.svg");
+        let png_path = dir.join("card.png");
+        fs::write(
+        &svg_path,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10"><rect width="20" height="10" fill="red"/></svg>"#,
+    )
+    .unwrap();
+
+        render_png(&svg_path, &png_path, 2.0).unwrap();
+
+        // The width and height are the first fields of the IHDR chunk
+        let png = fs::read(&png_path).unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        let dimension = |at: usize| u32::from_be_bytes(png[at..at + 4].try_into().unwrap());
+        assert_eq!((dimension(16), dimension(20)), (40, 20));
+    }
+}
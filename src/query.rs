@@ -1,19 +1,51 @@
 use crate::{
-    exports::{get_auth_headers, OWNER_ID, USER_NAME},
-    utility::{query_count, simple_request},
+    cache::{
+        CachedRepo, LocCache, ResumePoint, cache_file, cache_key, load_resume, lock_cache,
+        name_hash, parse_field, partial_file, save_resume,
+    },
+    error::StatsError,
+    exports::{CACHE_DIR, CONFIG, DRY_RUN, OWNER_IDS, cache_path},
+    utility::{
+        GraphQlTransport, LOC_CACHE_HITS, LOC_CACHE_MISSES, LocStats, StatsSnapshot, StreakInfo,
+        account_age_days, account_age_years, check_graphql_errors, compute_streaks,
+        format_with_commas, query_count, require_path, write_atomic,
+    },
 };
+use chrono::Utc;
 use dotenvy::dotenv;
-use serde_json::{json, Value};
-use sha2::{Digest, Sha256};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use serde_json::{Value, json};
 use std::{
-    error::Error,
+    collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{BufRead, BufReader, Read, Write},
+    io::{self, BufRead, BufReader},
     path::Path,
+    sync::atomic::Ordering,
 };
-use xmltree::{Element, XMLNode};
+use xml::common::Position;
+use xmltree::{Element, EmitterConfig, ParseError, XMLNode};
 
-pub fn user_getter(username: &str) -> Result<(String, String), Box<dyn Error>> {
+// Commits fetched per page when walking a repository's history. On GitHub's
+// anti-abuse 403 the page size is halved, down to the minimum, and retried.
+const HISTORY_PAGE_SIZE: usize = 100;
+const MIN_HISTORY_PAGE_SIZE: usize = 25;
+
+/// Account details of the user, from `user_getter`.
+#[derive(Debug, Clone, Default)]
+pub struct UserInfo {
+    pub id: String,
+    // ISO 8601 timestamp of when the account was created
+    pub created_at: String,
+    // Unknown offline, when only the owner cache is read
+    pub followers: Option<u64>,
+    pub following: Option<u64>,
+}
+
+pub async fn user_getter(
+    transport: &impl GraphQlTransport,
+    username: &str,
+) -> Result<UserInfo, StatsError> {
     // Count the query usage
     query_count("user_getter");
 
@@ -23,24 +55,142 @@ pub fn user_getter(username: &str) -> Result<(String, String), Box<dyn Error>> {
             user(login: $login){
             id
             createdAt
+            followers {
+                totalCount
+            }
+            following {
+                totalCount
+            }
+            }
+        }
+    "#;
+
+    let variables = json!({ "login": username });
+
+    let json = transport.post("user_getter", query, &variables).await?;
+
+    Ok(parse_user(require_path(&json, "data/user")?))
+}
+
+/// Read the `user` object of a `user_getter` response.
+fn parse_user(user: &Value) -> UserInfo {
+    UserInfo {
+        id: user["id"].as_str().unwrap_or_default().to_string(),
+        created_at: user["createdAt"].as_str().unwrap_or_default().to_string(),
+        followers: Some(user["followers"]["totalCount"].as_u64().unwrap_or(0)),
+        following: Some(user["following"]["totalCount"].as_u64().unwrap_or(0)),
+    }
+}
+
+/// Follower and following counts of the user. These change from run to
+/// run, unlike the id and creation date kept in the owner cache.
+pub async fn follow_counts(
+    transport: &impl GraphQlTransport,
+    username: &str,
+) -> Result<(u64, u64), StatsError> {
+    query_count("follow_counts");
+
+    let query = r#"
+        query($login: String!){
+            user(login: $login){
+            followers {
+                totalCount
+            }
+            following {
+                totalCount
+            }
             }
         }
     "#;
 
     let variables = json!({ "login": username });
 
-    let response = simple_request("user_getter", query, variables)?;
+    let json = transport.post("follow_counts", query, &variables).await?;
+
+    let user = parse_user(require_path(&json, "data/user")?);
+    Ok((
+        user.followers.unwrap_or_default(),
+        user.following.unwrap_or_default(),
+    ))
+}
+
+/// Receives progress updates while LOC is counted, which can take minutes
+/// for large repositories.
+pub trait LocProgress: Sync {
+    /// Started recounting the `index`th (1-based) of `total` repositories.
+    fn repo_started(&self, name_with_owner: &str, index: usize, total: usize);
+
+    /// Fetched one more page of a repository's commit history.
+    fn page_fetched(&self, name_with_owner: &str);
+}
+
+/// Reports LOC progress through the log: repositories at `info`, pages at
+/// `debug`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogProgress;
+
+impl LocProgress for LogProgress {
+    fn repo_started(&self, name_with_owner: &str, index: usize, total: usize) {
+        info!(
+            "Counting LOC of {} ({} of {})",
+            name_with_owner, index, total
+        );
+    }
+
+    fn page_fetched(&self, name_with_owner: &str) {
+        debug!("Fetched a page of the history of {}", name_with_owner);
+    }
+}
+
+// Scopes a classic token needs, each with the broader scopes that imply it
+const REQUIRED_SCOPES: [(&str, &[&str]); 2] = [("read:user", &["user"]), ("repo", &[])];
+
+/// Check that ACCESS_TOKEN has the scopes the queries need. Without them
+/// GitHub answers with nulls that would render as zeros. Fine-grained tokens
+/// don't report scopes, so they are let through.
+pub async fn check_token_scopes(transport: &impl GraphQlTransport) -> Result<(), StatsError> {
+    query_count("check_token_scopes");
 
-    let json: Value = response.json()?;
-    let user = &json["data"]["user"];
+    let Some(scopes) = transport.token_scopes("check_token_scopes").await? else {
+        return Ok(());
+    };
+    let scopes = scopes.as_str();
+
+    let missing = missing_scopes(scopes);
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    Err(StatsError::Invalid(format!(
+        "ACCESS_TOKEN is missing the {} scope{} (it has: {}). Create a token with the \
+         read:user and repo scopes at https://github.com/settings/tokens",
+        missing.join(", "),
+        if missing.len() == 1 { "" } else { "s" },
+        if scopes.trim().is_empty() {
+            "none"
+        } else {
+            scopes.trim()
+        }
+    )))
+}
 
-    let id = user["id"].as_str().unwrap_or_default().to_string();
-    let created_at = user["createdAt"].as_str().unwrap_or_default().to_string();
+/// The `REQUIRED_SCOPES` not granted by an `X-OAuth-Scopes` header value.
+fn missing_scopes(header: &str) -> Vec<&'static str> {
+    let granted: Vec<&str> = header.split(',').map(str::trim).collect();
 
-    Ok((id, created_at))
+    REQUIRED_SCOPES
+        .iter()
+        .filter(|(scope, implied_by)| {
+            !granted.contains(scope) && !implied_by.iter().any(|s| granted.contains(s))
+        })
+        .map(|(scope, _)| *scope)
+        .collect()
 }
 
-pub fn recursive_loc(
+#[allow(clippy::too_many_arguments)]
+pub async fn recursive_loc(
+    transport: &impl GraphQlTransport,
+    progress: &dyn LocProgress,
     owner: &str,
     repo_name: &str,
     data: &mut Value,
@@ -49,17 +199,20 @@ pub fn recursive_loc(
     deletion_total: usize,
     my_commits: usize,
     cursor: Option<String>,
-) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    page_size: usize,
+    since: Option<&str>,
+) -> Result<(usize, usize, usize), StatsError> {
     query_count("recursive_loc");
 
     // GraphQL query with pagination
     let query = r#"
-        query ($repo_name: String!, $owner: String!, $cursor: String) {
+        query ($repo_name: String!, $owner: String!, $cursor: String, $page_size: Int!, $since: GitTimestamp) {
             repository(name: $repo_name, owner: $owner) {
                 defaultBranchRef {
                     target {
+                        __typename
                         ... on Commit {
-                            history(first: 100, after: $cursor) {
+                            history(first: $page_size, after: $cursor, since: $since) {
                                 totalCount
                                 edges {
                                     node {
@@ -90,52 +243,88 @@ pub fn recursive_loc(
     let variables = json!({
         "repo_name": repo_name,
         "owner": owner,
-        "cursor": cursor
+        "cursor": cursor,
+        "page_size": page_size,
+        "since": since
     });
 
-    let client = reqwest::blocking::Client::new();
-    let response = client
-        .post("https://api.github.com/graphql")
-        .headers(get_auth_headers())
-        .json(&json!({
-            "query": query,
-            "variables": variables,
-        }))
-        .send()?;
-
-    let status = response.status();
-    let json: Value = response.json()?;
-
-    if status == 200 {
-        let repo = &json["data"]["repository"]["defaultBranchRef"];
-        if !repo.is_null() {
-            let history = &repo["target"]["history"];
-            return loc_counter_one_repo(
+    let json = match transport.post_raw("recursive_loc", query, &variables).await {
+        Ok(json) => json,
+        // Smaller pages are less likely to trip the anti-abuse limit, so
+        // retry the same cursor with half the page size before giving up.
+        Err(StatsError::RateLimited(_)) if page_size > MIN_HISTORY_PAGE_SIZE => {
+            let smaller = (page_size / 2).max(MIN_HISTORY_PAGE_SIZE);
+            warn!(
+                "recursive_loc: {}/{} hit the anti-abuse limit, retrying with {} commits per page",
+                owner, repo_name, smaller
+            );
+            return Box::pin(recursive_loc(
+                transport,
+                progress,
                 owner,
                 repo_name,
                 data,
                 cache_comment,
-                history,
                 addition_total,
                 deletion_total,
                 my_commits,
-            );
-        } else {
-            return Ok((0, 0, 0));
+                cursor,
+                smaller,
+                since,
+            ))
+            .await;
         }
-    }
+        Err(err) => {
+            force_close_file(data, cache_comment)?;
+            return Err(err);
+        }
+    };
 
-    force_close_file(data, cache_comment)?;
+    check_graphql_errors("recursive_loc", &json)?;
+    progress.page_fetched(&format!("{}/{}", owner, repo_name));
+
+    let repo = &json["data"]["repository"]["defaultBranchRef"];
+    if repo.is_null() {
+        info!(
+            "recursive_loc: {}/{} has no default branch, counting it as empty",
+            owner, repo_name
+        );
+        return Ok((addition_total, deletion_total, my_commits));
+    }
 
-    if status == 403 {
-        return Err("Too many arguments! You've hit Github's Anti-abuse limit".into());
+    // `history` only exists on commits; a branch pointing at a tag or other
+    // object would otherwise silently count as zero.
+    let target_type = repo["target"]["__typename"].as_str().unwrap_or("nothing");
+    if target_type != "Commit" {
+        warn!(
+            "recursive_loc: default branch of {}/{} points at a {}, not a commit; its LOC is not counted",
+            owner, repo_name, target_type
+        );
+        return Ok((addition_total, deletion_total, my_commits));
     }
 
-    // Generic error
-    Err(format!("recursive_loc() failed with status {}: {:?}", status, json).into())
+    let history = &repo["target"]["history"];
+    loc_counter_one_repo(
+        transport,
+        progress,
+        owner,
+        repo_name,
+        data,
+        cache_comment,
+        history,
+        addition_total,
+        deletion_total,
+        my_commits,
+        page_size,
+        since,
+    )
+    .await
 }
 
-pub fn loc_counter_one_repo(
+#[allow(clippy::too_many_arguments)]
+pub async fn loc_counter_one_repo(
+    transport: &impl GraphQlTransport,
+    progress: &dyn LocProgress,
     owner: &str,
     repo_name: &str,
     data: &mut Value,
@@ -144,11 +333,17 @@ pub fn loc_counter_one_repo(
     mut addition_total: usize,
     mut deletion_total: usize,
     mut my_commits: usize,
-) -> Result<(usize, usize, usize), Box<dyn Error>> {
+    page_size: usize,
+    since: Option<&str>,
+) -> Result<(usize, usize, usize), StatsError> {
     if let Some(edges) = history["edges"].as_array() {
         for node in edges {
             let author_id = &node["node"]["author"]["user"]["id"];
-            if !author_id.is_null() && author_id == OWNER_ID.get().unwrap() {
+            let owner_ids = OWNER_IDS.get().unwrap();
+            if author_id
+                .as_str()
+                .is_some_and(|author_id| owner_ids.iter().any(|id| id == author_id))
+            {
                 my_commits += 1;
                 addition_total += node["node"]["additions"].as_u64().unwrap_or(0) as usize;
                 deletion_total += node["node"]["deletions"].as_u64().unwrap_or(0) as usize;
@@ -162,7 +357,16 @@ pub fn loc_counter_one_repo(
             let end_cursor = history["pageInfo"]["endCursor"]
                 .as_str()
                 .map(|s| s.to_string());
-            return recursive_loc(
+
+            // Where to pick up again should a later page fail
+            data["cursor"] = json!(end_cursor);
+            data["loc_add"] = json!(addition_total);
+            data["loc_del"] = json!(deletion_total);
+            data["my_commits"] = json!(my_commits);
+
+            return Box::pin(recursive_loc(
+                transport,
+                progress,
                 owner,
                 repo_name,
                 data,
@@ -171,30 +375,238 @@ pub fn loc_counter_one_repo(
                 deletion_total,
                 my_commits,
                 end_cursor,
-            );
+                page_size,
+                since,
+            ))
+            .await;
         }
     }
     // Base case: no more pages
     Ok((addition_total, deletion_total, my_commits))
 }
 
-pub fn loc_query(
+#[allow(clippy::too_many_arguments)]
+pub async fn loc_query(
+    transport: &impl GraphQlTransport,
+    progress: &dyn LocProgress,
     owner_affiliation: Vec<String>,
     comment_size: usize,
     force_cache: bool,
-    cursor: Option<String>,
-    mut edges: Vec<Value>,
-) -> Result<(i32, i32, i32, bool), Box<dyn Error>> {
-    query_count("loc_query");
+    force_repos: &[String],
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+    max_repos: Option<usize>,
+    user_names: &[&str],
+) -> Result<(i32, i32, i32, bool), StatsError> {
+    // The repository list only matters for recounting, which `--cache-only`
+    // never does
+    if CONFIG.loc_cache_only() {
+        return cache_builder(
+            transport,
+            progress,
+            &[],
+            comment_size,
+            force_cache,
+            force_repos,
+            0,
+            0,
+            &user_names.join(","),
+        )
+        .await;
+    }
+
+    let mut edges = Vec::new();
+    for user_name in user_names {
+        let max_edges = max_repos.map(|max_repos| max_repos.saturating_sub(edges.len()));
+        let (user_edges, truncated) = loc_edges(
+            transport,
+            &owner_affiliation,
+            page_size,
+            max_edges,
+            user_name,
+        )
+        .await?;
+        edges.extend(user_edges);
 
+        if truncated {
+            warn!(
+                "Stopped listing repositories at --max-repos {}, LOC only covers those",
+                max_repos.unwrap_or_default()
+            );
+            break;
+        }
+    }
+
+    cache_builder(
+        transport,
+        progress,
+        &counted_edges(edges, include_forks, include_archived),
+        comment_size,
+        force_cache,
+        force_repos,
+        0,
+        0,
+        &user_names.join(","),
+    )
+    .await
+}
+
+/// The edges LOC is counted for. Forks, archived, excluded and
+/// topic-filtered repositories are dropped before caching, so the cache only
+/// ever holds the repositories that are counted. A repository listed under
+/// several affiliations, or for several users, is only kept the first time,
+/// so its LOC isn't counted twice.
+fn counted_edges(mut edges: Vec<Value>, include_forks: bool, include_archived: bool) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    edges.retain(|edge| {
+        (include_forks || !is_fork(edge))
+            && (include_archived || !is_archived(edge))
+            && !is_excluded(edge)
+            && is_topic_selected(edge)
+            && edge
+                .pointer("/node/nameWithOwner")
+                .and_then(|v| v.as_str())
+                .is_none_or(|name| seen.insert(name.to_string()))
+    });
+
+    edges
+}
+
+/// Check the LOC cache of the users against a fresh listing of their
+/// repositories, which is all the API calls it makes. Returns a line per
+/// discrepancy found, none if the cache is consistent.
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_cache(
+    transport: &impl GraphQlTransport,
+    owner_affiliation: Vec<String>,
+    comment_size: usize,
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+    user_names: &[&str],
+) -> Result<Vec<String>, StatsError> {
+    let users_key = user_names.join(",");
+    let Some(cache) = LocCache::load(&users_key, comment_size)? else {
+        return Err(StatsError::Cache(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no LOC cache at {}", cache_file(&users_key).display()),
+        )));
+    };
+
+    let (loc_add, loc_del) = cache.loc_totals();
+    info!(
+        "The LOC cache holds {} commits of yours and +{} -{} LOC",
+        format_with_commas(cache.repos.iter().map(|repo| repo.my_commits).sum()),
+        format_with_commas(loc_add),
+        format_with_commas(loc_del)
+    );
+
+    let mut edges = Vec::new();
+    for user_name in user_names {
+        let (user_edges, _) =
+            loc_edges(transport, &owner_affiliation, page_size, None, user_name).await?;
+        edges.extend(user_edges);
+    }
+
+    Ok(cache_discrepancies(
+        &cache,
+        &counted_edges(edges, include_forks, include_archived),
+    ))
+}
+
+/// Where `cache` disagrees with the repository `edges` it should hold an
+/// entry for at the same position: entries of the wrong repository or with
+/// another commit count than GitHub reports, entries that can't be right,
+/// and the commit totals.
+fn cache_discrepancies(cache: &LocCache, edges: &[Value]) -> Vec<String> {
+    let mut found = Vec::new();
+
+    if cache.repos.len() != edges.len() {
+        found.push(format!(
+            "the cache has {} entries for {} repositories",
+            cache.repos.len(),
+            edges.len()
+        ));
+    }
+
+    let mut github_commits = 0;
+    for (index, edge) in edges.iter().enumerate() {
+        let name = edge
+            .pointer("/node/nameWithOwner")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let commits = edge
+            .pointer("/node/defaultBranchRef/target/history/totalCount")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(0);
+        github_commits += commits;
+
+        match cache.repos.get(index) {
+            Some(repo) if repo.hash != name_hash(name) => found.push(format!(
+                "{}: entry {} is of another repository",
+                name, index
+            )),
+            Some(repo) if repo.commits != commits => found.push(format!(
+                "{}: {} commits cached, GitHub has {}",
+                name, repo.commits, commits
+            )),
+            Some(_) => {}
+            None => found.push(format!("{}: not cached", name)),
+        }
+    }
+
+    for (index, repo) in cache.repos.iter().enumerate() {
+        if repo.my_commits < 0
+            || repo.my_commits > repo.commits
+            || repo.loc_add < 0
+            || repo.loc_del < 0
+        {
+            found.push(format!(
+                "entry {}: {} of {} commits with +{} -{} LOC can't be right",
+                index, repo.my_commits, repo.commits, repo.loc_add, repo.loc_del
+            ));
+        }
+    }
+
+    let cached_commits: i64 = cache.repos.iter().map(|repo| repo.commits).sum();
+    if cached_commits != github_commits {
+        found.push(format!(
+            "{} commits cached in total, GitHub has {}",
+            cached_commits, github_commits
+        ));
+    }
+
+    found
+}
+
+/// Every repository edge of one user for `loc_query`, walking all pages, or
+/// the first `max_edges` of them. The flag is set when pages were left
+/// unread because of that cap.
+async fn loc_edges(
+    transport: &impl GraphQlTransport,
+    owner_affiliation: &[String],
+    page_size: usize,
+    max_edges: Option<usize>,
+    user_name: &str,
+) -> Result<(Vec<Value>, bool), StatsError> {
     let query = r#"
-        query ($owner_affiliation: [RepositoryAffiliation], $login: String!, $cursor: String) {
+        query ($owner_affiliation: [RepositoryAffiliation], $login: String!, $cursor: String, $page_size: Int!) {
             user(login: $login) {
-                repositories(first: 60, after: $cursor, ownerAffiliations: $owner_affiliation) {
+                repositories(first: $page_size, after: $cursor, ownerAffiliations: $owner_affiliation) {
                     edges {
                         node {
                             ... on Repository {
                                 nameWithOwner
+                                isFork
+                                isArchived
+                                repositoryTopics(first: 20) {
+                                    nodes {
+                                        topic {
+                                            name
+                                        }
+                                    }
+                                }
                                 defaultBranchRef {
                                     target {
                                         ... on Commit {
@@ -216,487 +628,4107 @@ pub fn loc_query(
         }
     "#;
 
-    let variables = json!({
-        "owner_affiliation": owner_affiliation,
-        "login": *USER_NAME,
-        "cursor": cursor,
-    });
+    let mut edges = Vec::new();
+    let mut cursor: Option<String> = None;
+
+    loop {
+        if max_edges.is_some_and(|max_edges| edges.len() >= max_edges) {
+            return Ok((edges, true));
+        }
+
+        query_count("loc_query");
 
-    let response = simple_request("loc_query", query, variables)?;
-    let json_data: Value = response.json()?;
+        // No more than the cap allows is asked for
+        let page_size = match max_edges {
+            Some(max_edges) => page_size.min(max_edges - edges.len()),
+            None => page_size,
+        };
+        let variables = json!({
+            "owner_affiliation": owner_affiliation,
+            "login": user_name,
+            "cursor": cursor,
+            "page_size": page_size,
+        });
 
-    let repo_data = &json_data["data"]["user"]["repositories"];
-    let new_edges = repo_data["edges"].as_array().unwrap_or(&vec![]).clone();
-    edges.extend(new_edges);
+        let json_data = transport.post("loc_query", query, &variables).await?;
 
-    let has_next = repo_data["pageInfo"]["hasNextPage"]
-        .as_bool()
-        .unwrap_or(false);
-    if has_next {
-        let end_cursor = repo_data["pageInfo"]["endCursor"]
+        let repo_data = require_path(&json_data, "data/user/repositories")?;
+        edges.extend(repo_data["edges"].as_array().into_iter().flatten().cloned());
+
+        if !repo_data["pageInfo"]["hasNextPage"]
+            .as_bool()
+            .unwrap_or(false)
+        {
+            return Ok((edges, false));
+        }
+        cursor = repo_data["pageInfo"]["endCursor"]
             .as_str()
             .map(|s| s.to_string());
-        return loc_query(owner_affiliation, comment_size, force_cache, cursor, edges);
     }
+}
 
-    cache_builder(
-        &edges,
-        comment_size,
-        force_cache,
-        0,
-        0,
-        USER_NAME.clone().as_str(),
-    )
+/// The resume point a failed recount left in its state, if it got past the
+/// first page.
+fn resume_point(state: &Value, cache_key: String, commits: i64) -> Option<ResumePoint> {
+    let count = |key: &str| state[key].as_u64().unwrap_or(0) as usize;
+
+    Some(ResumePoint {
+        cache_key,
+        commits,
+        cursor: state["cursor"].as_str()?.to_string(),
+        loc_add: count("loc_add"),
+        loc_del: count("loc_del"),
+        my_commits: count("my_commits"),
+    })
 }
 
-pub fn commit_counter(comment_size: usize) -> Result<usize, Box<dyn Error>> {
-    let hash = Sha256::digest(USER_NAME.as_bytes());
-    let filename = format!("cache/{}.txt", hex::encode(hash));
+/// Whether a repository edge is a fork.
+fn is_fork(edge: &Value) -> bool {
+    edge["node"]["isFork"].as_bool().unwrap_or(false)
+}
 
-    let file = File::open(&filename)?;
-    let reader = BufReader::new(file);
+/// Whether a repository edge is listed in `--exclude-repos`/EXCLUDE_REPOS.
+fn is_excluded(edge: &Value) -> bool {
+    is_listed(edge, &CONFIG.exclude_repos)
+}
 
-    let mut total_commits = 0;
+/// Whether the `nameWithOwner` of a repository edge is one of `repos`,
+/// ignoring case.
+fn is_listed(edge: &Value, repos: &[String]) -> bool {
+    edge.pointer("/node/nameWithOwner")
+        .and_then(|v| v.as_str())
+        .is_some_and(|name| {
+            repos
+                .iter()
+                .any(|listed| listed.trim().eq_ignore_ascii_case(name))
+        })
+}
 
-    for (index, line_result) in reader.lines().enumerate() {
-        let line = line_result?;
-        if index < comment_size {
-            continue; // skip comment block
-        }
+/// Whether a repository edge passes `--include-topics` and `--exclude-topics`.
+fn is_topic_selected(edge: &Value) -> bool {
+    topics_match(edge, &CONFIG.include_topics, &CONFIG.exclude_topics)
+}
 
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() > 3 {
-            if let Ok(commit_count) = parts[2].parse::<i32>() {
-                total_commits += commit_count;
-            }
-        }
-    }
+/// Whether a repository edge has one of the `include` topics, if any are
+/// given, and none of the `exclude` ones. Topics are matched ignoring case.
+fn topics_match(edge: &Value, include: &[String], exclude: &[String]) -> bool {
+    let topics: Vec<&str> = edge["node"]["repositoryTopics"]["nodes"]
+        .as_array()
+        .map_or(&[][..], |nodes| nodes)
+        .iter()
+        .filter_map(|node| node["topic"]["name"].as_str())
+        .collect();
+    let has_any = |wanted: &[String]| {
+        wanted.iter().any(|wanted| {
+            topics
+                .iter()
+                .any(|topic| topic.eq_ignore_ascii_case(wanted.trim()))
+        })
+    };
+
+    (include.is_empty() || has_any(include)) && !has_any(exclude)
+}
+
+/// Whether `--include-topics` or `--exclude-topics` narrow the repositories
+/// down, so that GitHub's total counts can't be used as they are.
+fn topic_filtered() -> bool {
+    !CONFIG.include_topics.is_empty() || !CONFIG.exclude_topics.is_empty()
+}
+
+/// Whether a repository edge has no default branch, i.e. is empty.
+fn is_empty_repo(edge: &Value) -> bool {
+    edge["node"]["defaultBranchRef"].is_null()
+}
+
+/// Whether a repository edge is archived.
+fn is_archived(edge: &Value) -> bool {
+    edge["node"]["isArchived"].as_bool().unwrap_or(false)
+}
+
+/// Sum the user's own commits (`my_commits`) from the LOC cache. Each entry
+/// also records `commits`, the repository's whole history, which is only used
+/// to notice when a repository changed.
+pub fn commit_counter(comment_size: usize) -> Result<usize, StatsError> {
+    let users_key = CONFIG.users_key()?;
+    let Some(cache) = LocCache::load(&users_key, comment_size)? else {
+        return Err(StatsError::Cache(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no LOC cache at {}", cache_file(&users_key).display()),
+        )));
+    };
+
+    let total_commits: i64 = cache.repos.iter().map(|repo| repo.my_commits).sum();
 
     Ok(total_commits as usize)
 }
 
-pub fn graph_repos_stars(
-    count_type: &str,
-    owner_affiliation: Vec<String>,
-    cursor: Option<String>,
-    user_name: &str,
-    github_token: &str,
-) -> Result<usize, Box<dyn Error>> {
+pub async fn repo_getter(
+    transport: &impl GraphQlTransport,
+    owner: &str,
+    name: &str,
+) -> Result<Option<Value>, StatsError> {
+    query_count("repo_getter");
+
     let query = r#"
-        query ($owner_affiliation: [RepositoryAffiliation], $login: String!, $cursor: String) {
-            user(login: $login) {
-                repositories(first: 100, after: $cursor, ownerAffiliations: $owner_affiliation) {
+        query ($owner: String!, $name: String!) {
+            repository(owner: $owner, name: $name) {
+                nameWithOwner
+                stargazers {
                     totalCount
-                    edges {
-                        node {
-                            ... on Repository {
-                                nameWithOwner
-                                stargazers {
-                                    totalCount
-                                }
+                }
+                defaultBranchRef {
+                    target {
+                        ... on Commit {
+                            history {
+                                totalCount
                             }
                         }
                     }
-                    pageInfo {
-                        endCursor
-                        hasNextPage
-                    }
                 }
             }
         }
     "#;
 
-    let variables = json!({
-        "owner_affiliation": owner_affiliation,
-        "login": user_name,
-        "cursor": cursor
-    });
+    let variables = json!({ "owner": owner, "name": name });
 
-    let response = simple_request("graph_repos_stars", query, variables)?;
-    let json: Value = response.json()?;
+    let json = transport.post_raw("repo_getter", query, &variables).await?;
 
-    println!("Graph star repo: Here's the json response: {:#?}", &json);
-    let user = &json["data"]["user"];
-    let repos = &user["repositories"];
+    // A missing repository comes back as null with a NOT_FOUND error, which
+    // the caller reports and skips
+    let repo = &json["data"]["repository"];
+    if repo.is_null() {
+        return Ok(None);
+    }
+    check_graphql_errors("repo_getter", &json)?;
 
-    match count_type {
-        "repos" => Ok(repos["totalCount"].as_i64().unwrap_or(0) as usize),
-        "stars" => {
-            let mut total_stars = 0;
-            if let Some(edges) = repos["edges"].as_array() {
-                for edge in edges {
-                    total_stars += edge["node"]["stargazers"]["totalCount"]
-                        .as_i64()
-                        .unwrap_or(0);
-                }
-            }
-            Ok(total_stars as usize)
+    Ok(Some(repo.clone()))
+}
+
+/// Resolve each `owner/name` in `repos` into an edge shaped like the ones
+/// `loc_query` collects, so the result can go straight into `cache_builder`.
+/// Repositories that can't be found are reported and skipped.
+pub async fn custom_repo_edges(
+    transport: &impl GraphQlTransport,
+    repos: &[String],
+) -> Result<Vec<Value>, StatsError> {
+    let mut edges = Vec::new();
+
+    for repo in repos {
+        let Some((owner, name)) = repo.split_once('/') else {
+            warn!("Skipping \"{}\": expected owner/name", repo);
+            continue;
+        };
+
+        match repo_getter(transport, owner, name).await? {
+            Some(node) => edges.push(json!({ "node": node })),
+            None => warn!("Skipping \"{}\": repository not found", repo),
         }
-        _ => Err("Invalid Count type. Use \"repos\" or \"stars\".".into()),
     }
+
+    Ok(edges)
 }
 
-pub fn stats_getter() -> Result<Value, Box<dyn Error>> {
-    query_count("stats_getter");
+/// Sum the stargazers of edges returned by `custom_repo_edges`.
+pub fn custom_repo_stars(edges: &[Value]) -> usize {
+    edges
+        .iter()
+        .map(|edge| {
+            edge["node"]["stargazers"]["totalCount"]
+                .as_u64()
+                .unwrap_or(0) as usize
+        })
+        .sum()
+}
 
-    let query = r#"
-    query($login: String!) {
+/// Repositories per page of the LOC query unless `--page-size` is given.
+pub const LOC_PAGE_SIZE: usize = 60;
+
+/// Repositories per page of the star, watcher and repository count queries
+/// unless `--page-size` is given.
+pub const REPOS_PAGE_SIZE: usize = 100;
+
+const REPOS_STARS_QUERY: &str = r#"
+    query ($owner_affiliation: [RepositoryAffiliation], $login: String!, $cursor: String, $page_size: Int!) {
         user(login: $login) {
-            pullRequests(first: 1) {
+            repositories(first: $page_size, after: $cursor, ownerAffiliations: $owner_affiliation) {
                 totalCount
+                edges {
+                    node {
+                        ... on Repository {
+                            nameWithOwner
+                            isFork
+                            isArchived
+                            repositoryTopics(first: 20) {
+                                nodes {
+                                    topic {
+                                        name
+                                    }
+                                }
+                            }
+                            isPrivate
+                            stargazers {
+                                totalCount
+                            }
+                            watchers {
+                                totalCount
+                            }
+                        }
+                    }
+                }
+                pageInfo {
+                    endCursor
+                    hasNextPage
+                }
             }
-            issues {
-                totalCount
+        }
+    }
+"#;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn graph_repos_stars(
+    transport: &impl GraphQlTransport,
+    count_type: &str,
+    owner_affiliation: Vec<String>,
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+    cursor: Option<String>,
+    user_names: &[&str],
+) -> Result<usize, StatsError> {
+    match count_type {
+        // Several users may share repositories, which only count once, and
+        // topic filters need every repository looked at
+        "repos" if user_names.len() > 1 || topic_filtered() => {
+            let mut private = HashMap::new();
+            for user_name in user_names {
+                private.extend(
+                    repo_privacy(transport, &owner_affiliation, page_size, user_name).await?,
+                );
             }
+            Ok(private.len())
         }
-    }"#;
+        "repos" => {
+            let user_name = user_names.first().copied().unwrap_or_default();
+            let variables = json!({
+                "owner_affiliation": owner_affiliation,
+                "login": user_name,
+                "cursor": cursor,
+                "page_size": page_size
+            });
 
-    let variables = json!({ "login": USER_NAME.to_string() });
+            let json = transport
+                .post("graph_repos_stars", REPOS_STARS_QUERY, &variables)
+                .await?;
 
-    let response = simple_request("stats_getter", query, variables)?;
-    let json: Value = response.json()?;
+            debug!("Graph star repo: Here's the json response: {:#?}", &json);
+            let total_count = require_path(&json, "data/user/repositories/totalCount")?;
+            Ok(total_count.as_u64().unwrap_or(0) as usize)
+        }
+        "stars" | "watchers" => {
+            let field = if count_type == "stars" {
+                "stargazers"
+            } else {
+                "watchers"
+            };
+            // Keyed by `nameWithOwner`, so repositories the users share
+            // are only counted once
+            let mut counts = HashMap::new();
+            for user_name in user_names {
+                counts.extend(
+                    repo_counts(
+                        transport,
+                        field,
+                        &owner_affiliation,
+                        include_forks,
+                        include_archived,
+                        page_size,
+                        cursor.clone(),
+                        user_name,
+                    )
+                    .await?,
+                );
+            }
+            Ok(counts.values().sum())
+        }
+        _ => Err(StatsError::Invalid(
+            "Invalid Count type. Use \"repos\", \"stars\" or \"watchers\".".to_string(),
+        )),
+    }
+}
 
-    println!("Stats: Here's the json response: {:#?}", &json);
+/// The `field` (`stargazers` or `watchers`) total of every counted repository
+/// the user is affiliated with, keyed by `nameWithOwner` so a repository is
+/// only counted once however many of the affiliations it falls under.
+#[allow(clippy::too_many_arguments)]
+async fn repo_counts(
+    transport: &impl GraphQlTransport,
+    field: &str,
+    owner_affiliation: &[String],
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+    mut cursor: Option<String>,
+    user_name: &str,
+) -> Result<HashMap<String, usize>, StatsError> {
+    let mut counts = HashMap::new();
 
-    // Instead of converting to HashMap, return the relevant user_data part as Value
-    let user_data = &json["data"]["user"];
+    // Counts are summed per repository, so every page has to be walked
+    loop {
+        let variables = json!({
+            "owner_affiliation": owner_affiliation,
+            "login": user_name,
+            "cursor": cursor,
+            "page_size": page_size
+        });
 
-    Ok(user_data.clone()) // clone to return owned Value
-}
+        let json = transport
+            .post("graph_repos_stars", REPOS_STARS_QUERY, &variables)
+            .await?;
 
-pub fn add_archive() -> Result<[i32; 5], Box<dyn Error>> {
-    let file = File::open("cache/repository_archive.txt")?;
-    let reader = BufReader::new(file);
+        debug!("Graph star repo: Here's the json response: {:#?}", &json);
+        let repos = require_path(&json, "data/user/repositories")?;
 
-    let lines: Vec<String> = reader.lines().filter_map(Result::ok).collect();
+        add_repo_counts(
+            repos["edges"].as_array().map_or(&[][..], |edges| edges),
+            field,
+            include_forks,
+            include_archived,
+            &mut counts,
+        );
 
-    if lines.len() < 10 {
-        return Ok([0, 0, 0, 0, 0]);
+        if !repos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+            return Ok(counts);
+        }
+        cursor = repos["pageInfo"]["endCursor"]
+            .as_str()
+            .map(|s| s.to_string());
     }
+}
 
-    let data = &lines[7..lines.len() - 3];
-    let mut added_loc = 0;
-    let mut deleted_loc = 0;
-    let mut added_commits = 0;
-    let contributed_repos = data.len();
+/// Record the `field` total of each counted edge under its `nameWithOwner`.
+fn add_repo_counts(
+    edges: &[Value],
+    field: &str,
+    include_forks: bool,
+    include_archived: bool,
+    counts: &mut HashMap<String, usize>,
+) {
+    let counted = edges.iter().filter(|edge| {
+        (include_forks || !is_fork(edge))
+            && (include_archived || !is_archived(edge))
+            && is_topic_selected(edge)
+    });
+    for edge in counted {
+        if let Some(name_with_owner) = edge["node"]["nameWithOwner"].as_str() {
+            counts.insert(
+                name_with_owner.to_string(),
+                edge["node"][field]["totalCount"].as_i64().unwrap_or(0) as usize,
+            );
+        }
+    }
+}
+
+/// Stars and watchers of the counted repositories the users are affiliated
+/// with, and how many of the repositories are public and private.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OwnedRepoTotals {
+    pub stars: usize,
+    pub watchers: usize,
+    pub public: usize,
+    pub private: usize,
+}
 
-    for line in data {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 5 {
-            let my_commits = parts[2];
-            let loc_add = parts[3].parse::<i32>().unwrap_or(0);
-            let loc_del = parts[4].parse::<i32>().unwrap_or(0);
+/// Walk the repositories the users are affiliated with once for their stars,
+/// watchers and visibility. Stars and watchers leave forks and archived
+/// repositories out unless included, like `graph_repos_stars`; visibility
+/// counts every repository like the `"repos"` total does. Repositories the
+/// users share are only counted once.
+pub async fn owned_repo_totals(
+    transport: &impl GraphQlTransport,
+    owner_affiliation: Vec<String>,
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+    user_names: &[&str],
+) -> Result<OwnedRepoTotals, StatsError> {
+    let mut stars = HashMap::new();
+    let mut watchers = HashMap::new();
+    let mut private = HashMap::new();
 
-            added_loc += loc_add;
-            deleted_loc += loc_del;
+    for user_name in user_names {
+        let mut cursor: Option<String> = None;
+        loop {
+            query_count("owned_repo_totals");
 
-            if let Ok(val) = my_commits.parse::<i32>() {
-                added_commits += val;
-            }
-        }
-    }
+            let variables = json!({
+                "owner_affiliation": owner_affiliation,
+                "login": user_name,
+                "cursor": cursor,
+                "page_size": page_size
+            });
 
-    // Parse last line's 5th element, strip the trailing `,` if present
-    if let Some(last_line) = lines.last() {
-        let last_parts: Vec<&str> = last_line.split_whitespace().collect();
-        if last_parts.len() >= 5 {
-            let val_str = last_parts[4].trim_end_matches(',');
-            if let Ok(val) = val_str.parse::<i32>() {
-                added_commits += val;
+            let json = transport
+                .post("owned_repo_totals", REPOS_STARS_QUERY, &variables)
+                .await?;
+
+            debug!("Owned repos: Here's the json response: {:#?}", &json);
+            let repos = require_path(&json, "data/user/repositories")?;
+            let edges = repos["edges"].as_array().map_or(&[][..], |edges| edges);
+
+            add_repo_counts(
+                edges,
+                "stargazers",
+                include_forks,
+                include_archived,
+                &mut stars,
+            );
+            add_repo_counts(
+                edges,
+                "watchers",
+                include_forks,
+                include_archived,
+                &mut watchers,
+            );
+            add_repo_visibility(edges, &mut private);
+
+            if !repos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+                break;
             }
+            cursor = repos["pageInfo"]["endCursor"]
+                .as_str()
+                .map(|s| s.to_string());
         }
     }
 
-    Ok([
-        added_loc,
-        deleted_loc,
-        added_loc - deleted_loc,
-        added_commits,
-        contributed_repos as i32,
-    ])
+    let private_count = private.values().filter(|&&is_private| is_private).count();
+    Ok(OwnedRepoTotals {
+        stars: stars.values().sum(),
+        watchers: watchers.values().sum(),
+        public: private.len() - private_count,
+        private: private_count,
+    })
 }
 
-pub fn cache_builder(
-    edges: &Vec<Value>,
-    comment_size: usize,
-    force_cache: bool,
-    mut loc_add: i32,
-    mut loc_del: i32,
+/// Whether each repository the user is affiliated with is private, keyed by
+/// `nameWithOwner`.
+async fn repo_privacy(
+    transport: &impl GraphQlTransport,
+    owner_affiliation: &[String],
+    page_size: usize,
     user_name: &str,
-) -> Result<(i32, i32, i32, bool), Box<dyn Error>> {
-    let mut cached = true;
+) -> Result<HashMap<String, bool>, StatsError> {
+    let mut private = HashMap::new();
+    let mut cursor: Option<String> = None;
 
-    // Determine cache filename
-    let hash = Sha256::digest(user_name.as_bytes());
-    let filename = format!("cache/{}.txt", hex::encode(hash));
-    fs::create_dir_all("cache")?; // Ensure directory exists
+    loop {
+        let variables = json!({
+            "owner_affiliation": owner_affiliation,
+            "login": user_name,
+            "cursor": cursor,
+            "page_size": page_size
+        });
 
-    // Read or initialize cache data
-    let mut data: Vec<String> = if Path::new(&filename).exists() {
-        BufReader::new(File::open(&filename)?)
-            .lines()
-            .filter_map(Result::ok)
-            .collect()
-    } else {
-        let mut f = File::create(&filename)?;
-        let comments = vec![
-            "This line is a comment block. Write whatever you want here."
-                .to_string();
-            comment_size
-        ];
-        for line in &comments {
-            writeln!(f, "{}", line)?;
-        }
-        comments
-    };
+        let json = transport
+            .post("repo_visibility", REPOS_STARS_QUERY, &variables)
+            .await?;
 
-    // If cache size doesn't match or force is set, flush
-    if data.len().saturating_sub(comment_size) != edges.len() || force_cache {
-        cached = false;
-        flush_cache(edges, &filename, comment_size)?;
-        data = BufReader::new(File::open(&filename)?)
-            .lines()
-            .filter_map(Result::ok)
-            .collect();
+        debug!("Repo visibility: Here's the json response: {:#?}", &json);
+        let repos = require_path(&json, "data/user/repositories")?;
+
+        add_repo_visibility(
+            repos["edges"].as_array().map_or(&[][..], |edges| edges),
+            &mut private,
+        );
+
+        if !repos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+            return Ok(private);
+        }
+        cursor = repos["pageInfo"]["endCursor"]
+            .as_str()
+            .map(|s| s.to_string());
     }
+}
 
-    // Separate comments and lines
-    let (cache_comment, mut lines) = data.split_at(comment_size);
-    let cache_comment_str = cache_comment.join("");
-    let mut lines: Vec<String> = lines.to_vec();
+/// Record whether each edge is private under its `nameWithOwner`, leaving
+/// out the edges the topic filters drop.
+fn add_repo_visibility(edges: &[Value], private: &mut HashMap<String, bool>) {
+    for edge in edges.iter().filter(|edge| is_topic_selected(edge)) {
+        if let Some(name_with_owner) = edge["node"]["nameWithOwner"].as_str() {
+            private.insert(
+                name_with_owner.to_string(),
+                edge["node"]["isPrivate"].as_bool().unwrap_or(false),
+            );
+        }
+    }
+}
 
-    let mut json_state = serde_json::json!({});
+/// Aggregate stats of an organization, from `org_stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OrgStats {
+    pub repos: usize,
+    // Stargazers of the counted repositories, like the user's stars
+    pub stars: usize,
+    pub members: usize,
+}
 
-    for (index, edge) in edges.iter().enumerate() {
-        if let Some(name_with_owner) = edge.pointer("/node/nameWithOwner").and_then(|v| v.as_str())
-        {
-            let repo_hash = Sha256::digest(name_with_owner.as_bytes());
-            let hash_str = hex::encode(repo_hash);
-
-            if let Some(line) = lines.get_mut(index) {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                let cached_hash = parts.get(0).copied().unwrap_or("");
-
-                if cached_hash == hash_str {
-                    let current_commit_count = edge
-                        .pointer("/node/defaultBranchRef/target/history/totalCount")
-                        .and_then(|v| v.as_i64())
-                        .unwrap_or(0);
-
-                    let cached_commit_count = parts
-                        .get(1)
-                        .and_then(|v| v.parse::<i64>().ok())
-                        .unwrap_or(0);
-
-                    // If commit count has changed, recalculate
-                    if current_commit_count != cached_commit_count {
-                        let mut split = name_with_owner.split('/');
-                        let owner = split.next().unwrap_or("");
-                        let repo_name = split.next().unwrap_or("");
-
-                        let (loc_add_new, loc_del_new, loc_total) = recursive_loc(
-                            owner,
-                            repo_name,
-                            &mut json_state,
-                            &cache_comment_str,
-                            0,
-                            0,
-                            0,
-                            None,
-                        )?;
-
-                        *line = format!(
-                            "{} {} {} {} {}\n",
-                            hash_str, current_commit_count, loc_total, loc_add_new, loc_del_new
-                        );
+const ORG_REPOS_QUERY: &str = r#"
+    query ($login: String!, $cursor: String, $page_size: Int!) {
+        organization(login: $login) {
+            membersWithRole {
+                totalCount
+            }
+            repositories(first: $page_size, after: $cursor) {
+                edges {
+                    node {
+                        ... on Repository {
+                            nameWithOwner
+                            isFork
+                            isArchived
+                            repositoryTopics(first: 20) {
+                                nodes {
+                                    topic {
+                                        name
+                                    }
+                                }
+                            }
+                            stargazers {
+                                totalCount
+                            }
+                        }
                     }
                 }
+                pageInfo {
+                    endCursor
+                    hasNextPage
+                }
             }
         }
     }
+"#;
 
-    // Re-write the cache file
-    let mut file = File::create(&filename)?;
-    for line in cache_comment {
-        writeln!(file, "{}", line)?;
-    }
-    for line in &lines {
-        write!(file, "{}", line)?; // no newline needed; already present
+/// Repositories, stars and members of the organization `org`. Forks and
+/// archived repositories are left out of the repositories and stars like they
+/// are for a user.
+pub async fn org_stats(
+    transport: &impl GraphQlTransport,
+    org: &str,
+    include_forks: bool,
+    include_archived: bool,
+    page_size: usize,
+) -> Result<OrgStats, StatsError> {
+    let mut stars = HashMap::new();
+    let mut cursor: Option<String> = None;
+
+    loop {
+        query_count("org_stats");
+
+        let variables = json!({
+            "login": org,
+            "cursor": cursor,
+            "page_size": page_size
+        });
+
+        let json = transport
+            .post("org_stats", ORG_REPOS_QUERY, &variables)
+            .await?;
+
+        debug!("Org stats: Here's the json response: {:#?}", &json);
+        let organization = require_path(&json, "data/organization")?;
+        let repos = require_path(&json, "data/organization/repositories")?;
+
+        add_repo_counts(
+            repos["edges"].as_array().map_or(&[][..], |edges| edges),
+            "stargazers",
+            include_forks,
+            include_archived,
+            &mut stars,
+        );
+
+        if !repos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+            return Ok(OrgStats {
+                repos: stars.len(),
+                stars: stars.values().sum(),
+                members: organization["membersWithRole"]["totalCount"]
+                    .as_u64()
+                    .unwrap_or(0) as usize,
+            });
+        }
+        cursor = repos["pageInfo"]["endCursor"]
+            .as_str()
+            .map(|s| s.to_string());
     }
+}
+
+/// Find the most recently pushed-to repository the user owns, returning its
+/// `nameWithOwner` and `pushedAt` timestamp. Repositories that were never
+/// pushed to are ignored, and on a tie the first one GitHub lists wins. Fails
+/// if the user doesn't exist.
+pub async fn last_active_repo(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+) -> Result<Option<(String, String)>, StatsError> {
+    query_count("last_active_repo");
+
+    let query = r#"
+        query ($login: String!) {
+            user(login: $login) {
+                repositories(first: 10, ownerAffiliations: [OWNER], orderBy: {field: PUSHED_AT, direction: DESC}) {
+                    nodes {
+                        nameWithOwner
+                        pushedAt
+                    }
+                }
+            }
+        }
+    "#;
+
+    let variables = json!({ "login": user_name });
+
+    let json = transport
+        .post("last_active_repo", query, &variables)
+        .await?;
+
+    let nodes = require_path(&json, "data/user/repositories/nodes")?;
+
+    let mut latest: Option<(String, String)> = None;
+    if let Some(nodes) = nodes.as_array() {
+        for node in nodes {
+            let (Some(name), Some(pushed_at)) =
+                (node["nameWithOwner"].as_str(), node["pushedAt"].as_str())
+            else {
+                continue;
+            };
 
-    // Sum the LOC values
-    for line in &lines {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() >= 5 {
-            loc_add += parts[3].parse::<i32>().unwrap_or(0);
-            loc_del += parts[4].parse::<i32>().unwrap_or(0);
+            // ISO 8601 timestamps in the same zone compare correctly as strings
+            if latest
+                .as_ref()
+                .is_none_or(|(_, latest_at)| pushed_at > latest_at.as_str())
+            {
+                latest = Some((name.to_string(), pushed_at.to_string()));
+            }
         }
     }
 
-    Ok((loc_add, loc_del, loc_add - loc_del, cached))
+    Ok(latest)
 }
 
-pub fn flush_cache(
-    edges: &Vec<Value>,
-    filename: &str,
-    comment_size: usize,
-) -> Result<(), Box<dyn Error>> {
-    let preserved_comments = {
-        println!("flush_cache: Does this file exists? {}", &filename);
-        let file = File::open(filename)?;
-        let mut reader = BufReader::new(file);
-        let mut lines = Vec::new();
+// Languages fetched per repository for `top_languages`, largest first
+const LANGUAGES_PER_REPO: usize = 10;
 
-        for _ in 0..comment_size {
-            let mut line = String::new();
-            let bytes = reader.read_line(&mut line)?;
-            if bytes == 0 {
-                break; //EOF
+/// Total bytes of code per language across the user's own non-fork
+/// repositories, largest first, without the `exclude`d languages and cut
+/// down to the `top_n` largest if given. GitHub already leaves files marked
+/// `linguist-generated` or `linguist-vendored` in `.gitattributes` out of the
+/// sizes; the API has no such flag of its own.
+pub async fn top_languages(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+    exclude: &[String],
+    top_n: Option<usize>,
+) -> Result<Vec<(String, u64)>, StatsError> {
+    let query = r#"
+        query ($login: String!, $cursor: String, $languages: Int!) {
+            user(login: $login) {
+                repositories(first: 100, after: $cursor, ownerAffiliations: [OWNER], isFork: false) {
+                    nodes {
+                        languages(first: $languages, orderBy: {field: SIZE, direction: DESC}) {
+                            edges {
+                                size
+                                node {
+                                    name
+                                }
+                            }
+                        }
+                    }
+                    pageInfo {
+                        endCursor
+                        hasNextPage
+                    }
+                }
             }
-            lines.push(line);
         }
-        lines
-    };
+    "#;
+
+    let mut totals = HashMap::new();
+    let mut cursor: Option<String> = None;
+
+    loop {
+        query_count("top_languages");
+
+        let variables = json!({
+            "login": user_name,
+            "cursor": cursor,
+            "languages": LANGUAGES_PER_REPO,
+        });
+
+        let json = transport.post("top_languages", query, &variables).await?;
+        let repos = require_path(&json, "data/user/repositories")?;
+
+        if let Some(nodes) = repos["nodes"].as_array() {
+            add_language_sizes(nodes, &mut totals);
+        }
 
-    let mut file = File::create(filename)?;
+        if !repos["pageInfo"]["hasNextPage"].as_bool().unwrap_or(false) {
+            break;
+        }
+        cursor = repos["pageInfo"]["endCursor"]
+            .as_str()
+            .map(|s| s.to_string());
+    }
 
-    for line in &preserved_comments {
-        file.write_all(line.as_bytes())?;
+    Ok(rank_languages(totals, exclude, top_n))
+}
+
+/// Sort language totals largest first, dropping the `exclude`d languages
+/// (matched ignoring case) and keeping the `top_n` largest if given.
+fn rank_languages(
+    totals: HashMap<String, u64>,
+    exclude: &[String],
+    top_n: Option<usize>,
+) -> Vec<(String, u64)> {
+    let mut languages: Vec<(String, u64)> = totals
+        .into_iter()
+        .filter(|(name, _)| {
+            !exclude
+                .iter()
+                .any(|excluded| excluded.trim().eq_ignore_ascii_case(name))
+        })
+        .collect();
+    languages.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    if let Some(top_n) = top_n {
+        languages.truncate(top_n);
     }
+    languages
+}
 
-    for node in edges.iter() {
-        if let Some(name_with_owner) = node.pointer("/node/nameWithOwner").and_then(|v| v.as_str())
-        {
-            let mut hasher = Sha256::new();
-            hasher.update(name_with_owner.as_bytes());
-            let hash = hex::encode(hasher.finalize());
+/// Add the language sizes of a page of repository nodes to `totals`.
+fn add_language_sizes(nodes: &[Value], totals: &mut HashMap<String, u64>) {
+    for node in nodes {
+        let Some(edges) = node["languages"]["edges"].as_array() else {
+            continue;
+        };
 
-            let entry = format!("{} 0 0 0 0\n", hash);
-            file.write_all(entry.as_bytes())?;
+        for edge in edges {
+            if let Some(name) = edge["node"]["name"].as_str() {
+                *totals.entry(name.to_string()).or_insert(0) += edge["size"].as_u64().unwrap_or(0);
+            }
         }
     }
+}
 
-    Ok(())
+/// Current and longest contribution streaks over the last year, from the
+/// user's contribution calendar.
+pub async fn commit_streak(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+) -> Result<StreakInfo, StatsError> {
+    query_count("commit_streak");
+
+    let query = r#"
+        query ($login: String!) {
+            user(login: $login) {
+                contributionsCollection {
+                    contributionCalendar {
+                        weeks {
+                            contributionDays {
+                                date
+                                contributionCount
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    "#;
+
+    let variables = json!({ "login": user_name });
+
+    let json = transport.post("commit_streak", query, &variables).await?;
+
+    Ok(compute_streaks(&calendar_days(require_path(
+        &json,
+        "data/user/contributionsCollection/contributionCalendar",
+    )?)))
 }
 
-pub fn force_close_file(
-    data: &mut Value,
-    cache_comment: &str,
-) -> std::result::Result<(), Box<dyn Error>> {
-    dotenv().ok();
-    println!("Force closing the file!!");
-    let mut hasher = Sha256::new();
-    hasher.update(USER_NAME.as_bytes());
-    let hash = format!("{:x}", hasher.finalize());
+/// Total contributions over the last year, or between `from` and `to` (ISO
+/// 8601 timestamps, at most a year apart) when given.
+pub async fn contributions_last_year(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+    from: Option<&str>,
+    to: Option<&str>,
+) -> Result<u64, StatsError> {
+    query_count("contributions_last_year");
 
-    let filename = format!("cache/{}.txt", hash);
-    let data_string = serde_json::to_string_pretty(data)?;
+    let query = r#"
+        query ($login: String!, $from: DateTime, $to: DateTime) {
+            user(login: $login) {
+                contributionsCollection(from: $from, to: $to) {
+                    contributionCalendar {
+                        totalContributions
+                    }
+                }
+            }
+        }
+    "#;
 
-    let mut file = File::create(&filename)?;
-    file.write_all(cache_comment.as_bytes())?;
-    file.write_all(data_string.as_bytes())?;
+    let variables = json!({ "login": user_name, "from": from, "to": to });
 
-    println!(
-        "There was an error while writing to the cache file. The file {} has had the partial data saved and closed.",
-        filename
-    );
+    let json = transport
+        .post("contributions_last_year", query, &variables)
+        .await?;
 
-    Ok(())
+    total_contributions(&json)
 }
 
-/// Load an SVG file, overwrite the text content of specific <tspan> elements,
-/// and write it back out.
-pub fn svg_overwrite(
-    filename: &str,
-    commit_data: &str,
-    star_data: &str,
-    repo_data: &str,
-    contrib_data: &str,
-    stats_data: &serde_json::Value,
-    loc_data: &[String],
-) -> Result<(), Box<dyn std::error::Error>> {
-    use std::fs;
-    use xmltree::{Element, XMLNode};
+/// Number of sponsors of the user, 0 if they don't have GitHub Sponsors set
+/// up.
+pub async fn sponsors_count(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+) -> Result<u64, StatsError> {
+    query_count("sponsors_count");
 
-    let svg_content = fs::read_to_string(filename)?;
-    let mut root = Element::parse(svg_content.as_bytes())?;
+    let query = r#"
+        query ($login: String!) {
+            user(login: $login) {
+                sponsors {
+                    totalCount
+                }
+            }
+        }
+    "#;
+
+    let variables = json!({ "login": user_name });
+
+    let json = transport.post("sponsors_count", query, &variables).await?;
 
-    let mut tspans: Vec<*mut Element> = vec![];
-    collect_tspans(&mut root, &mut tspans);
+    // `sponsors` is null when Sponsors isn't enabled for the account
+    Ok(json
+        .pointer("/data/user/sponsors/totalCount")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0))
+}
+
+/// The repositories pinned to the user's profile, up to six, with their
+/// stargazer counts in the order they are pinned.
+pub async fn pinned_repos(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+) -> Result<Vec<(String, u64)>, StatsError> {
+    query_count("pinned_repos");
 
-    // SAFETY: We ensure tspans are unique and safe to mutate after collection
-    unsafe {
-        if tspans.len() < 40 {
-            panic!("Not enough <tspan> elements: found {}", tspans.len());
+    let query = r#"
+        query ($login: String!) {
+            user(login: $login) {
+                pinnedItems(first: 6, types: REPOSITORY) {
+                    nodes {
+                        ... on Repository {
+                            nameWithOwner
+                            stargazerCount
+                        }
+                    }
+                }
+            }
         }
+    "#;
+
+    let variables = json!({ "login": user_name });
+
+    let json = transport.post("pinned_repos", query, &variables).await?;
 
-        (*tspans[34]).children = vec![XMLNode::Text(repo_data.to_string())];
-        (*tspans[36]).children = vec![XMLNode::Text(contrib_data.to_string())];
-        (*tspans[38]).children = vec![XMLNode::Text(star_data.to_string())];
-        (*tspans[40]).children = vec![XMLNode::Text(commit_data.to_string())];
-        (*tspans[42]).children = vec![XMLNode::Text(
-            stats_data["issues"]["totalCount"].to_string(),
-        )];
-        (*tspans[44]).children = vec![XMLNode::Text(
-            stats_data["pullRequests"]["totalCount"].to_string(),
-        )];
-        (*tspans[46]).children = vec![XMLNode::Text(loc_data[2].clone())];
-        (*tspans[47]).children = vec![XMLNode::Text(format!("{}++", loc_data[0]))];
-        (*tspans[48]).children = vec![XMLNode::Text(format!("{}--", loc_data[1]))];
+    pinned_from_json(&json)
+}
+
+/// Names and stargazer counts of the pins in a `pinned_repos` response.
+fn pinned_from_json(json: &Value) -> Result<Vec<(String, u64)>, StatsError> {
+    let nodes = json
+        .pointer("/data/user/pinnedItems/nodes")
+        .and_then(|nodes| nodes.as_array())
+        .ok_or_else(|| {
+            StatsError::Invalid("pinned_repos response has no pinned items".to_string())
+        })?;
+
+    nodes
+        .iter()
+        .map(|node| {
+            match (
+                node["nameWithOwner"].as_str(),
+                node["stargazerCount"].as_u64(),
+            ) {
+                (Some(name), Some(stars)) => Ok((name.to_string(), stars)),
+                _ => Err(StatsError::Invalid(format!(
+                    "pinned_repos response has a malformed repository: {}",
+                    node
+                ))),
+            }
+        })
+        .collect()
+}
+
+/// Read the total out of a `contributions_last_year` response.
+fn total_contributions(json: &Value) -> Result<u64, StatsError> {
+    json.pointer("/data/user/contributionsCollection/contributionCalendar/totalContributions")
+        .and_then(|v| v.as_u64())
+        .ok_or_else(|| {
+            StatsError::Invalid(
+                "contributions_last_year response has no totalContributions".to_string(),
+            )
+        })
+}
+
+/// Flatten a contribution calendar into `(date, contribution_count)` days.
+fn calendar_days(calendar: &Value) -> Vec<(String, u64)> {
+    calendar["weeks"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|week| week["contributionDays"].as_array())
+        .flatten()
+        .filter_map(|day| {
+            Some((
+                day["date"].as_str()?.to_string(),
+                day["contributionCount"].as_u64().unwrap_or(0),
+            ))
+        })
+        .collect()
+}
+
+/// Fetch the users' issue and pull request counts as a flat
+/// `{ "issues", "issues_closed", "prs_opened", "prs_merged", "prs_reviewed" }`
+/// object of numbers, summed over the users.
+pub async fn stats_getter(
+    transport: &impl GraphQlTransport,
+    user_names: &[&str],
+) -> Result<Value, StatsError> {
+    let mut totals = serde_json::Map::new();
+    for user_name in user_names {
+        let stats = user_stats(transport, user_name).await?;
+        for (key, count) in stats.as_object().into_iter().flatten() {
+            let total = totals.entry(key.clone()).or_insert(json!(0));
+            *total = json!(total.as_u64().unwrap_or(0) + count.as_u64().unwrap_or(0));
+        }
     }
 
-    let mut output = fs::File::create(filename)?;
-    root.write(&mut output)?;
+    Ok(Value::Object(totals))
+}
+
+/// The `stats_getter` counts of one user.
+async fn user_stats(
+    transport: &impl GraphQlTransport,
+    user_name: &str,
+) -> Result<Value, StatsError> {
+    query_count("stats_getter");
 
-    Ok(())
+    let query = r#"
+    query($login: String!) {
+        user(login: $login) {
+            pullRequests(first: 1) {
+                totalCount
+            }
+            mergedPullRequests: pullRequests(states: MERGED) {
+                totalCount
+            }
+            issues {
+                totalCount
+            }
+            closedIssues: issues(states: CLOSED) {
+                totalCount
+            }
+            contributionsCollection {
+                totalPullRequestReviewContributions
+            }
+        }
+    }"#;
+
+    let variables = json!({ "login": user_name });
+
+    let json = transport.post("stats_getter", query, &variables).await?;
+
+    debug!("Stats: Here's the json response: {:#?}", &json);
+
+    Ok(flatten_stats(require_path(&json, "data/user")?))
 }
 
-/// Print the index and text content of every <tspan> in the SVG.
-pub fn svg_element_getter(filename: &str) -> Result<(), Box<dyn Error>> {
-    let mut svg_string = String::new();
-    println!("Does this file exists? {}", &filename);
+/// Pull the `totalCount`s out of the `stats_getter` user object.
+fn flatten_stats(user_data: &Value) -> Value {
+    json!({
+        "issues": user_data["issues"]["totalCount"].as_u64().unwrap_or(0),
+        "prs_opened": user_data["pullRequests"]["totalCount"].as_u64().unwrap_or(0),
+        "prs_merged": user_data["mergedPullRequests"]["totalCount"].as_u64().unwrap_or(0),
+        "prs_reviewed": user_data["contributionsCollection"]["totalPullRequestReviewContributions"]
+            .as_u64()
+            .unwrap_or(0),
+        "issues_closed": user_data["closedIssues"]["totalCount"].as_u64().unwrap_or(0),
+    })
+}
 
-    File::open(filename)?.read_to_string(&mut svg_string)?;
-    let root = Element::parse(svg_string.as_bytes())?;
+// Archive of repositories the user no longer has access to, in the cache
+// directory
+pub const ARCHIVE_FILE: &str = "repository_archive.txt";
 
-    // Collect and print
-    let mut index = 0;
-    collect_and_print_tspans(&root, &mut index);
+// Comment lines before and summary lines after the repositories in the archive
+const ARCHIVE_HEADER_LINES: usize = 7;
+const ARCHIVE_FOOTER_LINES: usize = 3;
 
-    Ok(())
+/// Sum the archived repository data in `repository_archive.txt` in the cache
+/// directory.
+///
+/// The archive keeps the old text cache layout: a 7 line comment header, then
+/// one line per repository of
+/// `{hash} {total_commits} {my_commits} {loc_add} {loc_del}`, followed by a
+/// 3 line footer whose last line carries extra commits in its 5th field.
+/// Any numeric field may end with a `,`. Data lines that don't match this
+/// layout are reported and skipped.
+///
+/// Returns `[added_loc, deleted_loc, net_loc, added_commits, contributed_repos]`,
+/// which `main` adds to the totals when the archive exists.
+pub fn add_archive() -> Result<[i32; 5], StatsError> {
+    let file = File::open(cache_path(ARCHIVE_FILE))?;
+    let reader = BufReader::new(file);
+
+    let lines: Vec<String> = reader.lines().map_while(Result::ok).collect();
+
+    Ok(archive_totals(&lines))
 }
 
-/// Helper to recursively find and print each <tspan> element's text.
-fn collect_and_print_tspans(elem: &Element, index: &mut usize) {
-    if elem.name == "tspan" {
-        // Gather its text children
-        let text = elem
-            .children
-            .iter()
-            .filter_map(|node| match node {
-                XMLNode::Text(t) => Some(t.clone()),
-                _ => None,
-            })
-            .collect::<Vec<_>>()
-            .join("");
+/// The `add_archive` totals of the lines of an archive.
+fn archive_totals(lines: &[String]) -> [i32; 5] {
+    // Too short to hold both the header and the footer
+    let Some(data) =
+        lines.get(ARCHIVE_HEADER_LINES..lines.len().saturating_sub(ARCHIVE_FOOTER_LINES))
+    else {
+        return [0, 0, 0, 0, 0];
+    };
+    let mut added_loc = 0;
+    let mut deleted_loc = 0;
+    let mut added_commits = 0;
+    let contributed_repos = data.len();
 
-        println!("{}: {}", *index, text);
-        *index += 1;
+    for (index, line) in data.iter().enumerate() {
+        let Some(repo) = CachedRepo::from_legacy_line(line) else {
+            warn!(
+                "add_archive: skipping malformed line {}: {}",
+                index + ARCHIVE_HEADER_LINES + 1,
+                line
+            );
+            continue;
+        };
+
+        added_loc += repo.loc_add as i32;
+        deleted_loc += repo.loc_del as i32;
+        added_commits += repo.my_commits as i32;
     }
-    for child in &elem.children {
-        if let XMLNode::Element(child_elem) = child {
-            collect_and_print_tspans(child_elem, index);
-        }
+
+    // The last footer line carries the commits of repositories that were
+    // deleted before they made it into the archive. It is part of the footer,
+    // not of `data`, so these commits aren't counted twice.
+    if let Some(last_line) = lines.last() {
+        let last_parts: Vec<&str> = last_line.split_whitespace().collect();
+        added_commits += last_parts
+            .get(4)
+            .and_then(|field| parse_field(field))
+            .unwrap_or(0) as i32;
     }
+
+    [
+        added_loc,
+        deleted_loc,
+        added_loc - deleted_loc,
+        added_commits,
+        contributed_repos as i32,
+    ]
 }
 
-fn collect_tspans(element: &mut Element, tspans: &mut Vec<*mut Element>) {
-    for child in &mut element.children {
-        if let XMLNode::Element(e) = child {
-            if e.name == "tspan" {
-                tspans.push(e as *mut Element);
+/// The LOC totals of the cache of `user_name` as they are, added to
+/// `loc_add` and `loc_del`, for `--cache-only` and `--offline`. Fails if
+/// there is no cache.
+fn cached_loc(
+    edges: &[Value],
+    comment_size: usize,
+    mut loc_add: i32,
+    mut loc_del: i32,
+    user_name: &str,
+) -> Result<(i32, i32, i32, bool), StatsError> {
+    let Some(cache) = LocCache::load(user_name, comment_size)? else {
+        return Err(StatsError::Cache(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "--cache-only and --offline need a LOC cache at {}",
+                cache_file(user_name).display()
+            ),
+        )));
+    };
+    if CONFIG.per_repo {
+        print_per_repo(edges, &cache);
+    }
+
+    LOC_CACHE_HITS.fetch_add(cache.repos.len(), Ordering::Relaxed);
+    let (cached_add, cached_del) = cache.loc_totals();
+    loc_add += cached_add as i32;
+    loc_del += cached_del as i32;
+    Ok((loc_add, loc_del, loc_add - loc_del, true))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn cache_builder(
+    transport: &impl GraphQlTransport,
+    progress: &dyn LocProgress,
+    edges: &[Value],
+    comment_size: usize,
+    force_cache: bool,
+    force_repos: &[String],
+    mut loc_add: i32,
+    mut loc_del: i32,
+    user_name: &str,
+) -> Result<(i32, i32, i32, bool), StatsError> {
+    let mut cached = true;
+
+    // Excluded repositories never get a cache entry, so entries stay
+    // aligned with the edges that are counted
+    let edges: Vec<Value> = edges
+        .iter()
+        .filter(|edge| !is_excluded(edge))
+        .cloned()
+        .collect();
+    let edges = edges.as_slice();
+
+    // `--cache-only` reports the cache as it is, without recounting anything
+    if CONFIG.loc_cache_only() {
+        return cached_loc(edges, comment_size, loc_add, loc_del, user_name);
+    }
+
+    // Dry runs don't write the cache, so they don't need to keep others out
+    let _lock = if *DRY_RUN {
+        None
+    } else {
+        fs::create_dir_all(&*CACHE_DIR)?; // Ensure directory exists
+        Some(lock_cache(user_name)?)
+    };
+    let mut cache =
+        LocCache::load(user_name, comment_size)?.unwrap_or_else(|| LocCache::new(comment_size));
+
+    if CONFIG.vacuum {
+        let removed = cache.vacuum(edges);
+        info!(
+            "Vacuumed {} stale entr{} from the LOC cache of {}",
+            removed,
+            if removed == 1 { "y" } else { "ies" },
+            user_name
+        );
+    }
+
+    // With `--cache-ttl-hours` set, a cache generated longer ago than that is
+    // rebuilt as if `force_cache` were given
+    let expired = CONFIG.cache_ttl_hours.is_some_and(|ttl| cache.expired(ttl));
+
+    // Each cache entry must belong to the edge at the same position. That
+    // stops holding when the set of repositories changes, e.g. when forks
+    // are included or excluded, so rebuild the cache then. Likewise when it
+    // is expired or force is set.
+    if !cache.matches(edges) || expired || force_cache {
+        info!("LOC cache miss for {}, rebuilding it", user_name);
+        cached = false;
+        cache.flush(edges);
+    }
+
+    if let Some(comment) = &CONFIG.cache_comment {
+        cache.set_comment(comment, user_name);
+    }
+
+    let cache_comment_str = cache.comment.join("");
+
+    // Repositories whose commit count changed since they were cached, and
+    // those in `force_repos`, which are recounted either way. Empty ones have
+    // nothing to count and just get a zero entry.
+    let mut stale = Vec::new();
+    for (index, edge) in edges.iter().enumerate() {
+        if let Some(name_with_owner) = edge.pointer("/node/nameWithOwner").and_then(|v| v.as_str())
+            && let Some(repo) = cache.repos.get_mut(index)
+            && repo.hash == name_hash(name_with_owner)
+        {
+            if is_empty_repo(edge) {
+                debug!(
+                    "{} has no default branch, counting it as empty",
+                    name_with_owner
+                );
+                *repo = CachedRepo {
+                    hash: repo.hash.clone(),
+                    ..CachedRepo::default()
+                };
+                continue;
+            }
+
+            let current_commit_count = edge
+                .pointer("/node/defaultBranchRef/target/history/totalCount")
+                .and_then(|v| v.as_i64())
+                .unwrap_or(0);
+
+            let forced = force_repos
+                .iter()
+                .any(|forced| forced.eq_ignore_ascii_case(name_with_owner));
+            if current_commit_count != repo.commits || forced {
+                info!("LOC cache miss for {}, recounting it", name_with_owner);
+                stale.push((index, name_with_owner, current_commit_count));
             }
-            collect_tspans(e, tspans);
         }
     }
+    LOC_CACHE_HITS.fetch_add(edges.len() - stale.len(), Ordering::Relaxed);
+    LOC_CACHE_MISSES.fetch_add(stale.len(), Ordering::Relaxed);
+
+    // Walk up to `--loc-concurrency` histories at once. `buffered` yields the
+    // results in input order, so the cache comes out the same either way.
+    let total = stale.len();
+    let recounted: Vec<(usize, CachedRepo)> = stream::iter(stale.into_iter().enumerate())
+        .map(
+            |(position, (index, name_with_owner, current_commit_count))| {
+                let cache_comment_str = &cache_comment_str;
+                async move {
+                    progress.repo_started(name_with_owner, position + 1, total);
+
+                    let mut split = name_with_owner.split('/');
+                    let owner = split.next().unwrap_or("");
+                    let repo_name = split.next().unwrap_or("");
+
+                    // A recount that failed part way through before goes on
+                    // from the last page it counted
+                    let key = cache_key(user_name);
+                    let resume = load_resume(name_with_owner, &key, current_commit_count);
+                    let mut json_state = match &resume {
+                        Some(point) => {
+                            info!("Resuming the LOC count of {}", name_with_owner);
+                            json!({
+                                "cursor": point.cursor,
+                                "loc_add": point.loc_add,
+                                "loc_del": point.loc_del,
+                                "my_commits": point.my_commits,
+                            })
+                        }
+                        None => json!({}),
+                    };
+
+                    let counted = recursive_loc(
+                        transport,
+                        progress,
+                        owner,
+                        repo_name,
+                        &mut json_state,
+                        cache_comment_str,
+                        resume.as_ref().map_or(0, |point| point.loc_add),
+                        resume.as_ref().map_or(0, |point| point.loc_del),
+                        resume.as_ref().map_or(0, |point| point.my_commits),
+                        resume.as_ref().map(|point| point.cursor.clone()),
+                        HISTORY_PAGE_SIZE,
+                        CONFIG.loc_since_timestamp().as_deref(),
+                    )
+                    .await;
+
+                    let (loc_add_new, loc_del_new, my_commits) = match counted {
+                        Ok(counted) => {
+                            save_resume(name_with_owner, None)?;
+                            counted
+                        }
+                        Err(err) => {
+                            save_resume(
+                                name_with_owner,
+                                resume_point(&json_state, key, current_commit_count),
+                            )?;
+                            return Err(err);
+                        }
+                    };
+
+                    Ok::<_, StatsError>((
+                        index,
+                        CachedRepo {
+                            hash: name_hash(name_with_owner),
+                            commits: current_commit_count,
+                            my_commits: my_commits as i64,
+                            loc_add: loc_add_new as i64,
+                            loc_del: loc_del_new as i64,
+                        },
+                    ))
+                }
+            },
+        )
+        .buffered(CONFIG.loc_concurrency)
+        .try_collect()
+        .await?;
+
+    for (index, repo) in recounted {
+        cache.repos[index] = repo;
+    }
+
+    cache.save(user_name)?;
+
+    if CONFIG.per_repo {
+        print_per_repo(edges, &cache);
+    }
+
+    let (cached_add, cached_del) = cache.loc_totals();
+    loc_add += cached_add as i32;
+    loc_del += cached_del as i32;
+
+    Ok((loc_add, loc_del, loc_add - loc_del, cached))
+}
+
+/// The cache entries with the `nameWithOwner` of their edge, by net LOC
+/// descending. Entries without a matching edge, e.g. with `--cache-only`,
+/// keep their hash as the name.
+fn per_repo_rows<'a>(edges: &'a [Value], cache: &'a LocCache) -> Vec<(&'a str, &'a CachedRepo)> {
+    let names: HashMap<String, &str> = edges
+        .iter()
+        .filter_map(|edge| edge.pointer("/node/nameWithOwner")?.as_str())
+        .map(|name| (name_hash(name), name))
+        .collect();
+
+    let mut rows: Vec<(&str, &CachedRepo)> = cache
+        .repos
+        .iter()
+        .map(|repo| {
+            let name = names.get(&repo.hash).copied().unwrap_or(repo.hash.as_str());
+            (name, repo)
+        })
+        .collect();
+    rows.sort_by_key(|(_, repo)| std::cmp::Reverse(repo.loc_add - repo.loc_del));
+
+    rows
+}
+
+/// Print the `--per-repo` table of commits and LOC.
+fn print_per_repo(edges: &[Value], cache: &LocCache) {
+    println!(
+        "{:<40} {:>9} {:>12} {:>12}",
+        "Repository", "Commits", "Added", "Deleted"
+    );
+    for (name, repo) in per_repo_rows(edges, cache) {
+        println!(
+            "{:<40} {:>9} {:>12} {:>12}",
+            name,
+            format_with_commas(repo.my_commits),
+            format_with_commas(repo.loc_add),
+            format_with_commas(repo.loc_del)
+        );
+    }
+}
+
+/// Dump the state of a failed LOC count to the `.partial` file next to the
+/// cache, which is left as it is. Its cursor and totals are what the caller
+/// saves as the repository's resume point.
+pub fn force_close_file(data: &mut Value, cache_comment: &str) -> Result<(), StatsError> {
+    dotenv().ok();
+    if *DRY_RUN {
+        return Ok(());
+    }
+
+    let filename = partial_file(&CONFIG.users_key()?);
+    let partial = json!({
+        "comment": cache_comment.lines().collect::<Vec<_>>(),
+        "state": data,
+    });
+
+    fs::create_dir_all(&*CACHE_DIR)?;
+    write_atomic(&filename, &serde_json::to_string_pretty(&partial)?)?;
+
+    warn!(
+        "Counting LOC failed part way through. The partial data was saved to {}.",
+        filename.display()
+    );
+
+    Ok(())
+}
+
+// Ids a template can put on its <tspan>s to receive each stat, and the
+// position of that tspan in the original index-based templates.
+pub const TSPAN_SLOTS: [(&str, usize); 9] = [
+    ("repos", 34),
+    ("contrib", 36),
+    ("stars", 38),
+    ("commits", 40),
+    ("issues", 42),
+    ("prs", 44),
+    // Net LOC (added minus deleted), then the added and deleted lines. The
+    // total (added plus deleted) only renders into a `loc_total` tspan.
+    ("loc", 46),
+    ("loc_add", 47),
+    ("loc_del", 48),
+];
+
+// Fewest tspans an index-based template must have
+const INDEX_LAYOUT_TSPANS: usize = 40;
+
+// Ids of the tspans the top languages render into, most used first. Like
+// `last_active`, these are only filled by id.
+pub const LANGUAGE_SLOTS: [&str; 5] = ["lang_1", "lang_2", "lang_3", "lang_4", "lang_5"];
+
+// Ids of the tspans the pinned repositories render into, in pin order. Slots
+// past the last pin are cleared.
+pub const PINNED_SLOTS: [&str; 6] = [
+    "pinned_1", "pinned_2", "pinned_3", "pinned_4", "pinned_5", "pinned_6",
+];
+
+// Ids of the other stats a template can show. Like the language and pinned
+// slots, these are only filled by id.
+const ID_ONLY_STATS: [&str; 21] = [
+    "loc_total",
+    "loc_delta",
+    "loc_ema",
+    "last_active",
+    "streak_current",
+    "streak_current_range",
+    "streak_longest",
+    "streak_longest_range",
+    "contributions",
+    "watchers",
+    "sponsors",
+    "repos_public",
+    "repos_private",
+    "pinned_stars",
+    "prs_merged",
+    "prs_reviewed",
+    "issues_closed",
+    "followers",
+    "following",
+    "account_age",
+    "account_age_days",
+];
+
+// Stats shown with their change since the last run, by `StatsSnapshot` field,
+// and the tspan the "+N" or "-N" renders into: the stat's id with a `_diff`
+// suffix (`loc_delta` is the LOC trend). These are only filled by id.
+pub const DIFF_SLOTS: [(&str, &str); 18] = [
+    ("repos", "repos_diff"),
+    ("contributed_repos", "contrib_diff"),
+    ("stars", "stars_diff"),
+    ("commits", "commits_diff"),
+    ("issues", "issues_diff"),
+    ("prs", "prs_diff"),
+    ("loc_net", "loc_diff"),
+    ("loc_added", "loc_add_diff"),
+    ("loc_deleted", "loc_del_diff"),
+    ("prs_merged", "prs_merged_diff"),
+    ("prs_reviewed", "prs_reviewed_diff"),
+    ("issues_closed", "issues_closed_diff"),
+    ("contributions", "contributions_diff"),
+    ("watchers", "watchers_diff"),
+    ("sponsors", "sponsors_diff"),
+    ("repos_public", "repos_public_diff"),
+    ("repos_private", "repos_private_diff"),
+    ("pinned_stars", "pinned_stars_diff"),
+];
+
+// Class of the muted text in the templates, which the `_diff` tspans take on
+// if the template's stylesheet has it
+const MUTED_CLASS: &str = "commentColor";
+
+/// Load the SVG template, overwrite the text content of specific <tspan>
+/// elements, and write the result to `output`. Returns whether the output
+/// contents changed.
+///
+/// Stats passed as `None` keep whatever value the SVG already shows.
+/// `diff_data` holds the "+N" annotations of `diff_values`.
+#[allow(clippy::too_many_arguments)]
+pub fn svg_overwrite(
+    template: &Path,
+    output: &Path,
+    commit_data: Option<&str>,
+    star_data: Option<&str>,
+    repo_data: Option<&str>,
+    contrib_data: Option<&str>,
+    stats_data: Option<&serde_json::Value>,
+    loc_data: Option<&LocStats>,
+    last_active_data: Option<&str>,
+    language_data: Option<&[(String, u64)]>,
+    streak_data: Option<&StreakInfo>,
+    contributions_data: Option<u64>,
+    watcher_data: Option<&str>,
+    sponsors_data: Option<u64>,
+    visibility_data: Option<(usize, usize)>,
+    pinned_data: Option<&[(String, u64)]>,
+    user_data: Option<&UserInfo>,
+    diff_data: &HashMap<&'static str, String>,
+) -> Result<bool, StatsError> {
+    let mut values = svg_values(
+        commit_data,
+        star_data,
+        repo_data,
+        contrib_data,
+        stats_data,
+        loc_data,
+        last_active_data,
+        language_data,
+        streak_data,
+        contributions_data,
+        watcher_data,
+        sponsors_data,
+        visibility_data,
+        pinned_data,
+        user_data,
+    );
+    values.extend(diff_data.iter().map(|(id, text)| (*id, text.clone())));
+
+    svg_overwrite_by_id(template, output, &values)
+}
+
+/// The change of each stat since `previous` as a "+N" or "-N" annotation,
+/// keyed by its `DIFF_SLOTS` tspan. Stats missing from either snapshot, or
+/// that didn't change, get none.
+pub fn diff_values(
+    previous: &StatsSnapshot,
+    current: &StatsSnapshot,
+) -> Result<HashMap<&'static str, String>, StatsError> {
+    let previous = serde_json::to_value(previous)?;
+    let current = serde_json::to_value(current)?;
+
+    Ok(DIFF_SLOTS
+        .iter()
+        .filter_map(|(field, id)| {
+            let change = current[field].as_i64()? - previous[field].as_i64()?;
+            let sign = if change > 0 { "+" } else { "" };
+            (change != 0).then(|| (*id, format!("{}{}", sign, format_with_commas(change))))
+        })
+        .collect())
+}
+
+/// Load an SVG template, replace the text of each <tspan> whose `id` is a key
+/// of `values`, and write the result to `output`. Templates without any of
+/// the `TSPAN_SLOTS` ids are filled by position instead. Returns whether the
+/// output contents changed, which on a dry run is only reported and not
+/// written.
+pub fn svg_overwrite_by_id(
+    template: &Path,
+    output: &Path,
+    values: &HashMap<&str, String>,
+) -> Result<bool, StatsError> {
+    let svg_content = fs::read_to_string(template)?;
+    let previous_output = fs::read_to_string(output).ok();
+    let previous = previous_output.as_deref().filter(|_| template != output);
+    let rendered = render_svg(&svg_content, previous, values)?;
+    let changed = previous_output.as_ref() != Some(&rendered);
+    if *DRY_RUN {
+        info!("Dry run: not writing {}", output.display());
+    } else {
+        fs::write(output, rendered)?;
+    }
+
+    Ok(changed)
+}
+
+/// Fill an SVG template held in memory, without touching the filesystem.
+/// Stats missing from `values` keep what `previous`, an earlier render of the
+/// same template, showed rather than falling back to the placeholders.
+pub fn render_svg(
+    template: &str,
+    previous: Option<&str>,
+    values: &HashMap<&str, String>,
+) -> Result<String, StatsError> {
+    let mut root = parse_svg("SVG template", template)?;
+
+    let previous = match previous {
+        Some(previous) => rendered_values(&parse_svg("previous SVG output", previous)?),
+        None => HashMap::new(),
+    };
+    let mut merged: HashMap<&str, String> = previous
+        .iter()
+        .map(|(id, text)| (id.as_str(), text.clone()))
+        .collect();
+    merged.extend(values.iter().map(|(id, text)| (*id, text.clone())));
+
+    fill_svg(&mut root, &merged)?;
+    mute_diffs(&mut root);
+
+    write_svg(&root)
+}
+
+/// Map each available stat to the tspan id it renders into.
+#[allow(clippy::too_many_arguments)]
+fn svg_values<'a>(
+    commit_data: Option<&str>,
+    star_data: Option<&str>,
+    repo_data: Option<&str>,
+    contrib_data: Option<&str>,
+    stats_data: Option<&serde_json::Value>,
+    loc_data: Option<&LocStats>,
+    last_active_data: Option<&str>,
+    language_data: Option<&[(String, u64)]>,
+    streak_data: Option<&StreakInfo>,
+    contributions_data: Option<u64>,
+    watcher_data: Option<&str>,
+    sponsors_data: Option<u64>,
+    visibility_data: Option<(usize, usize)>,
+    pinned_data: Option<&[(String, u64)]>,
+    user_data: Option<&UserInfo>,
+) -> HashMap<&'a str, String> {
+    let mut values = HashMap::new();
+
+    if let Some(repo_data) = repo_data {
+        values.insert("repos", repo_data.to_string());
+    }
+    if let Some(contrib_data) = contrib_data {
+        values.insert("contrib", contrib_data.to_string());
+    }
+    if let Some(star_data) = star_data {
+        values.insert("stars", star_data.to_string());
+    }
+    if let Some(commit_data) = commit_data {
+        values.insert("commits", commit_data.to_string());
+    }
+    if let Some(stats_data) = stats_data {
+        let count = |key: &str| format_with_commas(stats_data[key].as_i64().unwrap_or(0));
+        values.insert("issues", count("issues"));
+        values.insert("prs", count("prs_opened"));
+        values.insert("prs_merged", count("prs_merged"));
+        values.insert("prs_reviewed", count("prs_reviewed"));
+        values.insert("issues_closed", count("issues_closed"));
+    }
+    if let Some(loc_data) = loc_data {
+        let loc = |n: i32| format_with_commas(n.into());
+        values.insert("loc", loc(loc_data.net));
+        values.insert("loc_add", format!("{}++", loc(loc_data.added)));
+        values.insert("loc_del", format!("{}--", loc(loc_data.deleted)));
+        values.insert("loc_total", loc(loc_data.total));
+        if let Some(trend) = loc_data.trend {
+            let sign = if trend.delta > 0 { "+" } else { "" };
+            values.insert(
+                "loc_delta",
+                format!("{}{}", sign, format_with_commas(trend.delta)),
+            );
+            values.insert("loc_ema", format_with_commas(trend.ema.round() as i64));
+        }
+    }
+    if let Some(last_active_data) = last_active_data {
+        values.insert("last_active", last_active_data.to_string());
+    }
+    if let Some(language_data) = language_data {
+        // Percentages are of the languages kept after `--exclude-languages`
+        // and `--top-languages`, not of all the user's code
+        let total: u64 = language_data.iter().map(|(_, size)| size).sum();
+        for (id, (name, size)) in LANGUAGE_SLOTS.iter().zip(language_data) {
+            let percent = *size as f64 * 100.0 / total.max(1) as f64;
+            values.insert(*id, format!("{} {:.1}%", name, percent));
+        }
+    }
+    if let Some(streak_data) = streak_data {
+        let days = |count: usize| format!("{} day{}", count, if count == 1 { "" } else { "s" });
+        let range = |range: &Option<(String, String)>| match range {
+            Some((start, end)) => format!("{} - {}", start, end),
+            None => "-".to_string(),
+        };
+
+        values.insert("streak_current", days(streak_data.current));
+        values.insert("streak_current_range", range(&streak_data.current_range));
+        values.insert("streak_longest", days(streak_data.longest));
+        values.insert("streak_longest_range", range(&streak_data.longest_range));
+    }
+    if let Some(contributions_data) = contributions_data {
+        values.insert("contributions", contributions_data.to_string());
+    }
+    if let Some(watcher_data) = watcher_data {
+        values.insert("watchers", watcher_data.to_string());
+    }
+    if let Some(sponsors_data) = sponsors_data {
+        values.insert("sponsors", format_with_commas(sponsors_data as i64));
+    }
+    if let Some((public, private)) = visibility_data {
+        values.insert("repos_public", format_with_commas(public as i64));
+        values.insert("repos_private", format_with_commas(private as i64));
+    }
+    if let Some(pinned_data) = pinned_data {
+        let stars: u64 = pinned_data.iter().map(|(_, stars)| stars).sum();
+        values.insert("pinned_stars", format_with_commas(stars as i64));
+        for (index, id) in PINNED_SLOTS.iter().enumerate() {
+            let text = match pinned_data.get(index) {
+                Some((name, stars)) => format!("{} ({})", name, format_with_commas(*stars as i64)),
+                None => String::new(),
+            };
+            values.insert(*id, text);
+        }
+    }
+    if let Some(user_data) = user_data {
+        if let Some(followers) = user_data.followers {
+            values.insert("followers", followers.to_string());
+        }
+        if let Some(following) = user_data.following {
+            values.insert("following", following.to_string());
+        }
+
+        let now = Utc::now();
+        if let Some(years) = account_age_years(&user_data.created_at, now) {
+            values.insert("account_age", format!("{:.1} years", years));
+        }
+        if let Some(days) = account_age_days(&user_data.created_at, now) {
+            values.insert("account_age_days", format!("{} days", days));
+        }
+    }
+
+    values
+}
+
+/// Overwrite the stat <tspan> elements of a parsed SVG. Index-based templates
+/// need at least 40 tspans; slots past the last one are skipped with a warning.
+fn fill_svg(root: &mut Element, values: &HashMap<&str, String>) -> Result<(), StatsError> {
+    let uses_ids = TSPAN_SLOTS.iter().any(|(id, _)| has_tspan_id(root, id));
+
+    let mut tspans = vec![];
+    if !uses_ids {
+        collect_tspans(root, &mut vec![], &mut tspans);
+
+        check_index_layout(tspans.len())?;
+    }
+
+    for (id, text) in values {
+        let slot = TSPAN_SLOTS.iter().find(|(slot_id, _)| slot_id == id);
+
+        match slot {
+            Some((_, index)) if !uses_ids => {
+                let Some(path) = tspans.get(*index) else {
+                    warn!(
+                        "Skipping \"{}\": the template has no <tspan> at index {} (found {})",
+                        id,
+                        index,
+                        tspans.len()
+                    );
+                    continue;
+                };
+                if let Some(tspan) = element_at_path(root, path) {
+                    tspan.children = vec![XMLNode::Text(text.clone())];
+                }
+            }
+            // Stats without a fixed position only render into templates that
+            // tag a tspan for them
+            _ => {
+                set_tspan_by_id(root, id, text);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Give the `DIFF_SLOTS` tspans that have no class of their own the muted
+/// style, if the template's stylesheet has it.
+fn mute_diffs(root: &mut Element) {
+    if !has_style_class(root, MUTED_CLASS) {
+        return;
+    }
+
+    for (_, id) in DIFF_SLOTS {
+        if let Some(tspan) = find_tspan_by_id(root, id) {
+            tspan
+                .attributes
+                .entry("class".to_string())
+                .or_insert_with(|| MUTED_CLASS.to_string());
+        }
+    }
+}
+
+/// Whether a <style> of the SVG has a rule for `class`.
+fn has_style_class(element: &Element, class: &str) -> bool {
+    element.children.iter().any(|child| match child {
+        XMLNode::Element(e) => {
+            (e.name == "style" && tspan_text(e).contains(&format!(".{}", class)))
+                || has_style_class(e, class)
+        }
+        _ => false,
+    })
+}
+
+/// Fail if an index-based template has too few tspans for `TSPAN_SLOTS`.
+fn check_index_layout(tspans: usize) -> Result<(), StatsError> {
+    if tspans < INDEX_LAYOUT_TSPANS {
+        return Err(StatsError::Svg(format!(
+            "Not enough <tspan> elements: found {}, the index-based layout needs at least {}. \
+             Tag the stat tspans with ids instead (see TSPAN_SLOTS).",
+            tspans, INDEX_LAYOUT_TSPANS
+        )));
+    }
+    Ok(())
+}
+
+/// Check that an SVG template parses and has somewhere to render the stats:
+/// every `TSPAN_SLOTS` id, or enough tspans for the index-based layout.
+pub fn check_template(path: &Path) -> Result<String, StatsError> {
+    let content = fs::read_to_string(path)?;
+    let root = parse_svg(&path.display().to_string(), &content)?;
+
+    let missing: Vec<&str> = TSPAN_SLOTS
+        .iter()
+        .map(|(id, _)| *id)
+        .filter(|id| !has_tspan_id(&root, id))
+        .collect();
+    if missing.len() < TSPAN_SLOTS.len() {
+        if !missing.is_empty() {
+            return Err(StatsError::Svg(format!(
+                "missing tspan ids: {}",
+                missing.join(", ")
+            )));
+        }
+        return Ok(format!("all {} stat ids present", TSPAN_SLOTS.len()));
+    }
+
+    let mut tspans = vec![];
+    collect_tspans(&root, &mut vec![], &mut tspans);
+    check_index_layout(tspans.len())?;
+
+    Ok(format!("index-based layout with {} tspans", tspans.len()))
+}
+
+/// The text of every stat <tspan> of a rendered SVG, keyed like the values
+/// passed to `fill_svg`: by `id`, or by `TSPAN_SLOTS` for index-based layouts.
+fn rendered_values(root: &Element) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+
+    if TSPAN_SLOTS.iter().any(|(id, _)| has_tspan_id(root, id)) {
+        // Labels and other static text come from the template, so that edits
+        // to it reach the output
+        collect_tspan_ids(root, &mut values);
+        values.retain(|id, _| is_stat_id(id));
+    } else {
+        let mut tspans = vec![];
+        collect_tspans(root, &mut vec![], &mut tspans);
+
+        let mut root = root.clone();
+        for (id, index) in TSPAN_SLOTS {
+            if let Some(tspan) = tspans
+                .get(index)
+                .and_then(|path| element_at_path(&mut root, path))
+            {
+                values.insert(id.to_string(), tspan_text(tspan));
+            }
+        }
+    }
+
+    values
+}
+
+/// Whether a tspan `id` receives a stat, rather than being static text.
+fn is_stat_id(id: &str) -> bool {
+    TSPAN_SLOTS.iter().any(|(slot, _)| *slot == id)
+        || LANGUAGE_SLOTS.contains(&id)
+        || PINNED_SLOTS.contains(&id)
+        || ID_ONLY_STATS.contains(&id)
+}
+
+/// Collect the text of every <tspan> that has an `id`.
+fn collect_tspan_ids(element: &Element, values: &mut HashMap<String, String>) {
+    for child in &element.children {
+        if let XMLNode::Element(e) = child {
+            if e.name == "tspan"
+                && let Some(id) = e.attributes.get("id")
+            {
+                values.insert(id.clone(), tspan_text(e));
+            }
+            collect_tspan_ids(e, values);
+        }
+    }
+}
+
+/// Ids of the tspans in the SVG template at `path`.
+pub fn template_tspan_ids(path: &Path) -> Result<HashSet<String>, StatsError> {
+    let svg_content = fs::read_to_string(path)?;
+    let root = parse_svg(&path.display().to_string(), &svg_content)?;
+
+    let mut values = HashMap::new();
+    collect_tspan_ids(&root, &mut values);
+    Ok(values.into_keys().collect())
+}
+
+/// The text directly inside an element.
+fn tspan_text(element: &Element) -> String {
+    element
+        .children
+        .iter()
+        .filter_map(|node| match node {
+            XMLNode::Text(t) => Some(t.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether any <tspan> in the tree has the given `id`.
+fn has_tspan_id(element: &Element, id: &str) -> bool {
+    element.children.iter().any(|child| match child {
+        XMLNode::Element(e) => {
+            (e.name == "tspan" && e.attributes.get("id").is_some_and(|v| v == id))
+                || has_tspan_id(e, id)
+        }
+        _ => false,
+    })
+}
+
+/// The <tspan> with the given `id`.
+fn find_tspan_by_id<'a>(element: &'a mut Element, id: &str) -> Option<&'a mut Element> {
+    for child in &mut element.children {
+        if let XMLNode::Element(e) = child {
+            if e.name == "tspan" && e.attributes.get("id").is_some_and(|v| v == id) {
+                return Some(e);
+            }
+            if let Some(tspan) = find_tspan_by_id(e, id) {
+                return Some(tspan);
+            }
+        }
+    }
+    None
+}
+
+/// Replace the text of the <tspan> with the given `id`, returning whether one
+/// was found.
+fn set_tspan_by_id(element: &mut Element, id: &str, text: &str) -> bool {
+    for child in &mut element.children {
+        if let XMLNode::Element(e) = child {
+            if e.name == "tspan" && e.attributes.get("id").is_some_and(|v| v == id) {
+                e.children = vec![XMLNode::Text(text.to_string())];
+                return true;
+            }
+            if set_tspan_by_id(e, id, text) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Serialize a parsed SVG back into a string. The settings are spelled out,
+/// and attributes keep their order (xmltree's `attribute-order` feature), so
+/// rendering the same stats again gives byte-identical output. Empty elements
+/// keep their end tag rather than being collapsed to `<a/>`.
+fn write_svg(root: &Element) -> Result<String, StatsError> {
+    let config = EmitterConfig::new()
+        .write_document_declaration(true)
+        .perform_indent(false)
+        .normalize_empty_elements(false);
+
+    let mut output = Vec::new();
+    root.write_with_config(&mut output, config)?;
+    Ok(String::from_utf8(output)?)
+}
+
+/// Parse an SVG document, naming the file and showing the offending text
+/// when the XML is malformed.
+fn parse_svg(filename: &str, svg_content: &str) -> Result<Element, StatsError> {
+    Element::parse(svg_content.as_bytes()).map_err(|err| {
+        let ParseError::MalformedXml(xml_err) = &err else {
+            return StatsError::Svg(format!("SVG parse error in {}: {}", filename, err));
+        };
+
+        let pos = xml_err.position();
+        let snippet = svg_content
+            .lines()
+            .nth(pos.row as usize)
+            .map(|line| {
+                // Templates can carry long inline images, so only show the
+                // text around the failing column.
+                let start = (pos.column as usize).saturating_sub(40);
+                line.chars().skip(start).take(80).collect::<String>()
+            })
+            .unwrap_or_default();
+
+        StatsError::Svg(format!(
+            "SVG parse error in {} at {}: {}\n    near: {}",
+            filename,
+            pos,
+            xml_err.msg(),
+            snippet
+        ))
+    })
+}
+
+/// Print the index, `id` and text of every <tspan> in the SVG, for template
+/// authors to see which slot is which.
+pub fn svg_element_getter(filename: &Path) -> Result<(), StatsError> {
+    for (index, id, text) in list_tspans(filename)? {
+        println!(
+            "{:>3}  {:<24} {}",
+            index,
+            id.as_deref().unwrap_or("-"),
+            text
+        );
+    }
+
+    Ok(())
+}
+
+/// The index, `id` and text of every <tspan> in the SVG, in document order.
+/// The index is the position an index-based template fills.
+pub fn list_tspans(filename: &Path) -> Result<Vec<(usize, Option<String>, String)>, StatsError> {
+    debug!("Reading the SVG template {}", filename.display());
+    let svg_content = fs::read_to_string(filename)?;
+    let root = parse_svg(&filename.display().to_string(), &svg_content)?;
+
+    let mut tspans = Vec::new();
+    collect_tspan_elements(&root, &mut tspans);
+
+    Ok(tspans
+        .into_iter()
+        .enumerate()
+        .map(|(index, tspan)| {
+            (
+                index,
+                tspan.attributes.get("id").cloned(),
+                tspan_text(tspan),
+            )
+        })
+        .collect())
+}
+
+/// Collect every <tspan> below `element`, in document order.
+fn collect_tspan_elements<'a>(element: &'a Element, tspans: &mut Vec<&'a Element>) {
+    for child in &element.children {
+        if let XMLNode::Element(e) = child {
+            if e.name == "tspan" {
+                tspans.push(e);
+            }
+            collect_tspan_elements(e, tspans);
+        }
+    }
+}
+
+/// Collect the child-index path to every <tspan>, in document order.
+fn collect_tspans(element: &Element, path: &mut Vec<usize>, tspans: &mut Vec<Vec<usize>>) {
+    for (index, child) in element.children.iter().enumerate() {
+        if let XMLNode::Element(e) = child {
+            path.push(index);
+            if e.name == "tspan" {
+                tspans.push(path.clone());
+            }
+            collect_tspans(e, path, tspans);
+            path.pop();
+        }
+    }
+}
+
+/// Follow a path from `collect_tspans` back down to its element.
+fn element_at_path<'a>(element: &'a mut Element, path: &[usize]) -> Option<&'a mut Element> {
+    let Some((&index, rest)) = path.split_first() else {
+        return Some(element);
+    };
+
+    match element.children.get_mut(index) {
+        Some(XMLNode::Element(e)) => element_at_path(e, rest),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::utility::LOC_TEST_LOCK;
+    use crate::utility::MockTransport;
+    use clap::Parser;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn repo_getter_returns_the_repository() {
+        let transport = MockTransport::new().respond(
+            "repo_getter",
+            json!({ "data": { "repository": {
+                "nameWithOwner": "octo/hello",
+                "stargazers": { "totalCount": 7 },
+            } } }),
+        );
+
+        let repo = repo_getter(&transport, "octo", "hello").await.unwrap();
+
+        assert_eq!(repo.unwrap()["nameWithOwner"], "octo/hello");
+        assert_eq!(
+            transport.requests()[0].1,
+            json!({ "owner": "octo", "name": "hello" })
+        );
+    }
+
+    #[tokio::test]
+    async fn repo_getter_treats_not_found_as_none() {
+        let transport = MockTransport::new().respond(
+            "repo_getter",
+            json!({
+                "data": { "repository": null },
+                "errors": [{ "type": "NOT_FOUND", "message": "Could not resolve" }],
+            }),
+        );
+
+        assert_eq!(repo_getter(&transport, "octo", "gone").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn custom_repo_edges_skips_missing_repos() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "repo_getter",
+                json!({ "name": "hello" }),
+                json!({ "data": { "repository": { "nameWithOwner": "octo/hello" } } }),
+            )
+            .respond_to(
+                "repo_getter",
+                json!({ "name": "gone" }),
+                json!({ "data": { "repository": null }, "errors": [{ "type": "NOT_FOUND" }] }),
+            );
+
+        let repos = ["octo/hello", "not-a-repo", "octo/gone"].map(String::from);
+        let edges = custom_repo_edges(&transport, &repos).await.unwrap();
+
+        assert_eq!(
+            edges,
+            vec![json!({ "node": { "nameWithOwner": "octo/hello" } })]
+        );
+        assert_eq!(transport.count("repo_getter"), 2);
+    }
+
+    #[tokio::test]
+    async fn last_active_repo_picks_the_latest_push() {
+        let transport = MockTransport::new().respond(
+            "last_active_repo",
+            json!({ "data": { "user": { "repositories": { "nodes": [
+                { "nameWithOwner": "octo/old", "pushedAt": "2024-01-01T00:00:00Z" },
+                { "nameWithOwner": "octo/never", "pushedAt": null },
+                { "nameWithOwner": "octo/new", "pushedAt": "2024-03-01T00:00:00Z" },
+            ] } } } }),
+        );
+
+        assert_eq!(
+            last_active_repo(&transport, "octo").await.unwrap(),
+            Some(("octo/new".to_string(), "2024-03-01T00:00:00Z".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn top_languages_sums_every_page() {
+        let page = |languages: Value, next: bool| {
+            json!({ "data": { "user": { "repositories": {
+                "nodes": [{ "languages": { "edges": languages } }],
+                "pageInfo": { "endCursor": "next", "hasNextPage": next },
+            } } } })
+        };
+        let transport = MockTransport::new()
+            .respond_to(
+                "top_languages",
+                json!({ "cursor": null }),
+                page(
+                    json!([
+                        { "size": 300, "node": { "name": "Rust" } },
+                        { "size": 50, "node": { "name": "HTML" } },
+                    ]),
+                    true,
+                ),
+            )
+            .respond_to(
+                "top_languages",
+                json!({ "cursor": "next" }),
+                page(
+                    json!([
+                        { "size": 200, "node": { "name": "Python" } },
+                        { "size": 100, "node": { "name": "Rust" } },
+                    ]),
+                    false,
+                ),
+            );
+
+        let languages = top_languages(&transport, "octo", &["html".to_string()], None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            languages,
+            vec![("Rust".to_string(), 400), ("Python".to_string(), 200)]
+        );
+        assert_eq!(transport.count("top_languages"), 2);
+    }
+
+    #[tokio::test]
+    async fn commit_streak_reads_the_calendar() {
+        let days = |days: &[(&str, u64)]| {
+            days.iter()
+                .map(|(date, count)| json!({ "date": date, "contributionCount": count }))
+                .collect::<Vec<_>>()
+        };
+        let transport = MockTransport::new().respond(
+            "commit_streak",
+            json!({ "data": { "user": { "contributionsCollection": { "contributionCalendar": {
+                "weeks": [
+                    { "contributionDays": days(&[("2024-01-01", 1), ("2024-01-02", 2), ("2024-01-03", 0)]) },
+                    { "contributionDays": days(&[("2024-01-04", 3), ("2024-01-05", 0)]) },
+                ],
+            } } } } }),
+        );
+
+        let streak = commit_streak(&transport, "octo").await.unwrap();
+
+        assert_eq!(streak.current, 1);
+        assert_eq!(
+            streak.current_range,
+            Some(("2024-01-04".to_string(), "2024-01-04".to_string()))
+        );
+        assert_eq!(streak.longest, 2);
+        assert_eq!(
+            streak.longest_range,
+            Some(("2024-01-01".to_string(), "2024-01-02".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn contributions_last_year_passes_the_range() {
+        let transport = MockTransport::new().respond(
+            "contributions_last_year",
+            json!({ "data": { "user": { "contributionsCollection": {
+                "contributionCalendar": { "totalContributions": 1234 },
+            } } } }),
+        );
+
+        let total = contributions_last_year(
+            &transport,
+            "octo",
+            Some("2024-01-01T00:00:00Z"),
+            Some("2024-06-30T00:00:00Z"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(total, 1234);
+        assert_eq!(
+            transport.requests()[0].1,
+            json!({
+                "login": "octo",
+                "from": "2024-01-01T00:00:00Z",
+                "to": "2024-06-30T00:00:00Z",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn graphql_errors_fail_the_query() {
+        let transport = MockTransport::new().respond(
+            "commit_streak",
+            json!({ "data": null, "errors": [{ "message": "Something went wrong" }] }),
+        );
+
+        assert!(matches!(
+            commit_streak(&transport, "octo").await,
+            Err(StatsError::GraphQl(_))
+        ));
+    }
+
+    #[test]
+    fn template_tspan_ids_lists_the_slots_a_template_has() {
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/template.svg");
+        let ids = template_tspan_ids(&fixture).unwrap();
+
+        assert!(ids.contains("stars"));
+        assert!(ids.contains("lang_1"));
+        assert!(!ids.contains("lang_2"));
+
+        // The bundled templates place their stats by index, so none of the
+        // id-only queries run for them unless asked for
+        let template = Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/dark_mode.svg");
+        assert!(
+            !template_tspan_ids(&template)
+                .unwrap()
+                .contains("streak_current")
+        );
+    }
+
+    #[tokio::test]
+    async fn owned_repo_totals_walks_the_repositories_once() {
+        let repo = |name: &str, fork: bool, private: bool, stars: u64, watchers: u64| {
+            json!({ "node": {
+                "nameWithOwner": name,
+                "isFork": fork,
+                "isArchived": false,
+                "repositoryTopics": { "nodes": [] },
+                "isPrivate": private,
+                "stargazers": { "totalCount": stars },
+                "watchers": { "totalCount": watchers },
+            } })
+        };
+        let page = |edges: Vec<Value>, next: Option<&str>| {
+            json!({ "data": { "user": { "repositories": {
+                "edges": edges,
+                "pageInfo": { "endCursor": next, "hasNextPage": next.is_some() },
+            } } } })
+        };
+        let transport = MockTransport::new()
+            .respond_to(
+                "owned_repo_totals",
+                json!({ "login": "octo", "cursor": null }),
+                page(vec![repo("octo/a", false, false, 5, 2)], Some("octo-1")),
+            )
+            .respond_to(
+                "owned_repo_totals",
+                json!({ "login": "octo", "cursor": "octo-1" }),
+                page(vec![repo("octo/fork", true, true, 100, 100)], None),
+            )
+            .respond_to(
+                "owned_repo_totals",
+                json!({ "login": "octo-work", "cursor": null }),
+                page(
+                    vec![
+                        repo("octo/a", false, false, 5, 2),
+                        repo("work/b", false, true, 3, 1),
+                    ],
+                    None,
+                ),
+            );
+
+        let totals = owned_repo_totals(
+            &transport,
+            vec!["OWNER".to_string()],
+            false,
+            false,
+            100,
+            &["octo", "octo-work"],
+        )
+        .await
+        .unwrap();
+
+        // The fork only counts towards visibility, and the shared repository
+        // only once
+        assert_eq!(
+            totals,
+            OwnedRepoTotals {
+                stars: 8,
+                watchers: 3,
+                public: 1,
+                private: 2,
+            }
+        );
+        assert_eq!(transport.count("owned_repo_totals"), 3);
+    }
+
+    #[test]
+    fn missing_or_malformed_fields_are_invalid() {
+        assert!(matches!(
+            total_contributions(&json!({ "data": { "user": { "contributionsCollection": {} } } })),
+            Err(StatsError::Invalid(_))
+        ));
+        assert!(matches!(
+            pinned_from_json(&json!({ "data": { "user": null } })),
+            Err(StatsError::Invalid(_))
+        ));
+        assert!(matches!(
+            pinned_from_json(&json!({ "data": { "user": { "pinnedItems": { "nodes": [
+                { "nameWithOwner": "octo/hello", "stargazerCount": "7" },
+            ] } } } })),
+            Err(StatsError::Invalid(_))
+        ));
+        assert_eq!(
+            pinned_from_json(&json!({ "data": { "user": { "pinnedItems": { "nodes": [] } } } }))
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn render_svg_keeps_what_the_previous_render_showed() {
+        let template =
+            r#"<svg><text><tspan id="repos">0</tspan> <tspan id="stars">0</tspan></text></svg>"#;
+        let previous =
+            r#"<svg><text><tspan id="repos">4</tspan> <tspan id="stars">9</tspan></text></svg>"#;
+        let values = HashMap::from([("repos", "5".to_string())]);
+
+        let rendered = render_svg(template, Some(previous), &values).unwrap();
+
+        assert!(rendered.contains(r#"<tspan id="repos">5</tspan>"#));
+        assert!(rendered.contains(r#"<tspan id="stars">9</tspan>"#));
+
+        let fresh = render_svg(template, None, &values).unwrap();
+        assert!(fresh.contains(r#"<tspan id="stars">0</tspan>"#));
+    }
+
+    #[tokio::test]
+    async fn transport_errors_fail_the_query() {
+        let transport = MockTransport::new().fail(
+            "last_active_repo",
+            StatsError::RateLimited("secondary rate limit".to_string()),
+        );
+
+        assert!(matches!(
+            last_active_repo(&transport, "octo").await,
+            Err(StatsError::RateLimited(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_svg_names_the_file_and_shows_the_text() {
+        let err = parse_svg("dark_mode.svg", "<svg>\n<text><tspan>1</text>\n</svg>").unwrap_err();
+
+        let StatsError::Svg(message) = err else {
+            panic!("expected an SVG error, got {:?}", err);
+        };
+        assert!(message.starts_with("SVG parse error in dark_mode.svg at 2:"));
+        assert!(message.contains("near: <text><tspan>1</text>"));
+    }
+
+    // The id of the user in tests/fixtures/user.json, which `run_stats` sets
+    // the owner ids to as well
+    fn set_owner_ids() {
+        OWNER_IDS.get_or_init(|| vec!["U_octo".to_string()]);
+    }
+
+    fn commit(author: &str, additions: u64, deletions: u64) -> Value {
+        json!({ "node": {
+            "author": { "user": { "id": author } },
+            "additions": additions,
+            "deletions": deletions,
+        } })
+    }
+
+    // A `recursive_loc` response with one page of the history
+    fn history(edges: Vec<Value>, next: Option<&str>) -> Value {
+        json!({ "data": { "repository": { "defaultBranchRef": { "target": {
+            "__typename": "Commit",
+            "history": {
+                "totalCount": edges.len(),
+                "edges": edges,
+                "pageInfo": { "endCursor": next, "hasNextPage": next.is_some() },
+            },
+        } } } } })
+    }
+
+    async fn count_loc(
+        transport: &MockTransport,
+        page_size: usize,
+    ) -> Result<(usize, usize, usize), StatsError> {
+        recursive_loc(
+            transport,
+            &LogProgress,
+            "octo",
+            "hello",
+            &mut json!({}),
+            "",
+            0,
+            0,
+            0,
+            None,
+            page_size,
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn recursive_loc_retries_with_smaller_pages_when_rate_limited() {
+        set_owner_ids();
+        let transport = MockTransport::new()
+            .fail(
+                "recursive_loc",
+                StatsError::RateLimited("secondary rate limit".to_string()),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "page_size": 50 }),
+                history(vec![commit("U_octo", 10, 2)], None),
+            );
+
+        assert_eq!(count_loc(&transport, 100).await.unwrap(), (10, 2, 1));
+        let page_sizes: Vec<Value> = transport
+            .requests()
+            .iter()
+            .map(|(_, variables)| variables["page_size"].clone())
+            .collect();
+        assert_eq!(page_sizes, vec![json!(100), json!(50)]);
+    }
+
+    #[tokio::test]
+    async fn recursive_loc_gives_up_at_the_smallest_page_size() {
+        set_owner_ids();
+        let transport = MockTransport::new().fail(
+            "recursive_loc",
+            StatsError::RateLimited("secondary rate limit".to_string()),
+        );
+
+        assert!(matches!(
+            count_loc(&transport, MIN_HISTORY_PAGE_SIZE).await,
+            Err(StatsError::RateLimited(_))
+        ));
+        assert_eq!(transport.count("recursive_loc"), 1);
+    }
+
+    #[tokio::test]
+    async fn recursive_loc_skips_a_default_branch_that_is_not_a_commit() {
+        set_owner_ids();
+        let transport = MockTransport::new().respond(
+            "recursive_loc",
+            json!({ "data": { "repository": { "defaultBranchRef": {
+                "target": { "__typename": "Tag" },
+            } } } }),
+        );
+
+        assert_eq!(count_loc(&transport, 100).await.unwrap(), (0, 0, 0));
+    }
+
+    // A repository edge of the star, watcher and visibility queries
+    fn repo_edge(name: &str, stars: u64, watchers: u64) -> Value {
+        json!({ "node": {
+            "nameWithOwner": name,
+            "isFork": false,
+            "isArchived": false,
+            "repositoryTopics": { "nodes": [] },
+            "isPrivate": false,
+            "stargazers": { "totalCount": stars },
+            "watchers": { "totalCount": watchers },
+        } })
+    }
+
+    fn repos_page(edges: Vec<Value>, next: Option<&str>) -> Value {
+        json!({ "data": { "user": { "repositories": {
+            "totalCount": edges.len(),
+            "edges": edges,
+            "pageInfo": { "endCursor": next, "hasNextPage": next.is_some() },
+        } } } })
+    }
+
+    #[tokio::test]
+    async fn graph_repos_stars_counts_every_page() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "cursor": null }),
+                repos_page(vec![repo_edge("octo/a", 5, 0)], Some("page-1")),
+            )
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "cursor": "page-1" }),
+                repos_page(vec![repo_edge("octo/b", 7, 0)], None),
+            );
+
+        let stars = graph_repos_stars(
+            &transport,
+            "stars",
+            vec!["OWNER".to_string()],
+            false,
+            false,
+            100,
+            None,
+            &["octo"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stars, 12);
+        assert_eq!(transport.count("graph_repos_stars"), 2);
+    }
+
+    #[tokio::test]
+    async fn loc_edges_follows_the_end_cursor() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "loc_query",
+                json!({ "cursor": null }),
+                repos_page(vec![repo_edge("octo/a", 0, 0)], Some("page-1")),
+            )
+            .respond_to(
+                "loc_query",
+                json!({ "cursor": "page-1" }),
+                repos_page(vec![repo_edge("octo/b", 0, 0)], None),
+            );
+
+        let (edges, truncated) = loc_edges(&transport, &["OWNER".to_string()], 100, None, "octo")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            edges,
+            vec![repo_edge("octo/a", 0, 0), repo_edge("octo/b", 0, 0)]
+        );
+        assert!(!truncated);
+    }
+
+    #[tokio::test]
+    async fn stats_getter_flattens_the_counts_the_card_shows() {
+        let transport = MockTransport::new().respond(
+            "stats_getter",
+            json!({ "data": { "user": {
+                "pullRequests": { "totalCount": 1200 },
+                "issues": { "totalCount": 4 },
+            } } }),
+        );
+
+        let stats = stats_getter(&transport, &["octo"]).await.unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            Some(&stats),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(stats["prs_opened"], 1200);
+        assert_eq!(stats["issues"], 4);
+        assert_eq!(stats["prs_merged"], 0);
+        assert_eq!(values["prs"], "1,200");
+        assert_eq!(values["issues"], "4");
+    }
+
+    #[test]
+    fn tspan_paths_lead_back_to_nested_tspans_in_document_order() {
+        let mut root = parse_svg(
+            "test.svg",
+            "<svg><text><tspan>a<tspan>b</tspan></tspan></text><g><text><tspan>c</tspan></text></g></svg>",
+        )
+        .unwrap();
+
+        let mut tspans = vec![];
+        collect_tspans(&root, &mut vec![], &mut tspans);
+        assert_eq!(tspans, vec![vec![0, 0], vec![0, 0, 1], vec![1, 0, 0]]);
+
+        // Innermost first, as overwriting a tspan drops the ones inside it
+        for (index, path) in tspans.iter().enumerate().rev() {
+            let tspan = element_at_path(&mut root, path).unwrap();
+            tspan.children = vec![XMLNode::Text(index.to_string())];
+        }
+        assert!(element_at_path(&mut root, &[5]).is_none());
+
+        let rendered = write_svg(&root).unwrap();
+        assert!(rendered.ends_with(
+            "<svg><text><tspan>0</tspan></text><g><text><tspan>2</tspan></text></g></svg>"
+        ));
+    }
+
+    #[test]
+    fn fill_svg_prefers_ids_and_falls_back_to_indices() {
+        let values = HashMap::from([
+            ("stars", "12".to_string()),
+            ("loc_del", "3--".to_string()),
+            ("last_active", "today".to_string()),
+        ]);
+
+        let by_id = render_svg(
+            r#"<svg><text><tspan>x</tspan><tspan id="stars">0</tspan><tspan id="last_active">-</tspan></text></svg>"#,
+            None,
+            &values,
+        )
+        .unwrap();
+        assert!(by_id.contains(r#"<tspan>x</tspan><tspan id="stars">12</tspan>"#));
+        assert!(by_id.contains(r#"<tspan id="last_active">today</tspan>"#));
+
+        let tspans: String = (0..49).map(|i| format!("<tspan>{}</tspan>", i)).collect();
+        let by_index = render_svg(
+            &format!("<svg><text>{}</text></svg>", tspans),
+            None,
+            &values,
+        )
+        .unwrap();
+        let texts: Vec<&str> = by_index
+            .split("<tspan>")
+            .skip(1)
+            .map(|tspan| tspan.split('<').next().unwrap())
+            .collect();
+        assert_eq!(texts[38], "12");
+        assert_eq!(texts[48], "3--");
+        assert_eq!(texts[37], "37");
+        assert!(!by_index.contains("today"));
+    }
+
+    // A repository edge of the LOC query, with `commits` on its default branch
+    fn loc_edge(name: &str, commits: u64) -> Value {
+        json!({ "node": {
+            "nameWithOwner": name,
+            "defaultBranchRef": { "target": { "history": { "totalCount": commits } } },
+        } })
+    }
+
+    #[tokio::test]
+    async fn cache_builder_recounts_stale_repos_into_their_own_entries() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let edges = [
+            loc_edge("octo/one", 1),
+            loc_edge("octo/two", 2),
+            loc_edge("octo/three", 1),
+        ];
+        let transport = MockTransport::new()
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "one" }),
+                history(vec![commit("U_octo", 10, 1)], None),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "two" }),
+                history(
+                    vec![commit("U_octo", 20, 2), commit("U_other", 99, 99)],
+                    None,
+                ),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "three" }),
+                history(vec![commit("U_octo", 30, 3)], None),
+            );
+        let misses = LOC_CACHE_MISSES.load(Ordering::Relaxed);
+
+        let counted = cache_builder(
+            &transport,
+            &LogProgress,
+            &edges,
+            2,
+            false,
+            &[],
+            0,
+            0,
+            "builder",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(counted, (60, 6, 54, false));
+        assert_eq!(LOC_CACHE_MISSES.load(Ordering::Relaxed), misses + 3);
+        let cache = LocCache::load("builder", 2).unwrap().unwrap();
+        assert!(cache.matches(&edges));
+        let entries: Vec<(i64, i64, i64)> = cache
+            .repos
+            .iter()
+            .map(|repo| (repo.commits, repo.my_commits, repo.loc_add))
+            .collect();
+        assert_eq!(entries, vec![(1, 1, 10), (2, 1, 20), (1, 1, 30)]);
+
+        // Nothing changed, so the second run only reads the cache
+        let again = cache_builder(
+            &transport,
+            &LogProgress,
+            &edges,
+            2,
+            false,
+            &[],
+            0,
+            0,
+            "builder",
+        )
+        .await
+        .unwrap();
+        assert_eq!(again, (60, 6, 54, true));
+        assert_eq!(transport.count("recursive_loc"), 3);
+    }
+
+    #[test]
+    fn rank_languages_sorts_by_size_then_name() {
+        let totals = HashMap::from([
+            ("Rust".to_string(), 300),
+            ("Go".to_string(), 100),
+            ("C".to_string(), 100),
+            ("HTML".to_string(), 900),
+        ]);
+
+        assert_eq!(
+            rank_languages(totals.clone(), &[" html ".to_string()], None),
+            vec![
+                ("Rust".to_string(), 300),
+                ("C".to_string(), 100),
+                ("Go".to_string(), 100),
+            ]
+        );
+        assert_eq!(
+            rank_languages(totals, &[], Some(2)),
+            vec![("HTML".to_string(), 900), ("Rust".to_string(), 300)]
+        );
+    }
+
+    #[test]
+    fn counted_edges_leaves_forks_out_unless_included() {
+        let mut fork = repo_edge("octo/fork", 50, 0);
+        fork["node"]["isFork"] = json!(true);
+        let edges = vec![repo_edge("octo/own", 5, 0), fork];
+
+        assert_eq!(
+            counted_edges(edges.clone(), false, false),
+            vec![repo_edge("octo/own", 5, 0)]
+        );
+        assert_eq!(counted_edges(edges.clone(), true, false), edges);
+    }
+
+    #[tokio::test]
+    async fn archived_repos_only_count_when_included() {
+        let mut archived = repo_edge("octo/old", 100, 0);
+        archived["node"]["isArchived"] = json!(true);
+        let transport = MockTransport::new().respond_always(
+            "graph_repos_stars",
+            json!({}),
+            repos_page(vec![repo_edge("octo/new", 5, 0), archived], None),
+        );
+        let stars = |include_archived| {
+            graph_repos_stars(
+                &transport,
+                "stars",
+                vec!["OWNER".to_string()],
+                false,
+                include_archived,
+                100,
+                None,
+                &["octo"],
+            )
+        };
+
+        assert_eq!(stars(false).await.unwrap(), 5);
+        assert_eq!(stars(true).await.unwrap(), 105);
+    }
+
+    #[test]
+    fn index_templates_with_too_few_tspans_fail_or_skip_missing_slots() {
+        let values = HashMap::from([("repos", "2".to_string()), ("loc_del", "3--".to_string())]);
+        let template = |count: usize| {
+            let tspans: String = (0..count)
+                .map(|i| format!("<tspan>{}</tspan>", i))
+                .collect();
+            format!("<svg><text>{}</text></svg>", tspans)
+        };
+
+        let err = render_svg(&template(3), None, &values).unwrap_err();
+        assert!(matches!(&err, StatsError::Svg(message) if message.contains("found 3")));
+
+        // Room for `repos` at 34, but not for `loc_del` at 48
+        let rendered = render_svg(&template(40), None, &values).unwrap();
+        assert!(rendered.contains("<tspan>33</tspan><tspan>2</tspan><tspan>35</tspan>"));
+        assert!(!rendered.contains("3--"));
+    }
+
+    #[tokio::test]
+    async fn user_getter_reads_the_follow_counts() {
+        let transport = MockTransport::new().respond(
+            "user_getter",
+            json!({ "data": { "user": {
+                "id": "U_octo",
+                "createdAt": "2015-06-01T00:00:00Z",
+                "followers": { "totalCount": 12 },
+                "following": { "totalCount": 3 },
+            } } }),
+        );
+
+        let user = user_getter(&transport, "octo").await.unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&user),
+        );
+
+        assert_eq!(user.id, "U_octo");
+        assert_eq!((user.followers, user.following), (Some(12), Some(3)));
+        assert_eq!(values["followers"], "12");
+        assert_eq!(values["following"], "3");
+    }
+
+    #[test]
+    fn excluded_repos_match_ignoring_case_and_spaces() {
+        let excluded = [" Octo/Monorepo ".to_string(), "octo/imported".to_string()];
+
+        assert!(is_listed(&repo_edge("octo/monorepo", 0, 0), &excluded));
+        assert!(is_listed(&repo_edge("octo/imported", 0, 0), &excluded));
+        assert!(!is_listed(&repo_edge("octo/hello", 0, 0), &excluded));
+        assert!(!is_listed(&json!({ "node": {} }), &excluded));
+    }
+
+    // Records every log line, for tests of what gets logged at which level
+    struct TestLogger(Mutex<Vec<(log::Level, String)>>);
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger(Mutex::new(Vec::new()));
+
+    /// The lines logged so far that contain `text`.
+    fn logged(text: &str) -> Vec<(log::Level, String)> {
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+        let lines = LOGGER.0.lock().unwrap();
+        lines
+            .iter()
+            .filter(|(_, line)| line.contains(text))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cache_misses_are_logged_at_info() {
+        let _lock = LOC_TEST_LOCK.lock().await;
+        logged("");
+        let edges =
+            [json!({ "node": { "nameWithOwner": "octo/empty", "defaultBranchRef": null } })];
+
+        cache_builder(
+            &MockTransport::new(),
+            &LogProgress,
+            &edges,
+            1,
+            false,
+            &[],
+            0,
+            0,
+            "logged",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            logged("LOC cache miss for logged"),
+            vec![(
+                log::Level::Info,
+                "LOC cache miss for logged, rebuilding it".to_string()
+            )]
+        );
+        assert_eq!(
+            logged("octo/empty has no default branch")[0].0,
+            log::Level::Debug
+        );
+    }
+
+    #[tokio::test]
+    async fn contributions_default_to_the_last_year() {
+        let transport = MockTransport::new().respond(
+            "contributions_last_year",
+            json!({ "data": { "user": { "contributionsCollection": {
+                "contributionCalendar": { "totalContributions": 1234 },
+            } } } }),
+        );
+
+        let total = contributions_last_year(&transport, "octo", None, None)
+            .await
+            .unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(total),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        // GitHub takes a missing range as the year up to now
+        assert_eq!(
+            transport.requests()[0].1,
+            json!({ "login": "octo", "from": null, "to": null })
+        );
+        assert_eq!(values["contributions"], "1234");
+    }
+
+    #[tokio::test]
+    async fn repos_shared_across_affiliations_and_users_count_once() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "login": "octo" }),
+                repos_page(
+                    vec![
+                        repo_edge("octo/hello", 5, 0),
+                        repo_edge("acme/tool", 7, 0),
+                        // Listed again as an organization member
+                        repo_edge("acme/tool", 7, 0),
+                    ],
+                    None,
+                ),
+            )
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "login": "work-octo" }),
+                repos_page(vec![repo_edge("acme/tool", 7, 0)], None),
+            );
+
+        let stars = graph_repos_stars(
+            &transport,
+            "stars",
+            vec!["OWNER".to_string(), "ORGANIZATION_MEMBER".to_string()],
+            false,
+            false,
+            100,
+            None,
+            &["octo", "work-octo"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(stars, 12);
+        assert_eq!(
+            transport.requests()[0].1["owner_affiliation"],
+            json!(["OWNER", "ORGANIZATION_MEMBER"])
+        );
+    }
+
+    #[tokio::test]
+    async fn recursive_loc_asks_for_history_since_the_window() {
+        set_owner_ids();
+        let transport = MockTransport::new().respond(
+            "recursive_loc",
+            history(vec![commit("U_octo", 10, 2)], None),
+        );
+
+        recursive_loc(
+            &transport,
+            &LogProgress,
+            "octo",
+            "hello",
+            &mut json!({}),
+            "",
+            0,
+            0,
+            0,
+            None,
+            100,
+            Some("2024-01-01T00:00:00Z"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(transport.requests()[0].1["since"], "2024-01-01T00:00:00Z");
+    }
+
+    // An archive with the usual 7 header and 3 footer lines around `data`,
+    // the last footer line carrying `extra_commits`
+    fn archive(data: &[&str], extra_commits: u64) -> Vec<String> {
+        let header = (1..=7).map(|line| format!("Header line {}", line));
+        let footer = [
+            "-".repeat(20),
+            "Totals".to_string(),
+            format!("Deleted repositories: 0 0 {}", extra_commits),
+        ];
+        header
+            .chain(data.iter().map(|line| line.to_string()))
+            .chain(footer)
+            .collect()
+    }
+
+    #[test]
+    fn archive_totals_sum_the_repositories_and_deleted_commits() {
+        let lines = archive(&["aaa 50 3 100 10", "bbb 20, 9, 40, 4,"], 5);
+
+        // The commits of both repositories plus the 5 of deleted ones
+        assert_eq!(archive_totals(&lines), [140, 14, 126, 17, 2]);
+    }
+
+    #[test]
+    fn archive_totals_survive_short_and_malformed_archives() {
+        let short: Vec<String> = ["Header line 1", "aaa 50 3 100 10"]
+            .map(String::from)
+            .into();
+        assert_eq!(archive_totals(&short), [0, 0, 0, 0, 0]);
+        assert_eq!(archive_totals(&archive(&[], 0)), [0, 0, 0, 0, 0]);
+
+        // The malformed line is skipped but still a repository, and the last
+        // data line isn't counted again with the footer
+        let lines = archive(&["aaa 50 3 100 10", "not a repository"], 0);
+        assert_eq!(archive_totals(&lines), [100, 10, 90, 3, 2]);
+    }
+
+    #[test]
+    fn counted_edges_keeps_the_first_of_duplicate_repos() {
+        let edges = vec![
+            loc_edge("octo/hello", 3),
+            loc_edge("acme/tool", 10),
+            loc_edge("octo/hello", 99),
+        ];
+
+        assert_eq!(
+            counted_edges(edges, false, false),
+            vec![loc_edge("octo/hello", 3), loc_edge("acme/tool", 10)]
+        );
+    }
+
+    #[test]
+    fn rendering_leaves_the_template_alone() {
+        let dir = CACHE_DIR.join("templates");
+        fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("dark_mode.svg");
+        let output = dir.join("dark_mode.out.svg");
+        let placeholder = r#"<svg><text><tspan id="stars">STARS</tspan></text></svg>"#;
+        fs::write(&template, placeholder).unwrap();
+        let values = HashMap::from([("stars", "12".to_string())]);
+
+        assert!(svg_overwrite_by_id(&template, &output, &values).unwrap());
+        assert!(!svg_overwrite_by_id(&template, &output, &values).unwrap());
+
+        assert_eq!(fs::read_to_string(&template).unwrap(), placeholder);
+        assert!(
+            fs::read_to_string(&output)
+                .unwrap()
+                .contains(r#"<tspan id="stars">12</tspan>"#)
+        );
+    }
+
+    // Records the progress updates it gets
+    #[derive(Default)]
+    struct RecordingProgress(Mutex<Vec<String>>);
+
+    impl LocProgress for RecordingProgress {
+        fn repo_started(&self, name_with_owner: &str, index: usize, total: usize) {
+            let update = format!("{} {} of {}", name_with_owner, index, total);
+            self.0.lock().unwrap().push(update);
+        }
+
+        fn page_fetched(&self, name_with_owner: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} page", name_with_owner));
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_is_reported_per_repo_and_page() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let edges = [loc_edge("octo/big", 2), loc_edge("octo/small", 1)];
+        let transport = MockTransport::new()
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "big", "cursor": null }),
+                history(vec![commit("U_octo", 1, 0)], Some("big-1")),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "big", "cursor": "big-1" }),
+                history(vec![commit("U_octo", 1, 0)], None),
+            )
+            .respond_to(
+                "recursive_loc",
+                json!({ "repo_name": "small" }),
+                history(vec![commit("U_octo", 1, 0)], None),
+            );
+        let progress = RecordingProgress::default();
+
+        cache_builder(
+            &transport,
+            &progress,
+            &edges,
+            1,
+            false,
+            &[],
+            0,
+            0,
+            "progress",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            progress.0.into_inner().unwrap(),
+            vec![
+                "octo/big 1 of 2",
+                "octo/big page",
+                "octo/big page",
+                "octo/small 2 of 2",
+                "octo/small page",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_scopes_counts_broader_scopes_as_granted() {
+        assert_eq!(missing_scopes("repo, read:user"), Vec::<&str>::new());
+        assert_eq!(missing_scopes("repo, user"), Vec::<&str>::new());
+        assert_eq!(missing_scopes("read:user, gist"), vec!["repo"]);
+        assert_eq!(missing_scopes(""), vec!["read:user", "repo"]);
+    }
+
+    #[tokio::test]
+    async fn watchers_are_summed_over_every_page() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "cursor": null }),
+                repos_page(
+                    vec![repo_edge("octo/a", 0, 2), repo_edge("octo/b", 0, 3)],
+                    Some("page-1"),
+                ),
+            )
+            .respond_to(
+                "graph_repos_stars",
+                json!({ "cursor": "page-1" }),
+                repos_page(vec![repo_edge("octo/c", 0, 4)], None),
+            );
+
+        let watchers = graph_repos_stars(
+            &transport,
+            "watchers",
+            vec!["OWNER".to_string()],
+            false,
+            false,
+            100,
+            None,
+            &["octo"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(watchers, 9);
+    }
+
+    #[test]
+    fn loc_values_show_net_added_deleted_and_total() {
+        let loc = LocStats::new(1200, 3400);
+        assert_eq!((loc.net, loc.total), (-2200, 4600));
+
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&loc),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(values["loc"], "-2,200");
+        assert_eq!(values["loc_add"], "1,200++");
+        assert_eq!(values["loc_del"], "3,400--");
+        assert_eq!(values["loc_total"], "4,600");
+        assert!(!values.contains_key("loc_delta"));
+    }
+
+    #[tokio::test]
+    async fn forced_repos_are_recounted_alone() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let edges = [
+            loc_edge("octo/one", 1),
+            loc_edge("octo/two", 1),
+            loc_edge("octo/three", 1),
+        ];
+        let transport = MockTransport::new().respond_always(
+            "recursive_loc",
+            json!({}),
+            history(vec![commit("U_octo", 10, 0)], None),
+        );
+        cache_builder(
+            &transport,
+            &LogProgress,
+            &edges,
+            1,
+            false,
+            &[],
+            0,
+            0,
+            "forced",
+        )
+        .await
+        .unwrap();
+        let before = LocCache::load("forced", 1).unwrap().unwrap();
+
+        let transport = MockTransport::new().respond_to(
+            "recursive_loc",
+            json!({ "repo_name": "two" }),
+            history(vec![commit("U_octo", 25, 5)], None),
+        );
+        let counted = cache_builder(
+            &transport,
+            &LogProgress,
+            &edges,
+            1,
+            false,
+            &["OCTO/Two".to_string()],
+            0,
+            0,
+            "forced",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(transport.count("recursive_loc"), 1);
+        assert_eq!(counted, (45, 5, 40, true));
+        let after = LocCache::load("forced", 1).unwrap().unwrap();
+        assert_eq!(after.repos[0], before.repos[0]);
+        assert_eq!((after.repos[1].loc_add, after.repos[1].loc_del), (25, 5));
+        assert_eq!(after.repos[2], before.repos[2]);
+    }
+
+    #[tokio::test]
+    async fn sponsors_count_is_zero_when_sponsors_are_disabled() {
+        let enabled = MockTransport::new().respond(
+            "sponsors_count",
+            json!({ "data": { "user": { "sponsors": { "totalCount": 3 } } } }),
+        );
+        let disabled = MockTransport::new().respond(
+            "sponsors_count",
+            json!({ "data": { "user": { "sponsors": null } } }),
+        );
+
+        assert_eq!(sponsors_count(&enabled, "octo").await.unwrap(), 3);
+        assert_eq!(sponsors_count(&disabled, "octo").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn merged_pull_requests_are_counted_apart_from_opened_ones() {
+        let transport = MockTransport::new().respond(
+            "stats_getter",
+            json!({ "data": { "user": {
+            "pullRequests": { "totalCount": 7 },
+            "mergedPullRequests": { "totalCount": 5 },
+        } } }),
+        );
+
+        let stats = stats_getter(&transport, &["octo"]).await.unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            Some(&stats),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(stats["prs_opened"], 7);
+        assert_eq!(stats["prs_merged"], 5);
+        assert_eq!(values["prs"], "7");
+        assert_eq!(values["prs_merged"], "5");
+    }
+
+    #[tokio::test]
+    async fn reviews_and_closed_issues_are_summed_over_the_users() {
+        let user = |issues, closed, prs, reviews| {
+            json!({ "data": { "user": {
+            "pullRequests": { "totalCount": prs },
+            "issues": { "totalCount": issues },
+            "closedIssues": { "totalCount": closed },
+            "contributionsCollection": { "totalPullRequestReviewContributions": reviews },
+        } } })
+        };
+        let transport = MockTransport::new()
+            .respond_to("stats_getter", json!({ "login": "octo" }), user(4, 3, 7, 2))
+            .respond_to(
+                "stats_getter",
+                json!({ "login": "octo-work" }),
+                user(6, 1, 1, 998),
+            );
+
+        let stats = stats_getter(&transport, &["octo", "octo-work"])
+            .await
+            .unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            Some(&stats),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(values["issues"], "10");
+        assert_eq!(values["issues_closed"], "4");
+        assert_eq!(values["prs"], "8");
+        assert_eq!(values["prs_reviewed"], "1,000");
+    }
+
+    #[tokio::test]
+    async fn cache_only_reports_the_cached_totals_as_they_are() {
+        let _lock = LOC_TEST_LOCK.lock().await;
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        assert!(matches!(
+            cached_loc(&[], 7, 0, 0, "cache-only"),
+            Err(StatsError::Cache(_))
+        ));
+
+        let mut cache = LocCache::new(7);
+        cache.repos = vec![
+            CachedRepo::from_legacy_line("aaa 50 3 100 10").unwrap(),
+            CachedRepo::from_legacy_line("bbb 20 9 40 4").unwrap(),
+        ];
+        cache.save("cache-only").unwrap();
+        let hits = LOC_CACHE_HITS.load(Ordering::Relaxed);
+
+        assert_eq!(
+            cached_loc(&[], 7, 5, 1, "cache-only").unwrap(),
+            (145, 15, 130, true)
+        );
+        assert_eq!(LOC_CACHE_HITS.load(Ordering::Relaxed), hits + 2);
+
+        let config = Config::try_parse_from(["test", "--cache-only"]).unwrap();
+        assert!(config.loc_cache_only());
+        assert!(
+            Config::try_parse_from(["test", "--cache-only", "--force-repos", "octo/one"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn repo_privacy_counts_public_and_private_repos() {
+        let repo = |name: &str, private: bool| {
+            json!({ "node": {
+            "nameWithOwner": name,
+            "repositoryTopics": { "nodes": [] },
+            "isPrivate": private,
+        } })
+        };
+        let transport = MockTransport::new().respond(
+            "repo_visibility",
+            json!({ "data": { "user": { "repositories": {
+            "edges": [repo("octo/a", false), repo("octo/b", true), repo("octo/c", false)],
+            "pageInfo": { "endCursor": null, "hasNextPage": false },
+        } } } }),
+        );
+
+        let private = repo_privacy(&transport, &["OWNER".to_string()], 100, "octo")
+            .await
+            .unwrap();
+        let private_count = private.values().filter(|&&is_private| is_private).count();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some((private.len() - private_count, private_count)),
+            None,
+            None,
+        );
+
+        assert!(private["octo/b"]);
+        assert_eq!(values["repos_public"], "2");
+        assert_eq!(values["repos_private"], "1");
+    }
+
+    #[tokio::test]
+    async fn repository_queries_use_the_configured_page_size() {
+        let owned = ["OWNER".to_string()];
+        let transport = MockTransport::new()
+            .respond_always("loc_query", json!({}), repos_page(vec![], None))
+            .respond(
+                "graph_repos_stars",
+                json!({ "data": { "user": { "repositories": { "totalCount": 4 } } } }),
+            );
+
+        for page_size in [100, 1, 30] {
+            loc_edges(&transport, &owned, page_size, None, "octo")
+                .await
+                .unwrap();
+        }
+        let repos = graph_repos_stars(
+            &transport,
+            "repos",
+            owned.to_vec(),
+            false,
+            false,
+            30,
+            None,
+            &["octo"],
+        )
+        .await
+        .unwrap();
+
+        let page_sizes: Vec<Value> = transport
+            .requests()
+            .iter()
+            .map(|(_, variables)| variables["page_size"].clone())
+            .collect();
+        assert_eq!(page_sizes, vec![json!(100), json!(1), json!(30), json!(30)]);
+        assert_eq!(repos, 4);
+    }
+
+    #[test]
+    fn per_repo_rows_name_the_entries_by_net_loc() {
+        let edges = vec![loc_edge("octo/small", 5), loc_edge("octo/big", 50)];
+        let entry = |name: &str, loc_add, loc_del| CachedRepo {
+            hash: name_hash(name),
+            commits: 10,
+            my_commits: 4,
+            loc_add,
+            loc_del,
+        };
+        let mut cache = LocCache::new(7);
+        cache.repos = vec![
+            entry("octo/small", 100, 90),
+            entry("octo/big", 500, 100),
+            entry("octo/gone", 50, 0),
+        ];
+
+        let rows: Vec<(&str, i64)> = per_repo_rows(&edges, &cache)
+            .into_iter()
+            .map(|(name, repo)| (name, repo.loc_add - repo.loc_del))
+            .collect();
+
+        // An entry without an edge is shown by its hash
+        let gone = name_hash("octo/gone");
+        assert_eq!(
+            rows,
+            vec![("octo/big", 400), (gone.as_str(), 50), ("octo/small", 10)]
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_repos_get_a_zero_entry_without_a_history_query() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let edges = [
+            loc_edge("octo/one", 1),
+            json!({ "node": { "nameWithOwner": "octo/empty", "defaultBranchRef": null } }),
+        ];
+        let transport = MockTransport::new().respond_to(
+            "recursive_loc",
+            json!({ "repo_name": "one" }),
+            history(vec![commit("U_octo", 10, 2)], None),
+        );
+
+        let counted = cache_builder(
+            &transport,
+            &LogProgress,
+            &edges,
+            1,
+            false,
+            &[],
+            0,
+            0,
+            "empty",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(transport.count("recursive_loc"), 1);
+        assert_eq!(counted, (10, 2, 8, false));
+        let cache = LocCache::load("empty", 1).unwrap().unwrap();
+        assert_eq!(
+            cache.repos[1],
+            CachedRepo {
+                hash: name_hash("octo/empty"),
+                ..CachedRepo::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn fewer_than_six_pins_leave_the_other_slots_empty() {
+        let pin =
+            |name: &str, stars: u64| json!({ "nameWithOwner": name, "stargazerCount": stars });
+        let transport = MockTransport::new().respond(
+            "pinned_repos",
+            json!({ "data": { "user": { "pinnedItems": { "nodes": [
+            pin("octo/hello", 5),
+            pin("octo/tool", 1200),
+            pin("acme/lib", 3),
+        ] } } } }),
+        );
+
+        let pinned = pinned_repos(&transport, "octo").await.unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&pinned),
+            None,
+        );
+
+        assert_eq!(
+            pinned
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>(),
+            ["octo/hello", "octo/tool", "acme/lib"]
+        );
+        assert_eq!(values["pinned_stars"], "1,208");
+        assert_eq!(values["pinned_2"], "octo/tool (1,200)");
+        assert_eq!(values["pinned_4"], "");
+        assert_eq!(values["pinned_6"], "");
+    }
+
+    #[tokio::test]
+    async fn repos_shared_by_two_users_are_counted_once() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let transport = MockTransport::new()
+            .respond_to(
+                "loc_query",
+                json!({ "login": "octo" }),
+                repos_page(
+                    vec![loc_edge("octo/shared", 1), loc_edge("octo/a", 1)],
+                    None,
+                ),
+            )
+            .respond_to(
+                "loc_query",
+                json!({ "login": "octo-work" }),
+                repos_page(
+                    vec![loc_edge("octo/shared", 1), loc_edge("work/b", 1)],
+                    None,
+                ),
+            )
+            .respond_always(
+                "recursive_loc",
+                json!({}),
+                history(vec![commit("U_octo", 10, 1)], None),
+            );
+
+        let counted = loc_query(
+            &transport,
+            &LogProgress,
+            vec!["OWNER".to_string()],
+            1,
+            false,
+            &[],
+            false,
+            false,
+            LOC_PAGE_SIZE,
+            None,
+            &["octo", "octo-work"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(transport.count("recursive_loc"), 3);
+        assert_eq!(counted, (30, 3, 27, false));
+        let config = Config::try_parse_from(["test", "--user", "octo, octo-work"]).unwrap();
+        let cache = LocCache::load(&config.users_key().unwrap(), 1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache.repos.len(), 3);
+    }
+
+    #[test]
+    fn list_tspans_reports_index_id_and_text() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        let path = CACHE_DIR.join("list_tspans.svg");
+        fs::write(
+        &path,
+        r#"<svg><text><tspan id="repos">12</tspan> <tspan>label</tspan></text><g><text><tspan id="stars">3</tspan></text></g></svg>"#,
+    )
+    .unwrap();
+
+        assert_eq!(
+            list_tspans(&path).unwrap(),
+            vec![
+                (0, Some("repos".to_string()), "12".to_string()),
+                (1, None, "label".to_string()),
+                (2, Some("stars".to_string()), "3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewriting_the_same_stats_leaves_the_svg_byte_identical() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        let template = CACHE_DIR.join("idempotent_template.svg");
+        let output = CACHE_DIR.join("idempotent.svg");
+        let markup = r#"<?xml version="1.0" encoding="UTF-8"?><svg xmlns="http://www.w3.org/2000/svg" width="10"><rect x="1" y="2"></rect><text><tspan id="label"></tspan><tspan id="repos">0</tspan></text></svg>"#;
+        fs::write(&template, markup).unwrap();
+        let values = HashMap::from([("repos", "5".to_string())]);
+
+        assert!(svg_overwrite_by_id(&template, &output, &values).unwrap());
+        let first = fs::read(&output).unwrap();
+        assert!(!svg_overwrite_by_id(&template, &output, &values).unwrap());
+
+        assert_eq!(fs::read(&output).unwrap(), first);
+        // Only the stat changed; empty elements aren't collapsed
+        assert_eq!(
+            String::from_utf8(first).unwrap(),
+            markup.replace(">0<", ">5<")
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_recounts_resume_without_counting_pages_twice() {
+        set_owner_ids();
+        let _lock = LOC_TEST_LOCK.lock().await;
+        let edges = &[loc_edge("octo/huge", 3)];
+        let build = |transport: MockTransport| async move {
+            let counted = cache_builder(
+                &transport,
+                &LogProgress,
+                edges,
+                1,
+                false,
+                &[],
+                0,
+                0,
+                "resumed",
+            )
+            .await;
+            (counted, transport)
+        };
+
+        let (counted, _) = build(
+            MockTransport::new()
+                .respond_to(
+                    "recursive_loc",
+                    json!({ "cursor": null }),
+                    history(vec![commit("U_octo", 10, 1)], Some("huge-1")),
+                )
+                .fail(
+                    "recursive_loc",
+                    StatsError::Invalid("connection reset".to_string()),
+                ),
+        )
+        .await;
+        assert!(counted.is_err());
+        let key = cache_key("resumed");
+        let point = load_resume("octo/huge", &key, 3).unwrap();
+        assert_eq!((point.cursor.as_str(), point.loc_add), ("huge-1", 10));
+        assert_eq!(load_resume("octo/huge", &key, 4), None);
+
+        let (counted, transport) = build(MockTransport::new().respond_to(
+            "recursive_loc",
+            json!({ "cursor": "huge-1" }),
+            history(vec![commit("U_octo", 20, 2)], None),
+        ))
+        .await;
+
+        assert_eq!(counted.unwrap(), (30, 3, 27, false));
+        assert_eq!(transport.count("recursive_loc"), 1);
+        assert_eq!(load_resume("octo/huge", &key, 3), None);
+    }
+
+    #[tokio::test]
+    async fn org_stats_sums_the_stars_over_every_page() {
+        let page = |edges: Vec<Value>, next: Option<&str>| {
+            json!({ "data": { "organization": {
+            "membersWithRole": { "totalCount": 12 },
+            "repositories": {
+                "edges": edges,
+                "pageInfo": { "endCursor": next, "hasNextPage": next.is_some() },
+            },
+        } } })
+        };
+        let mut fork = repo_edge("acme/fork", 500, 0);
+        fork["node"]["isFork"] = json!(true);
+        let transport = MockTransport::new()
+            .respond_to(
+                "org_stats",
+                json!({ "login": "acme", "cursor": null }),
+                page(vec![repo_edge("acme/api", 40, 0), fork], Some("acme-1")),
+            )
+            .respond_to(
+                "org_stats",
+                json!({ "login": "acme", "cursor": "acme-1" }),
+                page(vec![repo_edge("acme/web", 2, 0)], None),
+            );
+
+        let stats = org_stats(&transport, "acme", false, false, 100)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            OrgStats {
+                repos: 2,
+                stars: 42,
+                members: 12,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn force_close_file_leaves_the_cache_parseable() {
+        let _lock = LOC_TEST_LOCK.lock().await;
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        // The partial file is named after the configured user, whose cache the
+        // end-to-end test checks, so that cache is put back afterwards
+        let user_name = CONFIG.users_key().unwrap();
+        let previous = fs::read(cache_file(&user_name)).ok();
+        let mut cache = LocCache::new(7);
+        cache.repos = vec![CachedRepo::from_legacy_line("aaa 50 3 100 10").unwrap()];
+        cache.save(&user_name).unwrap();
+
+        let mut state = json!({ "cursor": "huge-1", "loc_add": 10 });
+        force_close_file(&mut state, "Cache of octo\nKeep out").unwrap();
+
+        let partial: Value =
+            serde_json::from_str(&fs::read_to_string(partial_file(&user_name)).unwrap()).unwrap();
+        assert_eq!(partial["comment"], json!(["Cache of octo", "Keep out"]));
+        assert_eq!(partial["state"], state);
+        let loaded = LocCache::load(&user_name, 7).unwrap().unwrap();
+        assert_eq!(loaded.repos, cache.repos);
+
+        fs::remove_file(partial_file(&user_name)).unwrap();
+        match previous {
+            Some(contents) => fs::write(cache_file(&user_name), contents).unwrap(),
+            None => fs::remove_file(cache_file(&user_name)).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn language_shares_are_of_the_kept_languages() {
+        let repo = |languages: &[(&str, u64)]| {
+            let edges: Vec<Value> = languages
+                .iter()
+                .map(|(name, size)| json!({ "size": size, "node": { "name": name } }))
+                .collect();
+            json!({ "languages": { "edges": edges } })
+        };
+        let transport = MockTransport::new().respond(
+            "top_languages",
+            json!({ "data": { "user": { "repositories": {
+            "nodes": [
+                repo(&[("Rust", 400), ("CSS", 5000), ("Shell", 50)]),
+                repo(&[("Go", 300), ("Rust", 200), ("Python", 100)]),
+            ],
+            "pageInfo": { "endCursor": null, "hasNextPage": false },
+        } } } }),
+        );
+
+        let languages = top_languages(&transport, "octo", &["css".to_string()], Some(3))
+            .await
+            .unwrap();
+        let values = svg_values(
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(&languages),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        assert_eq!(
+            languages,
+            vec![
+                ("Rust".to_string(), 600),
+                ("Go".to_string(), 300),
+                ("Python".to_string(), 100),
+            ]
+        );
+        assert_eq!(values["lang_1"], "Rust 60.0%");
+        assert_eq!(values["lang_3"], "Python 10.0%");
+        assert!(!values.contains_key("lang_4"));
+    }
+
+    #[test]
+    fn topic_filters_keep_only_the_matching_repos() {
+        let tagged = |name: &str, topics: &[&str]| {
+            let mut edge = repo_edge(name, 1, 0);
+            edge["node"]["repositoryTopics"]["nodes"] = topics
+                .iter()
+                .map(|topic| json!({ "topic": { "name": topic } }))
+                .collect();
+            edge
+        };
+        let edges = [
+            tagged("octo/site", &["Showcase", "web"]),
+            tagged("octo/demo", &["showcase", "archived-demo"]),
+            tagged("octo/dotfiles", &[]),
+        ];
+        let kept = |include: &[&str], exclude: &[&str]| -> Vec<&str> {
+            let include: Vec<String> = include.iter().map(|topic| topic.to_string()).collect();
+            let exclude: Vec<String> = exclude.iter().map(|topic| topic.to_string()).collect();
+            edges
+                .iter()
+                .filter(|edge| topics_match(edge, &include, &exclude))
+                .map(|edge| edge["node"]["nameWithOwner"].as_str().unwrap())
+                .collect()
+        };
+
+        assert_eq!(kept(&[], &[]), ["octo/site", "octo/demo", "octo/dotfiles"]);
+        assert_eq!(kept(&["showcase"], &[]), ["octo/site", "octo/demo"]);
+        assert_eq!(kept(&["showcase"], &[" archived-demo "]), ["octo/site"]);
+        assert_eq!(kept(&[], &["WEB"]), ["octo/demo", "octo/dotfiles"]);
+    }
+
+    #[tokio::test]
+    async fn loc_edges_stops_paginating_at_the_cap() {
+        let transport = MockTransport::new()
+            .respond_to(
+                "loc_query",
+                json!({ "cursor": null }),
+                repos_page(
+                    vec![repo_edge("octo/a", 0, 0), repo_edge("octo/b", 0, 0)],
+                    Some("page-1"),
+                ),
+            )
+            .respond_to(
+                "loc_query",
+                json!({ "cursor": "page-1" }),
+                repos_page(vec![repo_edge("octo/c", 0, 0)], Some("page-2")),
+            );
+
+        let (edges, truncated) = loc_edges(&transport, &["OWNER".to_string()], 2, Some(3), "octo")
+            .await
+            .unwrap();
+
+        assert_eq!(edges.len(), 3);
+        assert!(truncated);
+        let page_sizes: Vec<Value> = transport
+            .requests()
+            .iter()
+            .map(|(_, variables)| variables["page_size"].clone())
+            .collect();
+        assert_eq!(page_sizes, vec![json!(2), json!(1)]);
+        assert_eq!(Config::try_parse_from(["test"]).unwrap().max_repos, None);
+    }
+
+    #[tokio::test]
+    async fn verify_cache_reports_a_wrong_commit_count() {
+        fs::create_dir_all(&*CACHE_DIR).unwrap();
+        let entry = |name: &str, commits| CachedRepo {
+            hash: name_hash(name),
+            commits,
+            my_commits: 2,
+            loc_add: 20,
+            loc_del: 5,
+        };
+        let mut cache = LocCache::new(1);
+        cache.repos = vec![entry("octo/one", 3), entry("octo/two", 5)];
+        cache.save("verified").unwrap();
+        let transport = MockTransport::new().respond_always(
+            "loc_query",
+            json!({}),
+            repos_page(vec![loc_edge("octo/one", 3), loc_edge("octo/two", 7)], None),
+        );
+        let verify = || {
+            verify_cache(
+                &transport,
+                vec!["OWNER".to_string()],
+                1,
+                false,
+                false,
+                LOC_PAGE_SIZE,
+                &["verified"],
+            )
+        };
+
+        assert_eq!(
+            verify().await.unwrap(),
+            vec![
+                "octo/two: 5 commits cached, GitHub has 7",
+                "8 commits cached in total, GitHub has 10",
+            ]
+        );
+
+        cache.repos[1].commits = 7;
+        cache.save("verified").unwrap();
+        assert!(verify().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn template_edits_to_labels_reach_the_output() {
+        let previous = r#"<svg><text><tspan id="repos_label">Repos:</tspan> <tspan id="repos">4</tspan> <tspan id="lang_1">Rust 90.0%</tspan></text></svg>"#;
+        let template = r#"<svg><text><tspan id="repos_label">Repositories:</tspan> <tspan id="repos">0</tspan> <tspan id="lang_1">-</tspan></text></svg>"#;
+
+        let rendered = render_svg(template, Some(previous), &HashMap::new()).unwrap();
+
+        assert!(rendered.contains(r#"<tspan id="repos_label">Repositories:</tspan>"#));
+        assert!(rendered.contains(r#"<tspan id="repos">4</tspan>"#));
+        assert!(rendered.contains(r#"<tspan id="lang_1">Rust 90.0%</tspan>"#));
+    }
+
+    #[test]
+    fn stat_changes_render_into_the_diff_tspans() {
+        let last = StatsSnapshot {
+            stars: Some(10),
+            commits: Some(1200),
+            repos: Some(5),
+            ..Default::default()
+        };
+        let current = StatsSnapshot {
+            stars: Some(12),
+            commits: Some(190),
+            repos: Some(5),
+            watchers: Some(3),
+            ..Default::default()
+        };
+        let diffs = diff_values(&last, &current).unwrap();
+        assert_eq!(
+            diffs,
+            HashMap::from([
+                ("stars_diff", "+2".to_string()),
+                ("commits_diff", "-1,010".to_string()),
+            ])
+        );
+
+        let text = r#"<text><tspan id="stars">12</tspan><tspan id="stars_diff"></tspan><tspan id="commits">190</tspan><tspan id="commits_diff" class="delColor"></tspan></text>"#;
+        let muted = format!(
+            "<svg><style>.commentColor {{ fill: #8b949e; }}</style>{}</svg>",
+            text
+        );
+        let rendered = render_svg(&muted, None, &diffs).unwrap();
+        assert!(rendered.contains(r#"<tspan id="stars_diff" class="commentColor">+2</tspan>"#));
+        assert!(rendered.contains(r#"<tspan id="commits_diff" class="delColor">-1,010</tspan>"#));
+
+        let plain = render_svg(&format!("<svg>{}</svg>", text), None, &diffs).unwrap();
+        assert!(plain.contains(r#"<tspan id="stars_diff">+2</tspan>"#));
+    }
+
+    #[tokio::test]
+    async fn last_active_repo_of_a_missing_user_is_an_error() {
+        let transport =
+            MockTransport::new().respond("last_active_repo", json!({ "data": { "user": null } }));
+
+        assert!(matches!(
+            last_active_repo(&transport, "ghost").await,
+            Err(StatsError::GraphQl(_))
+        ));
+    }
 }
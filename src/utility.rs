@@ -1,16 +1,81 @@
+use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
-use reqwest::blocking::Client;
-use serde_json::{json, Value};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
 use std::collections::HashMap;
-use std::error::Error;
-use std::sync::Mutex;
-use std::time::Instant;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    Mutex,
+    atomic::{AtomicUsize, Ordering},
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::exports::get_auth_headers;
+use crate::error::StatsError;
+use crate::exports::{CONFIG, access_token, get_auth_headers};
 
 pub static QUERY_COUNT: Lazy<Mutex<HashMap<String, usize>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+// One client for every request, so connections to GitHub are reused. It goes
+// through `--proxy` if given, and HTTPS_PROXY/HTTP_PROXY otherwise.
+pub static CLIENT: Lazy<Client> = Lazy::new(|| {
+    // `--proxy` was validated when parsed
+    build_client(
+        Duration::from_secs(CONFIG.request_timeout_secs),
+        CONFIG.proxy.as_deref(),
+    )
+    .expect("Failed to build the HTTP client")
+});
+
+/// An HTTP client that gives requests up after `timeout`, going through
+/// `proxy` if given.
+fn build_client(timeout: Duration, proxy: Option<&str>) -> reqwest::Result<Client> {
+    let mut builder = Client::builder().timeout(timeout);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(reqwest::NoProxy::from_env()));
+    }
+
+    builder.build()
+}
+
+// Rate-limit budget reported by the most recent GitHub response
+pub static RATE_LIMIT: Lazy<Mutex<Option<RateLimitInfo>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitInfo {
+    pub limit: u64,
+    pub remaining: u64,
+    pub used: u64,
+    // Unix time (seconds) at which the budget resets
+    pub reset: u64,
+}
+
+/// The last rate-limit values seen on a response, if any.
+pub fn rate_limit_info() -> Option<RateLimitInfo> {
+    *RATE_LIMIT.lock().unwrap()
+}
+
+/// Store the `X-RateLimit-*` headers of a response in `RATE_LIMIT`.
+fn record_rate_limit(response: &Response) {
+    let header = |name: &str| header_u64(response, name);
+
+    if let Some(remaining) = header("x-ratelimit-remaining") {
+        *RATE_LIMIT.lock().unwrap() = Some(RateLimitInfo {
+            limit: header("x-ratelimit-limit").unwrap_or(0),
+            remaining,
+            used: header("x-ratelimit-used").unwrap_or(0),
+            reset: header("x-ratelimit-reset").unwrap_or(0),
+        });
+    }
+}
+
+// Repositories whose LOC came from the cache, and those that were recounted
+pub static LOC_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+pub static LOC_CACHE_MISSES: AtomicUsize = AtomicUsize::new(0);
+
 pub fn query_count(func_id: &str) {
     let mut count = QUERY_COUNT.lock().unwrap();
     let entry = count.entry(func_id.to_string()).or_insert(0);
@@ -27,37 +92,565 @@ where
     (result, duration)
 }
 
+/// `perf_counter` for a fallible function. The elapsed time is returned
+/// whether or not it succeeded.
+pub fn perf_counter_try<F, T, E>(func: F) -> (Result<T, E>, f64)
+where
+    F: FnOnce() -> Result<T, E>,
+{
+    perf_counter(func)
+}
+
+/// `perf_counter` for a future: awaits it and times how long that took.
+pub async fn perf_counter_async<F>(future: F) -> (F::Output, f64)
+where
+    F: Future,
+{
+    let start = Instant::now();
+    let result = future.await;
+    let duration = start.elapsed().as_secs_f64();
+    (result, duration)
+}
+
+/// Whether the timing table and query counts are printed, i.e. with
+/// `--format table` unless `--quiet` is given.
+pub fn show_timings() -> bool {
+    CONFIG.has_format("table") && !CONFIG.quiet
+}
+
+/// Print the header of the timing table.
+pub fn print_table_header() {
+    println!("Calculation times:");
+}
+
+/// Print the total time of the queries below the timing table.
+pub fn print_total_time(total: f64) {
+    if show_timings() {
+        println!(
+            "{:<23}{:>12}",
+            "Total function time:",
+            humanize_duration(total)
+        );
+    }
+}
+
+/// A duration in seconds in the largest of s, ms, µs and ns that keeps it
+/// at 1 or more, e.g. `1.5000 s`, `12.3400 ms` or `250.0000 ns`.
+pub fn humanize_duration(secs: f64) -> String {
+    let (value, unit) = if secs >= 1.0 {
+        (secs, "s")
+    } else if secs >= 1e-3 {
+        (secs * 1e3, "ms")
+    } else if secs >= 1e-6 {
+        (secs * 1e6, "µs")
+    } else {
+        (secs * 1e9, "ns")
+    };
+
+    format!("{:.4} {}", value, unit)
+}
+
 pub fn formatter(
     query_type: &str,
     duration: f64,
     funct_return: Option<usize>,
     whitespace: usize,
 ) -> Option<String> {
-    print!("{:<23}", format!("   {}:", query_type));
-
-    if duration > 1.0 {
-        println!("{:>12}", format!("{:.4} s", duration));
-    } else {
-        println!("{:>12}", format!("{:.4} ms", duration * 1000.0));
+    if show_timings() {
+        println!(
+            "{:<23}{:>12}",
+            format!("   {}:", query_type),
+            humanize_duration(duration)
+        );
     }
 
-    if let Some(value) = funct_return {
-        Some(format!(
+    funct_return.map(|value| {
+        format!(
             "{:>width$}",
-            format!("{:}", value),
+            format_with_commas(value as i64),
             width = whitespace
-        ))
+        )
+    })
+}
+
+/// Group the digits of a number in threes with commas, e.g. `1,234,567`.
+pub fn format_with_commas(n: i64) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3 + 1);
+    if n < 0 {
+        grouped.push('-');
+    }
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Abbreviate a number for badges, e.g. `1.2k` for 1200 and `3.4M` for
+/// 3,400,000. Numbers below 1000 are shown as they are.
+pub fn humanize_count(n: i64) -> String {
+    let abs = n.unsigned_abs() as f64;
+    // 999,950 would round up to "1000.0k", so it is already shown in millions
+    let (value, suffix) = if abs >= 999_950.0 {
+        (abs / 1_000_000.0, "M")
+    } else if abs >= 1000.0 {
+        (abs / 1000.0, "k")
     } else {
-        None
+        return n.to_string();
+    };
+
+    let value = format!("{:.1}", value);
+    format!(
+        "{}{}{}",
+        if n < 0 { "-" } else { "" },
+        value.strip_suffix(".0").unwrap_or(&value),
+        suffix
+    )
+}
+
+/// Describe how long ago an ISO 8601 UTC timestamp (`2024-01-31T12:00:00Z`)
+/// was, e.g. "2 days ago". Returns `None` if the timestamp can't be parsed.
+pub fn relative_time(timestamp: &str, now: DateTime<Utc>) -> Option<String> {
+    let then = DateTime::parse_from_rfc3339(timestamp).ok()?;
+    let elapsed = now.signed_duration_since(then.with_timezone(&Utc));
+
+    let (count, unit) = match elapsed.num_days() {
+        days @ 365.. => (days / 365, "year"),
+        days @ 30.. => (days / 30, "month"),
+        days @ 1.. => (days, "day"),
+        _ if elapsed.num_hours() >= 1 => (elapsed.num_hours(), "hour"),
+        _ if elapsed.num_minutes() >= 1 => (elapsed.num_minutes(), "minute"),
+        _ => return Some("just now".to_string()),
+    };
+
+    Some(format!(
+        "{} {}{} ago",
+        count,
+        unit,
+        if count == 1 { "" } else { "s" }
+    ))
+}
+
+/// Whole days between an ISO 8601 timestamp (`2024-01-31T12:00:00Z`) and
+/// `now`. Returns `None` if the timestamp can't be parsed.
+pub fn account_age_days(created_at: &str, now: DateTime<Utc>) -> Option<i64> {
+    let created_at = DateTime::parse_from_rfc3339(created_at).ok()?;
+    Some((now - created_at.with_timezone(&Utc)).num_days())
+}
+
+/// Fractional years between an ISO 8601 timestamp and `now`, counting a
+/// year as 365.2425 days so leap years average out.
+pub fn account_age_years(created_at: &str, now: DateTime<Utc>) -> Option<f64> {
+    let created_at = DateTime::parse_from_rfc3339(created_at).ok()?;
+    let seconds = (now - created_at.with_timezone(&Utc)).num_seconds();
+    Some(seconds as f64 / (365.2425 * 86400.0))
+}
+
+/// Lines of code the user added and deleted. `net` (added minus deleted) is
+/// what the cards show as "lines of code"; `total` (added plus deleted) is
+/// every line the user touched.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LocStats {
+    pub added: i32,
+    pub deleted: i32,
+    pub net: i32,
+    pub total: i32,
+    // How net LOC moved compared to the previous runs, once there are any
+    pub trend: Option<LocTrend>,
+}
+
+impl LocStats {
+    pub fn new(added: i32, deleted: i32) -> Self {
+        LocStats {
+            added,
+            deleted,
+            net: added - deleted,
+            total: added + deleted,
+            trend: None,
+        }
     }
 }
 
-pub fn simple_request(
+// Weight of the newest run in the LOC moving average
+pub const LOC_EMA_ALPHA: f64 = 0.2;
+
+/// Net LOC of the latest run compared to the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocTrend {
+    // Change since the previous run
+    pub delta: i64,
+    // Exponential moving average over all runs, oldest first
+    pub ema: f64,
+}
+
+/// The trend of a history of net LOC values, oldest first. `None` until
+/// there are at least two runs to compare.
+pub fn loc_trend(history: &[i64], alpha: f64) -> Option<LocTrend> {
+    let [.., previous, latest] = history else {
+        return None;
+    };
+
+    let ema = history[1..].iter().fold(history[0] as f64, |ema, &loc| {
+        alpha * loc as f64 + (1.0 - alpha) * ema
+    });
+
+    Some(LocTrend {
+        delta: latest - previous,
+        ema,
+    })
+}
+
+/// Current and longest runs of consecutive days with contributions, with
+/// their first and last `YYYY-MM-DD` dates.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreakInfo {
+    pub current: usize,
+    pub current_range: Option<(String, String)>,
+    pub longest: usize,
+    pub longest_range: Option<(String, String)>,
+}
+
+/// Compute streaks from a contribution calendar given as consecutive
+/// `(date, contribution_count)` days, oldest first. The current streak is
+/// the one ending on the last day, or the day before since today may not
+/// have any contributions yet.
+pub fn compute_streaks(days: &[(String, u64)]) -> StreakInfo {
+    let mut info = StreakInfo::default();
+    let mut run_start = 0;
+
+    for (index, (_, count)) in days.iter().enumerate() {
+        if *count == 0 {
+            run_start = index + 1;
+            continue;
+        }
+
+        let length = index + 1 - run_start;
+        if length > info.longest {
+            info.longest = length;
+            info.longest_range = Some((days[run_start].0.clone(), days[index].0.clone()));
+        }
+    }
+
+    // Walk back from the last day, allowing it to be empty
+    let mut end = days.len();
+    if days.last().is_some_and(|(_, count)| *count == 0) {
+        end -= 1;
+    }
+    let start = days[..end]
+        .iter()
+        .rposition(|(_, count)| *count == 0)
+        .map_or(0, |index| index + 1);
+
+    if end > start {
+        info.current = end - start;
+        info.current_range = Some((days[start].0.clone(), days[end - 1].0.clone()));
+    }
+
+    info
+}
+
+/// Print a timing-table row for a query that was not run.
+pub fn formatter_skipped(query_type: &str) {
+    if !show_timings() {
+        return;
+    }
+    println!("{:<23}{:>12}", format!("   {}:", query_type), "skipped");
+}
+
+// Retries on GitHub's secondary rate limits, see `send_with_retry`
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+pub async fn simple_request(
     func_name: &str,
     query: &str,
     variables: Value,
-) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
-    let client = Client::new();
+) -> Result<Response, StatsError> {
+    simple_request_with_retry(
+        func_name,
+        query,
+        variables,
+        DEFAULT_MAX_RETRIES,
+        DEFAULT_RETRY_BASE_DELAY,
+    )
+    .await
+}
+
+/// Fail with the messages of a non-empty `errors` array in a GraphQL response.
+pub fn check_graphql_errors(func_name: &str, json: &Value) -> Result<(), StatsError> {
+    let Some(errors) = json["errors"]
+        .as_array()
+        .filter(|errors| !errors.is_empty())
+    else {
+        return Ok(());
+    };
+
+    let messages: Vec<&str> = errors
+        .iter()
+        .map(|error| error["message"].as_str().unwrap_or("unknown error"))
+        .collect();
+    Err(StatsError::GraphQl(format!(
+        "{} returned errors: {}",
+        func_name,
+        messages.join("; ")
+    )))
+}
+
+/// The value at a `/`-separated path of a GraphQL response, e.g.
+/// `data/user/repositories/totalCount`. Fails naming the part of the path
+/// that is missing or null, so a change in the response shape doesn't
+/// silently turn into zeros.
+pub fn require_path<'a>(json: &'a Value, path: &str) -> Result<&'a Value, StatsError> {
+    let mut value = json;
+    let mut walked = Vec::new();
+    for key in path.split('/') {
+        walked.push(key);
+        value = match value.get(key) {
+            Some(next) if !next.is_null() => next,
+            _ => {
+                return Err(StatsError::GraphQl(format!(
+                    "response has no {} (looking for {})",
+                    walked.join("/"),
+                    path
+                )));
+            }
+        };
+    }
+
+    Ok(value)
+}
+
+/// Decode a GraphQL response, failing if it carries an `errors` array. GitHub
+/// reports bad queries and partial failures that way, with status 200.
+pub async fn graphql_json(func_name: &str, response: Response) -> Result<Value, StatsError> {
+    let json: Value = response.json().await?;
+    check_graphql_errors(func_name, &json)?;
+    Ok(json)
+}
+
+/// Sends a GraphQL query and returns the decoded response. The query
+/// functions go through this, so they can run against something other than
+/// the GitHub API.
+pub trait GraphQlTransport {
+    /// The decoded response, `errors` array and all.
+    fn post_raw(
+        &self,
+        func_name: &str,
+        query: &str,
+        variables: &Value,
+    ) -> impl Future<Output = Result<Value, StatsError>>;
+
+    /// The decoded response, failing if it carries an `errors` array.
+    fn post(
+        &self,
+        func_name: &str,
+        query: &str,
+        variables: &Value,
+    ) -> impl Future<Output = Result<Value, StatsError>> {
+        async move {
+            let json = self.post_raw(func_name, query, variables).await?;
+            check_graphql_errors(func_name, &json)?;
+            Ok(json)
+        }
+    }
+
+    /// The scopes granted to the token, from the `X-OAuth-Scopes` header of a
+    /// minimal query. `None` for tokens that don't report any, like
+    /// fine-grained ones.
+    fn token_scopes(
+        &self,
+        func_name: &str,
+    ) -> impl Future<Output = Result<Option<String>, StatsError>>;
+}
+
+/// The GitHub GraphQL API, through `simple_request`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GitHubTransport;
+
+impl GraphQlTransport for GitHubTransport {
+    async fn post_raw(
+        &self,
+        func_name: &str,
+        query: &str,
+        variables: &Value,
+    ) -> Result<Value, StatsError> {
+        let response = simple_request(func_name, query, variables.clone()).await?;
+        Ok(response.json().await?)
+    }
+
+    async fn post(
+        &self,
+        func_name: &str,
+        query: &str,
+        variables: &Value,
+    ) -> Result<Value, StatsError> {
+        let response = simple_request(func_name, query, variables.clone()).await?;
+        graphql_json(func_name, response).await
+    }
+
+    async fn token_scopes(&self, func_name: &str) -> Result<Option<String>, StatsError> {
+        let response = simple_request(func_name, "query { viewer { login } }", json!({})).await?;
+        Ok(response
+            .headers()
+            .get("x-oauth-scopes")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from))
+    }
+}
+
+// Held by tests that count LOC or write the owner cache, as they share the
+// cache hit and miss counters and `owner.json`
+#[cfg(test)]
+pub static LOC_TEST_LOCK: futures::lock::Mutex<()> = futures::lock::Mutex::new(());
+
+/// Canned GraphQL responses for tests. Each query gets the first response
+/// queued for its function name whose variables it matches, and every query
+/// is recorded.
+#[cfg(test)]
+#[derive(Debug)]
+struct MockResponse {
+    func_name: String,
+    variables: Value,
+    response: Result<Value, StatsError>,
+    // Answers every matching query rather than only the next one
+    repeat: bool,
+}
+
+#[cfg(test)]
+impl MockResponse {
+    fn matches(&self, func_name: &str, variables: &Value) -> bool {
+        self.func_name == func_name
+            && self.variables.as_object().is_none_or(|wanted| {
+                wanted
+                    .iter()
+                    .all(|(key, value)| variables.get(key) == Some(value))
+            })
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    responses: Mutex<Vec<MockResponse>>,
+    requests: Mutex<Vec<(String, Value)>>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue `response` for the next query of `func_name`.
+    pub fn respond(self, func_name: &str, response: Value) -> Self {
+        self.respond_to(func_name, json!({}), response)
+    }
+
+    /// Queue `response` for the next query of `func_name` whose variables
+    /// include all of `variables`.
+    pub fn respond_to(self, func_name: &str, variables: Value, response: Value) -> Self {
+        self.push(func_name, variables, Ok(response), false)
+    }
+
+    /// Answer every query of `func_name` whose variables include all of
+    /// `variables` with `response`.
+    pub fn respond_always(self, func_name: &str, variables: Value, response: Value) -> Self {
+        self.push(func_name, variables, Ok(response), true)
+    }
+
+    /// Queue a failure for the next query of `func_name`.
+    pub fn fail(self, func_name: &str, err: StatsError) -> Self {
+        self.push(func_name, json!({}), Err(err), false)
+    }
+
+    fn push(
+        self,
+        func_name: &str,
+        variables: Value,
+        response: Result<Value, StatsError>,
+        repeat: bool,
+    ) -> Self {
+        self.responses.lock().unwrap().push(MockResponse {
+            func_name: func_name.to_string(),
+            variables,
+            response,
+            repeat,
+        });
+        self
+    }
+
+    /// The function name and variables of every query so far.
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// How many queries of `func_name` were sent.
+    pub fn count(&self, func_name: &str) -> usize {
+        self.requests()
+            .iter()
+            .filter(|(name, _)| name == func_name)
+            .count()
+    }
+}
+
+#[cfg(test)]
+impl GraphQlTransport for MockTransport {
+    async fn post_raw(
+        &self,
+        func_name: &str,
+        _query: &str,
+        variables: &Value,
+    ) -> Result<Value, StatsError> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push((func_name.to_string(), variables.clone()));
+
+        let mut responses = self.responses.lock().unwrap();
+        let position = responses
+            .iter()
+            .position(|response| response.matches(func_name, variables))
+            .unwrap_or_else(|| {
+                panic!(
+                    "MockTransport has no response for {} with {}",
+                    func_name, variables
+                )
+            });
+        match &responses[position] {
+            MockResponse {
+                repeat: true,
+                response: Ok(response),
+                ..
+            } => Ok(response.clone()),
+            _ => responses.remove(position).response,
+        }
+    }
+
+    // The scopes are queued like a response, as a string or as null
+    async fn token_scopes(&self, func_name: &str) -> Result<Option<String>, StatsError> {
+        let scopes = self.post_raw(func_name, "", &json!({})).await?;
+        Ok(scopes.as_str().map(String::from))
+    }
+}
+
+pub async fn simple_request_with_retry(
+    func_name: &str,
+    query: &str,
+    variables: Value,
+    max_retries: u32,
+    base_delay: Duration,
+) -> Result<Response, StatsError> {
+    if CONFIG.offline {
+        return Err(StatsError::Invalid(format!(
+            "{} needs GitHub, which --offline doesn't talk to",
+            func_name
+        )));
+    }
+
     let url = "https://api.github.com/graphql";
 
     let payload = json!({
@@ -65,17 +658,972 @@ pub fn simple_request(
         "variables": variables,
     });
 
-    let headers = get_auth_headers();
+    let headers = get_auth_headers()?;
 
-    let response = client
-        .post(url)
-        .headers(headers.clone())
-        .json(&payload)
-        .send()?;
+    let response = send_with_retry(
+        || {
+            CLIENT
+                .post(url)
+                .headers(headers.clone())
+                .json(&payload)
+                .send()
+        },
+        max_retries,
+        base_delay,
+    )
+    .await?;
 
     if response.status().is_success() {
         Ok(response)
     } else {
-        Err(format!("{} failed with status {}", func_name, response.status()).into())
+        Err(status_error(func_name, response.status()))
+    }
+}
+
+/// The error for a GraphQL request GitHub answered with a failing `status`.
+fn status_error(func_name: &str, status: StatusCode) -> StatsError {
+    let message = format!("{} failed with status {}", func_name, status);
+    match status {
+        StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS => StatsError::RateLimited(message),
+        // GitHub's GraphQL API can't be used without a token
+        StatusCode::UNAUTHORIZED if access_token().ok().flatten().is_none() => {
+            StatsError::MissingEnv(
+                "ACCESS_TOKEN (GitHub's GraphQL API needs a token, pass --token or set it)"
+                    .to_string(),
+            )
+        }
+        StatusCode::UNAUTHORIZED => StatsError::GraphQl(format!(
+            "{}: ACCESS_TOKEN was rejected, it may be expired or revoked",
+            message
+        )),
+        _ => StatsError::GraphQl(message),
+    }
+}
+
+/// Send a request, retrying up to `max_retries` times when GitHub answers 403
+/// or 429 with a `Retry-After` or `X-RateLimit-Reset` header. Each retry waits
+/// for the indicated time or `base_delay * 2^attempt`, whichever is longer,
+/// capped at a minute. Other responses are returned as they are.
+pub async fn send_with_retry<F, Fut>(
+    send: F,
+    max_retries: u32,
+    base_delay: Duration,
+) -> reqwest::Result<Response>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = reqwest::Result<Response>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        let response = send().await?;
+        record_rate_limit(&response);
+        let status = response.status();
+
+        if !matches!(
+            status,
+            StatusCode::FORBIDDEN | StatusCode::TOO_MANY_REQUESTS
+        ) || attempt >= max_retries
+        {
+            return Ok(response);
+        }
+
+        let Some(indicated) = rate_limit_delay(&response) else {
+            return Ok(response);
+        };
+
+        let delay = indicated
+            .max(base_delay * 2u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY);
+        log::warn!(
+            "Rate limited ({}), retrying in {} s ({}/{})",
+            status,
+            delay.as_secs(),
+            attempt + 1,
+            max_retries
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// A numeric response header, if present and valid.
+fn header_u64(response: &Response, name: &str) -> Option<u64> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+}
+
+/// How long a rate-limited response asks us to wait, if it says.
+fn rate_limit_delay(response: &Response) -> Option<Duration> {
+    let header = |name: &str| header_u64(response, name);
+
+    if let Some(seconds) = header("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let reset = header("x-ratelimit-reset")?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(Duration::from_secs(reset.saturating_sub(now)))
+}
+
+/// Write `contents` to `path` by way of a `.tmp` sibling that is renamed into
+/// place, so a failure part way through leaves the previous file intact.
+pub fn write_atomic(path: impl AsRef<Path>, contents: &str) -> Result<(), StatsError> {
+    let path = path.as_ref();
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    Ok(result?)
+}
+
+/// Write a Shields.io endpoint file, which a badge can point at with
+/// `https://img.shields.io/endpoint?url=...`.
+pub fn write_shields_endpoint(
+    path: impl AsRef<Path>,
+    label: &str,
+    message: &str,
+    color: &str,
+) -> Result<(), StatsError> {
+    let endpoint = json!({
+        "schemaVersion": 1,
+        "label": label,
+        "message": message,
+        "color": color,
+    });
+
+    write_atomic(path, &serde_json::to_string_pretty(&endpoint)?)
+}
+
+/// Write the stars, commits and LOC endpoint files into `dir`. Stats that
+/// weren't queried this run keep their previous file.
+pub fn write_shields_endpoints(dir: &Path, stats: &StatsSnapshot) -> Result<(), StatsError> {
+    fs::create_dir_all(dir)?;
+
+    let badges = [
+        ("stars", stats.stars.map(|stars| stars as i64)),
+        ("commits", stats.commits.map(|commits| commits as i64)),
+        ("loc", stats.loc_net.map(i64::from)),
+    ];
+    for (label, value) in badges {
+        if let Some(value) = value {
+            write_shields_endpoint(
+                dir.join(format!("{}.json", label)),
+                label,
+                &humanize_count(value),
+                "blue",
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// Markers around the block of stats `update_readme` rewrites
+pub const README_START: &str = "<!--START_STATS-->";
+pub const README_END: &str = "<!--END_STATS-->";
+
+/// Write the stats as a table between the stats markers of the README at
+/// `path`, leaving everything outside them as it is. Fails if the markers
+/// are missing, as there is no telling where the stats should go.
+pub fn update_readme(path: &Path, stats: &StatsSnapshot) -> Result<(), StatsError> {
+    let contents = fs::read_to_string(path)?;
+    let updated = replace_stats_block(&contents, &readme_block(stats)).ok_or_else(|| {
+        StatsError::Invalid(format!(
+            "{} has no {} and {} markers to write the stats between",
+            path.display(),
+            README_START,
+            README_END
+        ))
+    })?;
+
+    if updated != contents {
+        write_atomic(path, &updated)?;
+    }
+    Ok(())
+}
+
+/// `contents` with what is between the stats markers replaced by `block`,
+/// or `None` if they are missing or out of order.
+fn replace_stats_block(contents: &str, block: &str) -> Option<String> {
+    let start = contents.find(README_START)? + README_START.len();
+    let end = start + contents[start..].find(README_END)?;
+
+    Some(format!(
+        "{}\n{}{}",
+        &contents[..start],
+        block,
+        &contents[end..]
+    ))
+}
+
+/// A Markdown table of the stats that were queried this run.
+fn readme_block(stats: &StatsSnapshot) -> String {
+    let count = |value: Option<u64>| value.map(|value| format_with_commas(value as i64));
+    let loc = stats.loc_net.map(|net| {
+        format!(
+            "{} (+{}, -{})",
+            format_with_commas(net.into()),
+            format_with_commas(stats.loc_added.unwrap_or(0).into()),
+            format_with_commas(stats.loc_deleted.unwrap_or(0).into())
+        )
+    });
+
+    let rows = [
+        ("Commits", count(stats.commits.map(|n| n as u64))),
+        ("Stars", count(stats.stars.map(|n| n as u64))),
+        ("Repositories", count(stats.repos.map(|n| n as u64))),
+        (
+            "Contributed to",
+            count(stats.contributed_repos.map(|n| n as u64)),
+        ),
+        ("Contributions", count(stats.contributions)),
+        ("Issues", count(stats.issues)),
+        ("Pull requests", count(stats.prs)),
+        ("Lines of code", loc),
+        ("Watchers", count(stats.watchers.map(|n| n as u64))),
+        ("Sponsors", count(stats.sponsors)),
+    ];
+
+    let mut block = String::from("| Stat | Value |\n| --- | ---: |\n");
+    for (label, value) in rows {
+        if let Some(value) = value {
+            block.push_str(&format!("| {} | {} |\n", label, value));
+        }
+    }
+    block
+}
+
+/// What a run did, for `--report-file`. It is filled in as the run goes, so
+/// a failed run reports how far it got.
+#[derive(Debug, Default)]
+pub struct RunReport {
+    // Time and outcome of each stat: "ok", "failed" or "skipped"
+    stats: Vec<(String, f64, &'static str)>,
+    pub loc_cached: Option<bool>,
+    pub svg_changed: Vec<(String, bool)>,
+    pub error: Option<String>,
+}
+
+impl RunReport {
+    /// Record how long a stat took and whether it succeeded, `None` if it
+    /// wasn't run.
+    pub fn record(&mut self, stat: &str, time: f64, succeeded: Option<bool>) {
+        let status = match succeeded {
+            Some(true) => "ok",
+            Some(false) => "failed",
+            None => "skipped",
+        };
+        self.stats.push((stat.to_string(), time, status));
+    }
+}
+
+/// Write a JSON summary of the run (timings and outcome of each stat, query
+/// counts, cache, rate-limit and SVG status, and the error it failed with)
+/// for CI to upload.
+pub fn write_run_report(path: impl AsRef<Path>, report: &RunReport) -> Result<(), StatsError> {
+    let query_count = QUERY_COUNT.lock().unwrap().clone();
+    let rate_limit = rate_limit_info().map(|info| {
+        json!({
+            "limit": info.limit,
+            "remaining": info.remaining,
+            "used": info.used,
+            "reset": info.reset,
+        })
+    });
+
+    let json = json!({
+        "timings": report
+            .stats
+            .iter()
+            .map(|(name, time, _)| (name.clone(), json!(time)))
+            .collect::<serde_json::Map<_, _>>(),
+        "status": report
+            .stats
+            .iter()
+            .map(|(name, _, status)| (name.clone(), json!(status)))
+            .collect::<serde_json::Map<_, _>>(),
+        "query_count": query_count,
+        "loc_cached": report.loc_cached,
+        "cache": {
+            "hits": LOC_CACHE_HITS.load(Ordering::Relaxed),
+            "misses": LOC_CACHE_MISSES.load(Ordering::Relaxed),
+        },
+        "rate_limit": rate_limit,
+        "svg_changed": report
+            .svg_changed
+            .iter()
+            .map(|(name, changed)| (name.clone(), json!(changed)))
+            .collect::<serde_json::Map<_, _>>(),
+        "error": report.error,
+    });
+
+    fs::write(path, serde_json::to_string_pretty(&json)?)?;
+
+    Ok(())
+}
+
+/// The numbers shown on the cards. Stats that weren't queried this run are
+/// `None` and serialize as `null`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub commits: Option<usize>,
+    pub stars: Option<usize>,
+    pub repos: Option<usize>,
+    pub contributed_repos: Option<usize>,
+    pub issues: Option<u64>,
+    pub prs: Option<u64>,
+    pub prs_merged: Option<u64>,
+    pub prs_reviewed: Option<u64>,
+    pub issues_closed: Option<u64>,
+    pub loc_added: Option<i32>,
+    pub loc_deleted: Option<i32>,
+    pub loc_net: Option<i32>,
+    pub contributions: Option<u64>,
+    pub watchers: Option<usize>,
+    pub sponsors: Option<u64>,
+    pub repos_public: Option<usize>,
+    pub repos_private: Option<usize>,
+    pub pinned_stars: Option<u64>,
+}
+
+/// POST the stats as JSON to `url`, with `auth` as the Authorization header
+/// if given. Connection failures, timeouts and 408, 429 and 5xx answers are
+/// retried up to `max_retries` times, waiting `base_delay * 2^attempt`.
+pub async fn post_stats(
+    url: &str,
+    auth: Option<&str>,
+    stats: &StatsSnapshot,
+    max_retries: u32,
+    base_delay: Duration,
+) -> Result<(), StatsError> {
+    let mut attempt = 0;
+
+    loop {
+        let mut request = CLIENT
+            .post(url)
+            .header(reqwest::header::USER_AGENT, &CONFIG.user_agent)
+            .json(stats);
+        if let Some(auth) = auth {
+            request = request.header(reqwest::header::AUTHORIZATION, auth);
+        }
+
+        let transient = match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                if !(status.is_server_error()
+                    || matches!(
+                        status,
+                        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                    ))
+                    || attempt >= max_retries
+                {
+                    response.error_for_status()?;
+                    return Ok(());
+                }
+                status.to_string()
+            }
+            Err(err) if (err.is_connect() || err.is_timeout()) && attempt < max_retries => {
+                err.to_string()
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let delay = (base_delay * 2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY);
+        log::warn!(
+            "Posting the stats to {} failed ({}), retrying in {} s ({}/{})",
+            url,
+            transient,
+            delay.as_secs(),
+            attempt + 1,
+            max_retries
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Write the stats as JSON for integrators that don't want to parse the SVGs.
+pub fn write_stats_json(path: impl AsRef<Path>, stats: &StatsSnapshot) -> Result<(), StatsError> {
+    write_atomic(path, &serde_json::to_string_pretty(stats)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use clap::Parser;
+
+    #[test]
+    fn relative_time_picks_the_largest_whole_unit() {
+        let now = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let ago = |timestamp| relative_time(timestamp, now);
+
+        assert_eq!(ago("2024-03-01T11:59:30Z").as_deref(), Some("just now"));
+        assert_eq!(ago("2024-03-01T11:59:00Z").as_deref(), Some("1 minute ago"));
+        assert_eq!(ago("2024-03-01T09:00:00Z").as_deref(), Some("3 hours ago"));
+        // Across the leap day
+        assert_eq!(ago("2024-02-28T12:00:00Z").as_deref(), Some("2 days ago"));
+        assert_eq!(ago("2023-12-01T12:00:00Z").as_deref(), Some("3 months ago"));
+        assert_eq!(ago("2022-03-01T12:00:00Z").as_deref(), Some("2 years ago"));
+        // Clock skew shouldn't show a negative time
+        assert_eq!(ago("2024-03-01T12:05:00Z").as_deref(), Some("just now"));
+        assert_eq!(ago("yesterday"), None);
+    }
+
+    #[test]
+    fn run_report_records_failures_and_the_error() {
+        let mut report = RunReport::default();
+        report.record("account data", 0.5, Some(true));
+        report.record("LOC", 1.5, Some(false));
+        report.record("commit counter", 0.0, None);
+        report.error = Some("rate limited by GitHub: LOC".to_string());
+
+        let path = CONFIG.cache_dir.join("failed_report.json");
+        fs::create_dir_all(&CONFIG.cache_dir).unwrap();
+        write_run_report(&path, &report).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            written["status"],
+            json!({ "account data": "ok", "LOC": "failed", "commit counter": "skipped" })
+        );
+        assert_eq!(written["timings"]["LOC"], 1.5);
+        assert_eq!(written["error"], "rate limited by GitHub: LOC");
+    }
+
+    #[test]
+    fn write_atomic_reports_write_errors() {
+        let path = CONFIG.cache_dir.join("missing-dir").join("cache.json");
+
+        assert!(matches!(
+            write_atomic(&path, "{}"),
+            Err(StatsError::Cache(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn write_atomic_keeps_the_old_file_when_the_write_fails() {
+        let dir = CONFIG.cache_dir.join("atomic");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.json");
+
+        write_atomic(&path, "old").unwrap();
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!dir.join("cache.json.tmp").exists());
+
+        // A directory in the way of the temporary file fails the write
+        fs::create_dir(dir.join("cache.json.tmp")).unwrap();
+        assert!(write_atomic(&path, "newer").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    // A response with `status` and the given headers, as GitHub would send it
+    fn response(status: u16, headers: &[(&str, &str)]) -> Response {
+        let mut builder = http::Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        Response::from(builder.body("").unwrap())
+    }
+
+    /// Send the `statuses` in turn through `send_with_retry`, returning the
+    /// final status and how many requests went out.
+    async fn retry_statuses(
+        statuses: &[u16],
+        headers: &[(&str, &str)],
+        max_retries: u32,
+    ) -> (u16, usize) {
+        let sent = AtomicUsize::new(0);
+        let final_response = send_with_retry(
+            || {
+                let status = statuses[sent.fetch_add(1, Ordering::SeqCst)];
+                async move { Ok(response(status, headers)) }
+            },
+            max_retries,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+
+        (final_response.status().as_u16(), sent.into_inner())
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_waits_out_rate_limits() {
+        let retry_after = [("retry-after", "0")];
+
+        assert_eq!(
+            retry_statuses(&[403, 429, 200], &retry_after, 3).await,
+            (200, 3)
+        );
+        assert_eq!(
+            retry_statuses(&[429, 429, 200], &retry_after, 1).await,
+            (429, 2)
+        );
+        // A 403 that doesn't say when to retry is a real permission error
+        assert_eq!(retry_statuses(&[403, 200], &[], 3).await, (403, 1));
+        assert_eq!(retry_statuses(&[500, 200], &retry_after, 3).await, (500, 1));
+    }
+
+    #[test]
+    fn rate_limit_headers_are_recorded() {
+        record_rate_limit(&response(
+            200,
+            &[
+                ("x-ratelimit-limit", "5000"),
+                ("x-ratelimit-remaining", "4990"),
+                ("x-ratelimit-used", "10"),
+                ("x-ratelimit-reset", "1700000000"),
+            ],
+        ));
+        // Responses without the headers leave the last values alone
+        record_rate_limit(&response(200, &[]));
+
+        assert_eq!(
+            rate_limit_info(),
+            Some(RateLimitInfo {
+                limit: 5000,
+                remaining: 4990,
+                used: 10,
+                reset: 1700000000,
+            })
+        );
+    }
+
+    #[test]
+    fn failing_statuses_map_to_their_error() {
+        assert!(matches!(
+            status_error("user_getter", StatusCode::FORBIDDEN),
+            StatsError::RateLimited(message) if message == "user_getter failed with status 403 Forbidden"
+        ));
+        assert!(matches!(
+            status_error("user_getter", StatusCode::TOO_MANY_REQUESTS),
+            StatsError::RateLimited(_)
+        ));
+        assert!(matches!(
+            status_error("user_getter", StatusCode::BAD_GATEWAY),
+            StatsError::GraphQl(_)
+        ));
+
+        let config = Config::try_parse_from(["test", "--user", " , "]).unwrap();
+        let err = config.user_names().unwrap_err();
+        assert!(matches!(&err, StatsError::MissingEnv(name) if name == "USER_NAME"));
+        assert_eq!(err.to_string(), "USER_NAME not found");
+    }
+
+    #[test]
+    fn stats_json_has_every_stat_and_nulls_for_skipped_ones() {
+        let path = CONFIG.cache_dir.join("stats.json");
+        fs::create_dir_all(&CONFIG.cache_dir).unwrap();
+        let stats = StatsSnapshot {
+            commits: Some(120),
+            stars: Some(6),
+            loc_added: Some(130),
+            loc_deleted: Some(30),
+            loc_net: Some(100),
+            ..StatsSnapshot::default()
+        };
+
+        write_stats_json(&path, &stats).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(written["commits"], 120);
+        assert_eq!(written["stars"], 6);
+        assert_eq!(written["loc_added"], 130);
+        assert_eq!(written["loc_deleted"], 30);
+        assert_eq!(written["loc_net"], 100);
+        assert_eq!(written["repos"], Value::Null);
+        assert_eq!(written.as_object().unwrap().len(), 18);
+    }
+
+    #[test]
+    fn compute_streaks_allows_today_to_be_empty() {
+        let dates = [
+            "2024-02-27",
+            "2024-02-28",
+            "2024-02-29",
+            "2024-03-01",
+            "2024-03-02",
+            "2024-03-03",
+        ];
+        let calendar = |counts: &[u64]| -> Vec<(String, u64)> {
+            dates
+                .iter()
+                .zip(counts)
+                .map(|(date, count)| (date.to_string(), *count))
+                .collect()
+        };
+        let range = |start: &str, end: &str| Some((start.to_string(), end.to_string()));
+
+        // Across the leap day, then a day off, a day on and nothing yet today
+        let streaks = compute_streaks(&calendar(&[1, 4, 2, 0, 5, 0]));
+        assert_eq!(streaks.longest, 3);
+        assert_eq!(streaks.longest_range, range("2024-02-27", "2024-02-29"));
+        assert_eq!(streaks.current, 1);
+        assert_eq!(streaks.current_range, range("2024-03-02", "2024-03-02"));
+
+        let broken = compute_streaks(&calendar(&[3, 0, 0]));
+        assert_eq!((broken.current, broken.longest), (0, 1));
+        assert_eq!(broken.current_range, None);
+
+        let quiet = compute_streaks(&[]);
+        assert_eq!((quiet.current, quiet.longest), (0, 0));
+    }
+
+    #[test]
+    fn account_age_counts_leap_days() {
+        let now = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(account_age_days("2024-02-28T00:00:00Z", now), Some(2));
+        assert_eq!(account_age_days("2020-03-01T00:00:00Z", now), Some(1461));
+        let years = account_age_years("2020-03-01T00:00:00Z", now).unwrap();
+        assert!((years - 4.0).abs() < 0.001, "{}", years);
+        assert_eq!(account_age_years("not a date", now), None);
+    }
+
+    #[test]
+    fn formatter_pads_the_value_without_escape_codes() {
+        let value = formatter("LOC", 0.25, Some(1234567), 12).unwrap();
+
+        assert_eq!(value, "   1,234,567");
+        assert!(!value.contains('\x1b'));
+        assert_eq!(formatter("LOC", 0.25, None, 12), None);
+    }
+
+    #[tokio::test]
+    async fn requests_give_up_after_the_timeout() {
+        // Accepts connections but never answers
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/graphql", server.local_addr().unwrap());
+        let client = build_client(Duration::from_millis(200), None).unwrap();
+
+        let err = client.post(&url).send().await.unwrap_err();
+
+        assert!(err.is_timeout(), "{}", err);
+    }
+
+    #[test]
+    fn request_timeout_must_be_positive() {
+        let timeout = |args: &[&str]| {
+            Config::try_parse_from(["test"].iter().chain(args))
+                .map(|config| config.request_timeout_secs)
+        };
+
+        assert_eq!(timeout(&["--request-timeout-secs", "5"]).unwrap(), 5);
+        assert!(timeout(&["--request-timeout-secs", "0"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_transport_answers_by_function_and_variables() {
+        let transport = MockTransport::new()
+            .respond_to("repo", json!({ "name": "b" }), json!({ "data": "b" }))
+            .respond("repo", json!({ "data": "any" }))
+            .respond_always("user", json!({}), json!({ "data": "user" }))
+            .fail("stats", StatsError::RateLimited("limit".to_string()));
+        let transport = &transport;
+        let post = |func_name, variables: Value| async move {
+            transport.post(func_name, "", &variables).await
+        };
+
+        // Responses are used up in order, skipping those whose variables differ
+        assert_eq!(
+            post("repo", json!({ "name": "a" })).await.unwrap()["data"],
+            "any"
+        );
+        assert_eq!(
+            post("repo", json!({ "name": "b", "owner": "octo" }))
+                .await
+                .unwrap()["data"],
+            "b"
+        );
+        for _ in 0..2 {
+            assert_eq!(post("user", json!({})).await.unwrap()["data"], "user");
+        }
+        assert!(matches!(
+            post("stats", json!({})).await,
+            Err(StatsError::RateLimited(_))
+        ));
+
+        assert_eq!(transport.count("repo"), 2);
+        assert_eq!(
+            transport.requests()[0],
+            ("repo".to_string(), json!({ "name": "a" }))
+        );
+    }
+
+    /// Serve one HTTP request per entry of `responses` on a local port,
+    /// answering each with the next one, and return the address and the
+    /// requests as they were received.
+    fn serve(responses: &'static [&'static str]) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let handle = std::thread::spawn(move || {
+            responses
+                .iter()
+                .map(|response| {
+                    let (mut stream, _) = server.accept().unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request = String::new();
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if let Some((name, value)) = line.split_once(':')
+                            && name.eq_ignore_ascii_case("content-length")
+                        {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                        request.push_str(&line);
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+                    request.push_str(&String::from_utf8(body).unwrap());
+
+                    stream.write_all(response.as_bytes()).unwrap();
+                    request
+                })
+                .collect()
+        });
+
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() {
+        let (proxy, received) =
+            serve(&["HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}"]);
+        let client =
+            build_client(Duration::from_secs(5), Some(&format!("http://{}", proxy))).unwrap();
+
+        let response = client
+            .get("http://github.invalid/graphql")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(
+            received.join().unwrap()[0].starts_with("GET http://github.invalid/graphql HTTP/1.1")
+        );
+        assert!(Config::try_parse_from(["test", "--proxy", "not a url"]).is_err());
+    }
+
+    #[test]
+    fn format_with_commas_groups_digits_in_threes() {
+        assert_eq!(format_with_commas(0), "0");
+        assert_eq!(format_with_commas(999), "999");
+        assert_eq!(format_with_commas(1000), "1,000");
+        assert_eq!(format_with_commas(1234567), "1,234,567");
+        assert_eq!(format_with_commas(-1234), "-1,234");
+        assert_eq!(format_with_commas(i64::MIN), "-9,223,372,036,854,775,808");
+    }
+
+    #[tokio::test]
+    async fn graphql_errors_are_surfaced_despite_a_200() {
+        let body = r#"{ "data": { "user": null }, "errors": [
+        { "message": "Field 'nope' doesn't exist" },
+        { "message": "Something went wrong" }
+    ] }"#;
+        let response = Response::from(http::Response::builder().status(200).body(body).unwrap());
+
+        match graphql_json("stats_getter", response).await {
+            Err(StatsError::GraphQl(message)) => assert_eq!(
+                message,
+                "stats_getter returned errors: Field 'nope' doesn't exist; Something went wrong"
+            ),
+            other => panic!("expected a GraphQL error, got {:?}", other),
+        }
+
+        let ok = Response::from(
+            http::Response::builder()
+                .status(200)
+                .body(r#"{ "data": { "user": {} }, "errors": [] }"#)
+                .unwrap(),
+        );
+        assert!(graphql_json("stats_getter", ok).await.is_ok());
+    }
+
+    #[test]
+    fn perf_counter_try_times_failures_too() {
+        let (result, elapsed) = perf_counter_try(|| {
+            std::thread::sleep(Duration::from_millis(20));
+            Err::<(), _>(StatsError::MissingEnv("ACCESS_TOKEN".to_string()))
+        });
+
+        assert!(matches!(result, Err(StatsError::MissingEnv(_))));
+        assert!(elapsed >= 0.02);
+    }
+
+    #[test]
+    fn humanize_count_abbreviates_thousands_and_millions() {
+        assert_eq!(humanize_count(999), "999");
+        assert_eq!(humanize_count(1000), "1k");
+        assert_eq!(humanize_count(1200), "1.2k");
+        assert_eq!(humanize_count(999_949), "999.9k");
+        assert_eq!(humanize_count(999_950), "1M");
+        assert_eq!(humanize_count(3_400_000), "3.4M");
+        assert_eq!(humanize_count(-1500), "-1.5k");
+    }
+
+    #[test]
+    fn shields_endpoints_are_written_for_the_queried_stats() {
+        let dir = CONFIG.cache_dir.join("shields");
+        let stats = StatsSnapshot {
+            stars: Some(1200),
+            loc_net: Some(-42),
+            ..StatsSnapshot::default()
+        };
+
+        write_shields_endpoints(&dir, &stats).unwrap();
+        let endpoint = |label: &str| -> Value {
+            serde_json::from_str(&fs::read_to_string(dir.join(format!("{}.json", label))).unwrap())
+                .unwrap()
+        };
+
+        assert_eq!(
+            endpoint("stars"),
+            json!({ "schemaVersion": 1, "label": "stars", "message": "1.2k", "color": "blue" })
+        );
+        assert_eq!(endpoint("loc")["message"], "-42");
+        assert!(!dir.join("commits.json").exists());
+    }
+
+    #[test]
+    fn require_path_names_the_missing_part() {
+        let json = json!({ "data": { "user": { "repositories": { "totalCount": 0 } } } });
+        assert_eq!(
+            require_path(&json, "data/user/repositories/totalCount").unwrap(),
+            &json!(0)
+        );
+
+        let message = |json: &Value| match require_path(json, "data/user/repositories/totalCount") {
+            Err(StatsError::GraphQl(message)) => message,
+            other => panic!("expected a GraphQL error, got {:?}", other),
+        };
+        assert_eq!(
+            message(&json!({ "data": { "user": null } })),
+            "response has no data/user (looking for data/user/repositories/totalCount)"
+        );
+        assert_eq!(
+            message(&json!({ "data": { "user": { "repositories": {} } } })),
+            "response has no data/user/repositories/totalCount \
+         (looking for data/user/repositories/totalCount)"
+        );
+    }
+
+    #[test]
+    fn loc_trend_compares_the_latest_run_to_the_previous_ones() {
+        assert_eq!(loc_trend(&[], 0.5), None);
+        assert_eq!(loc_trend(&[100], 0.5), None);
+        assert_eq!(
+            loc_trend(&[100, 150, 130], 0.5),
+            Some(LocTrend {
+                delta: -20,
+                ema: 127.5,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn post_stats_retries_transient_failures_with_the_same_request() {
+        let (server, received) = serve(&[
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+        ]);
+        let stats = StatsSnapshot {
+            commits: Some(120),
+            ..StatsSnapshot::default()
+        };
+
+        post_stats(
+            &format!("http://{}/hooks/stats", server),
+            Some("Bearer hook-secret"),
+            &stats,
+            2,
+            Duration::from_millis(1),
+        )
+        .await
+        .unwrap();
+
+        let requests = received.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let (head, body) = request.split_once("\r\n\r\n").unwrap();
+            let head = head.to_lowercase();
+            assert!(head.starts_with("post /hooks/stats http/1.1"));
+            assert!(head.contains("authorization: bearer hook-secret"));
+            assert!(head.contains("content-type: application/json"));
+            let body: Value = serde_json::from_str(body).unwrap();
+            assert_eq!(body["commits"], 120);
+            assert_eq!(body["stars"], Value::Null);
+        }
+    }
+
+    #[test]
+    fn humanize_duration_picks_the_unit_by_magnitude() {
+        assert_eq!(humanize_duration(12.5), "12.5000 s");
+        assert_eq!(humanize_duration(1.0), "1.0000 s");
+        assert_eq!(humanize_duration(0.25), "250.0000 ms");
+        assert_eq!(humanize_duration(0.0000425), "42.5000 µs");
+        assert_eq!(humanize_duration(0.0000000075), "7.5000 ns");
+        assert_eq!(humanize_duration(0.0), "0.0000 ns");
+    }
+
+    #[test]
+    fn update_readme_only_rewrites_between_the_markers() {
+        fs::create_dir_all(&CONFIG.cache_dir).unwrap();
+        let path = CONFIG.cache_dir.join("README.md");
+        fs::write(
+            &path,
+            "# Octo\n\nIntro\n<!--START_STATS-->\nold stats\n<!--END_STATS-->\nOutro\n",
+        )
+        .unwrap();
+        let stats = StatsSnapshot {
+            commits: Some(1200),
+            loc_added: Some(130),
+            loc_deleted: Some(30),
+            loc_net: Some(100),
+            ..StatsSnapshot::default()
+        };
+
+        update_readme(&path, &stats).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Octo\n\nIntro\n<!--START_STATS-->\n\
+         | Stat | Value |\n| --- | ---: |\n\
+         | Commits | 1,200 |\n| Lines of code | 100 (+130, -30) |\n\
+         <!--END_STATS-->\nOutro\n"
+        );
+
+        fs::write(&path, "# Octo\n<!--START_STATS-->\n").unwrap();
+        assert!(matches!(
+            update_readme(&path, &stats),
+            Err(StatsError::Invalid(_))
+        ));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Octo\n<!--START_STATS-->\n"
+        );
     }
 }